@@ -1,210 +1,7608 @@
-use std::collections::{BTreeMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{self};
+use std::io::{self, IsTerminal, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
-use toml;
 use calcbits::{create_progress_bar, save_to_db};
+use flate2::read::GzDecoder;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
 
 const BPM_VERSION: &str = "0.1.2";
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct PackageVersion {
     path: String,
     binaries: Vec<String>,
     #[serde(default)]
     dependencies: Vec<String>, // dependencies field
+    #[serde(default, alias = "release_notes")]
+    changelog: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    checksums: BTreeMap<String, String>, // binary -> sha256 published by the repo, checked on install
+    #[serde(default)]
+    requires_env: Vec<String>, // env vars that must be set, or `tool:<name>` for a program on PATH
+    #[serde(default)]
+    mode: Option<u32>, // Unix permissions for the installed binaries, overrides the config
+    #[serde(default)]
+    replaces: Vec<String>, // packages this one supersedes, e.g. after a rename; removed when it is installed
+    #[serde(default)]
+    deprecated: Option<String>, // why not to use this version; warned about on install, refused under security = strict
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct Repo {
+    #[serde(skip)]
+    schema_version: u32,
     #[serde(flatten)]
     packages: BTreeMap<String, BTreeMap<String, PackageVersion>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Index format versions:
+//   1 — the current format; also assumed when `schema_version` is absent
+//   newer — loaded best-effort with a warning, skipping packages that don't parse
+const INDEX_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 struct InstalledPackage {
     repo: String,
     version: String,
     binaries: Vec<String>,
+    #[serde(default)]
+    checksums: BTreeMap<String, String>, // binary path -> sha256
+    #[serde(default)]
+    verified_mtimes: BTreeMap<String, u64>, // binary path -> mtime when the checksum last matched
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_sha256: Option<String>, // hash of the index file the install was resolved from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_schema: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_url: Option<String>, // set for `/i --url` installs, which have no index
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    installed_at: Option<u64>, // unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>, // bytes on disk across all binaries
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    aliases: BTreeMap<String, String>, // binary -> the name it was installed under (`/i --as`)
+    #[serde(default = "default_true")]
+    explicit: bool, // false when only pulled in as a dependency; older records count as explicit
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    dependencies: Vec<String>, // "name:version" of each dependency as resolved at install time; a bare name was --assume-installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous: Option<PreviousVersion>, // what was installed before this version, for /rollback
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PreviousVersion {
+    repo: String,
+    version: String,
+}
+
+// A reinstall of the same version keeps the older record, so /rollback still goes back a version
+fn previous_of(existing: Option<&InstalledPackage>, repo: &str, version: &str) -> Option<PreviousVersion> {
+    let existing = existing?;
+    if existing.repo == repo && existing.version == version { return existing.previous.clone(); }
+    Some(PreviousVersion { repo: existing.repo.clone(), version: existing.version.clone() })
 }
 
 type InstalledDb = BTreeMap<String, InstalledPackage>;
 
-fn load_installed(db_path: &Path) -> InstalledDb {
-    if db_path.exists() {
-        let content = fs::read_to_string(db_path).unwrap();
-        serde_json::from_str(&content).unwrap_or_default()
-    } else {
-        InstalledDb::new()
+// Per-repo settings, keyed by repo name; repos without an entry use the defaults
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RepoEntry {
+    #[serde(default = "default_true")]
+    enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>, // remote index; cached under cache/<name>/ by /repo refresh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fetched_at: Option<u64>, // unix seconds of the last successful refresh
+    // Credentials are never stored here, only where to find them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_env: Option<String>, // env var holding the token/password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_file: Option<String>, // file holding the token/password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>, // with a username the secret is sent as basic auth, otherwise as a bearer token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<i64>, // higher comes first when a package is in several repos; unset is 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>, // public key file the index is signed with, for /repo verify
+}
+
+impl Default for RepoEntry {
+    fn default() -> Self {
+        RepoEntry { enabled: true, url: None, fetched_at: None, token_env: None, token_file: None, username: None, priority: None, key: None }
     }
 }
 
-fn save_installed(db_path: &Path, db: &InstalledDb) {
-    let content = serde_json::to_string_pretty(db).unwrap();
-    fs::write(db_path, content).unwrap();
+fn default_true() -> bool { true }
+
+type Registry = BTreeMap<String, RepoEntry>;
+
+// The portable form of the registry written by /repo export and read by /repo import.
+// fetched_at is machine state and stays behind; credentials are only ever locations anyway.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RepoList {
+    #[serde(default)]
+    repos: BTreeMap<String, RepoListEntry>,
 }
 
-fn parse_pkg_arg(arg: &str) -> (String, String, Option<String>) {
-    let parts: Vec<&str> = arg.split(':').collect();
-    let repo = parts[0].to_string();
-    let package = parts[1].to_string();
-    let version = if parts.len() > 2 { Some(parts[2].to_string()) } else { None };
-    (repo, package, version)
+#[derive(Debug, Serialize, Deserialize)]
+struct RepoListEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>, // unset for a local repo, which only carries its settings
+    #[serde(default = "default_true")]
+    enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    priority: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_env: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
 }
 
-// --- Recursive installer with dependencies + cycle detection ---
-fn install_package(
-    bpm_store: &Path,
-    repo_name: &str,
-    package: &str,
-    version: Option<&str>,
-) {
-    let mut visited = HashSet::new();
-    install_recursive(bpm_store, repo_name, package, version, &mut visited);
+// --- Config (config.toml in the store) ---
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Config {
+    assume_installed: Vec<String>, // always treated as satisfied, never installed
+    release_url: Option<String>,   // release manifest checked by /selfupdate
+    network_timeout: Option<u64>,  // seconds, for every remote request
+    prefix: Option<String>,        // install to <prefix>/<repo>/<package>/ instead of bins/
+    strict_env: bool,              // refuse, rather than warn, when a package's requires_env is unmet
+    mode: Option<u32>,             // Unix permissions for installed binaries, e.g. 0o755
+    cache_ttl: Option<u64>,        // seconds a cached remote index stays fresh; unset means refresh always fetches
+    repos_from: Option<String>,    // "user" or "system": use that root's repos instead of this root's own
+    profile: Option<String>,       // profile set by /profile use; --profile and BPM_PROFILE override it
+    security: Security,            // what to do about binaries without a checksum and unsigned remote indexes
+    progress_style: Option<String>, // "default", "minimal", "spinner" or an indicatif template
+    frozen: bool,                  // refuse every command that would change the store
+    save_db: Option<bool>,         // false: don't copy installed binaries into packages.db; unset means true
+    case_sensitive: bool,          // only exact-case package and repo names match
+    default_repo: Option<String>,  // set by /repo default: bare names are looked up here first
 }
 
-fn install_recursive(
-    bpm_store: &Path,
-    repo_name: &str,
-    package: &str,
-    version: Option<&str>,
-    visited: &mut HashSet<String>,
-) {
-    let repo_path = bpm_store.join(repo_name).join("packages.mri");
-    let toml_content = fs::read_to_string(&repo_path).expect("Failed to read .mri file");
-    let repo: Repo = toml::from_str(&toml_content).expect("Failed to parse TOML");
-
-    if let Some(versions) = repo.packages.get(package) {
-        let ver = version.unwrap_or_else(|| versions.keys().max().unwrap());
-        if let Some(pkg) = versions.get(ver) {
-            // --- Detect cycle ---
-            let key = format!("{}:{}", package, ver);
-            if visited.contains(&key) {
-                println!("⚠ Circular dependency detected at {}", key);
-                return;
-            }
-            visited.insert(key.clone());
+// How much verification an install needs. Indexes can't be signed yet, so under strict a
+// remote repo can never be verified and is refused outright.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Security {
+    #[default]
+    Permissive, // install whatever the index offers
+    Warn,       // install, but say what couldn't be verified
+    Strict,     // refuse anything that can't be verified
+}
 
-            // --- 1. Install dependencies first ---
-            for dep in &pkg.dependencies {
-                let (dep_pkg, dep_ver) = if dep.contains(':') {
-                    let parts: Vec<&str> = dep.split(':').collect();
-                    (parts[0], Some(parts[1]))
-                } else {
-                    (dep.as_str(), None)
-                };
+// Settings a --flag can override
+impl Config {
+    fn timeout(&self, opts: &Opts) -> u64 { opts.timeout.or(self.network_timeout).unwrap_or(DEFAULT_NETWORK_TIMEOUT) }
+    fn prefix<'a>(&'a self, opts: &'a Opts) -> Option<&'a str> { opts.prefix.as_deref().or(self.prefix.as_deref()) }
+    fn frozen(&self, opts: &Opts) -> bool { opts.frozen || self.frozen }
+    fn save_db(&self, opts: &Opts) -> bool { !opts.no_save_db && self.save_db != Some(false) }
+    fn assumed_installed(&self, opts: &Opts, name: &str) -> bool {
+        self.assume_installed.iter().chain(&opts.assume_installed)
+            .any(|assumed| assumed == name || (!self.case_sensitive && assumed.to_lowercase() == name.to_lowercase()))
+    }
+}
 
-                let db = load_installed(&bpm_store.join("installed.json"));
-                if !db.contains_key(dep_pkg) {
-                    println!("Installing dependency {}...", dep_pkg);
-                    install_recursive(bpm_store, repo_name, dep_pkg, dep_ver, visited);
-                } else {
-                    println!("Dependency {} already installed.", dep_pkg);
-                }
+fn load_config(store: &Store) -> Config {
+    match fs::read_to_string(store.config_path()) {
+        Ok(content) => toml::from_str(&content).unwrap_or_else(|e| {
+            println!("{} Ignoring invalid config.toml: {}", paint("⚠", YELLOW), e);
+            Config::default()
+        }),
+        Err(_) => Config::default(),
+    }
+}
+
+// --- /config ---
+fn read_config_table(store: &Store) -> toml::Table {
+    fs::read_to_string(store.config_path()).ok()
+        .and_then(|content| content.parse().ok())
+        .unwrap_or_default()
+}
+
+// Sets or (with None) removes one key of config.toml, leaving the rest of the file as it was
+fn write_config_key(store: &Store, key: &str, value: Option<toml::Value>) -> Result<(), String> {
+    let mut table = read_config_table(store);
+    match value {
+        Some(value) => { table.insert(key.to_string(), value); }
+        None => { table.remove(key); }
+    }
+    fs::write(store.config_path(), toml::to_string(&table).unwrap())
+        .map_err(|e| format!("Failed to write {}: {}", store.config_path().display(), e))
+}
+
+// Every key with its effective value (defaults + config.toml + flags) and where that value came from
+fn effective_config(store: &Store, flags: &Opts) -> Vec<(String, serde_json::Value, &'static str)> {
+    let config = &store.config;
+    let file = read_config_table(store);
+    let serde_json::Value::Object(values) = serde_json::to_value(config).unwrap() else { unreachable!() };
+    values.into_iter().map(|(key, value)| {
+        let file_source = if file.contains_key(&key) { "config.toml" } else { "default" };
+        match key.as_str() {
+            // --assume-installed adds to the configured list, the other flags replace the value
+            "assume_installed" if !flags.assume_installed.is_empty() => {
+                let mut all = config.assume_installed.clone();
+                all.extend(flags.assume_installed.iter().cloned());
+                let source = if config.assume_installed.is_empty() { "flag" } else { "config.toml + flag" };
+                (key, serde_json::json!(all), source)
             }
+            "network_timeout" => (key, serde_json::json!(config.timeout(flags)), if flags.timeout.is_some() { "flag" } else { file_source }),
+            "prefix" if flags.prefix.is_some() => (key, serde_json::json!(config.prefix(flags)), "flag"),
+            "save_db" => (key, serde_json::json!(config.save_db(flags)), if flags.no_save_db { "flag" } else { file_source }),
+            "frozen" if flags.frozen => (key, serde_json::json!(true), "flag"),
+            _ => (key, value, file_source),
+        }
+    }).collect()
+}
 
-            // --- 2. Install main package ---
-            let bins_dir = bpm_store.join("bins");
-            fs::create_dir_all(&bins_dir).unwrap();
+fn config_command(store: &Store, args: &[String], opts: &Opts) -> Result<(), String> {
+    let entries = effective_config(store, opts);
+    let show = |value: &serde_json::Value| if value.is_null() { "(unset)".to_string() } else { value.to_string() };
+    match (args.first().map(String::as_str), args.get(1), args.get(2)) {
+        (Some("list"), None, None) => {
+            if opts.json {
+                let map: serde_json::Map<String, serde_json::Value> = entries.into_iter()
+                    .map(|(key, value, source)| (key, serde_json::json!({ "value": value, "source": source })))
+                    .collect();
+                print_json(&map);
+            } else {
+                for (key, value, source) in entries { println!("{} = {} ({})", key, show(&value), source); }
+            }
+        }
+        (Some("get"), Some(key), None) => {
+            let Some((_, value, _)) = entries.iter().find(|(k, _, _)| k == key) else {
+                return Err(format!("Unknown config key {}", key));
+            };
+            println!("{}", show(value));
+        }
+        (Some("set"), Some(key), Some(raw)) => {
+            if !entries.iter().any(|(k, _, _)| k == key) { return Err(format!("Unknown config key {}", key)); }
+            // TOML literals (30, true, ["a"]) keep their type; anything else is taken as a string
+            let value = format!("v = {}", raw).parse::<toml::Table>().ok()
+                .and_then(|mut table| table.remove("v"))
+                .unwrap_or_else(|| toml::Value::String(raw.clone()));
+            let mut table = read_config_table(store);
+            table.insert(key.clone(), value);
+            Config::deserialize(toml::Value::Table(table.clone()))
+                .map_err(|e| format!("Invalid value for {}: {}", key, e.to_string().trim().replace('\n', " ")))?;
+            fs::write(store.config_path(), toml::to_string(&table).unwrap())
+                .map_err(|e| format!("Failed to write {}: {}", store.config_path().display(), e))?;
+            println!("{} = {}", key, table[key]);
+        }
+        _ => println!("Usage: bpm /config list [--json] | get <key> | set <key> <value>"),
+    }
+    Ok(())
+}
 
-            let installing_message = format!("Installing {}", package);
-            let pb = create_progress_bar(pkg.binaries.len() as u64, &installing_message);
-            let mut installed_bins = Vec::new();
+// Global `--flags`, stripped out of the positional args
+#[derive(Debug, Default, Clone)]
+struct Opts {
+    yes: bool,            // never prompt, take the default choice
+    exclude: Vec<String>, // packages to leave alone for this run only
+    quiet: bool,          // no progress output at all
+    no_progress: bool,    // plain status lines instead of the animated bar
+    dry_run: bool,        // resolve and print the plan, change nothing
+    json: bool,           // machine-readable output
+    deep: bool,           // /verify: rehash everything, ignore the mtime cache
+    force: bool,          // allow installing from a disabled repo
+    assume_installed: Vec<String>, // dependencies provided outside bpm, on top of the config's
+    timeout: Option<u64>, // network timeout in seconds, overrides the config
+    prefix: Option<String>, // per-package install layout root, overrides the config
+    max_depth: Option<usize>, // longest dependency chain the resolver will follow
+    verbose: bool,        // /info: checksums and source index
+    trace: bool,          // -vv: explain every resolver decision on stderr
+    allow_cycles: bool,   // warn about dependency cycles instead of failing
+    keep_going: bool,     // batch installs/upgrades: carry on past failures and summarize
+    url: Option<String>,  // /i --url: a single binary to download, bypassing the indexes
+    name: Option<String>, // package name for --url, defaults to the file name in the URL
+    sha256: Option<String>, // expected hash of the --url download
+    check: bool,          // /i --check: verify the closure is available locally, install nothing
+    apply: bool,          // /diff: make the installed packages match the manifest
+    regex: bool,          // /search: the query is a regular expression
+    interactive: bool,    // ask how to settle version conflicts instead of failing
+    archive: Option<String>, // /i --archive: install from a .tar.gz with an embedded package.mri
+    switch_repo: Option<String>, // /u: move a package to another repo instead of its recorded one
+    parallel: Option<usize>, // concurrent binary downloads, defaults to the CPU count
+    as_name: Option<String>, // /i --as: install the requested package's binary under another name
+    color: Option<ColorChoice>, // --color always|auto|never; --no-color is never
+    explicit_only: bool,  // /l --explicit: only packages installed by name
+    deps_only: bool,      // /l --deps: only packages pulled in as dependencies
+    tree: bool,           // /l --tree: nest dependencies under the packages that pulled them in
+    fix: bool,            // /doctor: offer repairs for what it finds
+    bundle: Option<String>, // /export: also copy the binaries into this directory
+    out: Option<String>,    // /freeze: lockfile to write instead of stdout
+    locked: Option<String>, // /i: install exactly what this lockfile pins
+    indexes: bool,        // /cache clear --indexes
+    binaries: bool,       // /cache clear --binaries
+    since: Option<String>, // /versions: only versions newer than this
+    limit: Option<usize>,  // /versions: only the newest N
+    system: bool,          // work on the system root instead of the user one
+    all: bool,             // /l --all: list both roots; /which --all: every binary
+    format: Option<String>, // /l --format: one line per package from a template like "{name} {version}"
+    profile: Option<String>, // --profile, else BPM_PROFILE: the profile to work on for this run
+    frozen: bool,           // --frozen: commands that change the store fail, whatever the config says
+    events_file: Option<String>, // append a JSON line per install/remove step here
+    info_deps: bool,        // /info --deps-only: just the dependency list
+    resolved: bool,         // /info --deps-only --resolved: name:version instead of the declared strings
+    no_save_db: bool,       // --no-save-db: installs skip packages.db, whatever the config says
+    priority: Option<i64>,  // /repo edit --priority
+    key: Option<String>,    // /repo edit --key: public key file, or "none" to drop it
+    summary: bool,          // --summary/--stats: print an OperationReport when the command finishes
+    repair: bool,           // /verify --repair: put back missing or modified binaries from their source
+    stream: bool,           // /i --dry-run --json --stream: one NDJSON line per resolved node, then a summary
+    refresh: bool,          // /i, /u: fetch the remote indexes involved before resolving, ignoring cache_ttl
+    update: bool,           // /repo import: overwrite repos already registered instead of skipping them
+    repo: Option<String>,   // /upgrade --repo: only packages installed from this repo
+    allow_deprecated: bool, // install deprecated versions even under security = strict
+    store: Option<String>,  // --store, else BPM_STORE: a store root to use instead of the user or system one
+}
 
-            for bin in &pkg.binaries {
-                let src = Path::new(&pkg.path).join(bin);
-                let filename = Path::new(bin).file_name().unwrap();
-                let dest = bins_dir.join(filename);
+fn parse_opts(args: Vec<String>) -> Result<(Vec<String>, Opts), String> {
+    let mut opts = Opts::default();
+    let mut rest = Vec::new();
+    let mut iter = args.into_iter().peekable();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            // A bare `/doctor --store` names what it checks; only a value that follows picks a store
+            "--store" if iter.peek().is_none_or(|next| next.starts_with('-')) => rest.push(arg),
+            "--store" => opts.store = iter.next(),
+            "--yes" | "-y" => opts.yes = true,
+            "--exclude" => opts.exclude.push(flag_value(&arg, iter.next())?),
+            "--quiet" | "-q" => opts.quiet = true,
+            "--no-progress" => opts.no_progress = true,
+            "--dry-run" => opts.dry_run = true,
+            "--json" => opts.json = true,
+            "--deep" => opts.deep = true,
+            // A second -v (or -vv) also turns on resolver tracing
+            "--verbose" | "-v" => { opts.trace = opts.verbose; opts.verbose = true; }
+            "-vv" => { opts.verbose = true; opts.trace = true; }
+            "--force" => opts.force = true,
+            "--update" => opts.update = true,
+            "--refresh" => opts.refresh = true,
+            "--allow-cycles" => opts.allow_cycles = true,
+            "--keep-going" => opts.keep_going = true,
+            "--url" => opts.url = Some(flag_value(&arg, iter.next())?),
+            "--name" => opts.name = Some(flag_value(&arg, iter.next())?),
+            "--sha256" => opts.sha256 = Some(flag_value(&arg, iter.next())?),
+            "--check" => opts.check = true,
+            "--apply" => opts.apply = true,
+            "--regex" => opts.regex = true,
+            "--switch-repo" => opts.switch_repo = Some(flag_value(&arg, iter.next())?),
+            "--repo" => opts.repo = Some(flag_value(&arg, iter.next())?),
+            "--events-file" => opts.events_file = Some(flag_value(&arg, iter.next())?),
+            "--deps-only" => opts.info_deps = true,
+            "--resolved" => opts.resolved = true,
+            "--no-save-db" => opts.no_save_db = true,
+            "--summary" | "--stats" => opts.summary = true,
+            "--repair" => opts.repair = true,
+            "--stream" => opts.stream = true,
+            "--priority" => opts.priority = Some(flag_number(&arg, iter.next())?),
+            "--key" => opts.key = Some(flag_value(&arg, iter.next())?),
+            "--allow-deprecated" => opts.allow_deprecated = true,
+            "--archive" => opts.archive = Some(flag_value(&arg, iter.next())?),
+            "--interactive" => opts.interactive = true,
+            "--as" => opts.as_name = Some(flag_value(&arg, iter.next())?),
+            "--explicit" => opts.explicit_only = true,
+            "--color" => opts.color = Some(flag_color(&arg, iter.next())?),
+            "--no-color" => opts.color = Some(ColorChoice::Never),
+            "--deps" => opts.deps_only = true,
+            "--tree" => opts.tree = true,
+            "--indexes" => opts.indexes = true,
+            "--binaries" => opts.binaries = true,
+            "--fix" => opts.fix = true,
+            "--bundle" => opts.bundle = Some(flag_value(&arg, iter.next())?),
+            "--out" => opts.out = Some(flag_value(&arg, iter.next())?),
+            "--locked" => opts.locked = Some(flag_value(&arg, iter.next())?),
+            "--parallel" => opts.parallel = Some(flag_number(&arg, iter.next())?),
+            "--assume-installed" => opts.assume_installed.push(flag_value(&arg, iter.next())?),
+            "--timeout" => opts.timeout = Some(flag_number(&arg, iter.next())?),
+            "--prefix" => opts.prefix = Some(flag_value(&arg, iter.next())?),
+            "--system" => opts.system = true,
+            "--user" => opts.system = false,
+            "--all" => opts.all = true,
+            "--format" => opts.format = Some(flag_value(&arg, iter.next())?),
+            "--profile" => opts.profile = Some(flag_value(&arg, iter.next())?),
+            "--frozen" => opts.frozen = true,
+            "--since" => opts.since = Some(flag_value(&arg, iter.next())?),
+            "--limit" => opts.limit = Some(flag_number(&arg, iter.next())?),
+            "--max-depth" => opts.max_depth = Some(flag_number(&arg, iter.next())?),
+            _ => rest.push(arg),
+        }
+    }
+    if std::env::var_os("BPM_NO_PROGRESS").is_some_and(|v| !v.is_empty()) { opts.no_progress = true; }
+    if opts.profile.is_none() { opts.profile = std::env::var("BPM_PROFILE").ok().filter(|v| !v.is_empty()); }
+    if opts.store.is_none() { opts.store = std::env::var("BPM_STORE").ok().filter(|v| !v.is_empty()); }
+    Ok((rest, opts))
+}
 
-                fs::copy(&src, &dest).unwrap_or_else(|_| {
-                    println!("Simulated copy {} -> {}", src.display(), dest.display());
-                    0
-                });
+// A flag's value, which must be there: `--url` at the end of the line is a mistake, not "no url"
+fn flag_value(flag: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("{} needs a value", flag))
+}
 
-                // Save binary to DB
-                let db_file = bpm_store.join("packages.db").to_string_lossy().to_string();
-                let _ = save_to_db(&db_file, &filename.to_string_lossy(), &fs::read(&dest).unwrap(), false);
+fn flag_number<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = flag_value(flag, value)?;
+    value.parse().map_err(|_| format!("Invalid value {:?} for {}, expected a number", value, flag))
+}
 
-                installed_bins.push(dest.to_string_lossy().to_string());
-                pb.inc(1);
-            }
-            pb.finish_with_message(format!("Installed {} successfully!", package));
+fn flag_color(flag: &str, value: Option<String>) -> Result<ColorChoice, String> {
+    let value = flag_value(flag, value)?;
+    ColorChoice::parse(&value).ok_or_else(|| format!("Invalid value {:?} for {}, expected always, auto or never", value, flag))
+}
 
-            let mut db = load_installed(&bpm_store.join("installed.json"));
-            db.insert(package.to_string(), InstalledPackage {
-                repo: repo_name.to_string(),
-                version: ver.to_string(),
-                binaries: installed_bins,
-            });
-            save_installed(&bpm_store.join("installed.json"), &db);
+// --- Color ---
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColorChoice { Auto, Always, Never }
+
+impl ColorChoice {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            "never" => Some(ColorChoice::Never),
+            _ => None,
+        }
+    }
+}
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+
+static COLOR: OnceLock<bool> = OnceLock::new();
+
+// --color/--no-color win, then NO_COLOR, then FORCE_COLOR (any value but "0"); otherwise
+// color only when stdout is a terminal. Empty env vars count as unset.
+fn color_enabled(choice: Option<ColorChoice>, no_color: Option<&str>, force_color: Option<&str>, terminal: bool) -> bool {
+    match choice {
+        Some(ColorChoice::Always) => true,
+        Some(ColorChoice::Never) => false,
+        _ if no_color.is_some_and(|v| !v.is_empty()) => false,
+        _ if force_color.is_some_and(|v| !v.is_empty()) => force_color != Some("0"),
+        _ => terminal,
+    }
+}
+
+fn init_color(opts: &Opts) {
+    let no_color = std::env::var("NO_COLOR").ok();
+    let force_color = std::env::var("FORCE_COLOR").ok();
+    let _ = COLOR.set(color_enabled(opts.color, no_color.as_deref(), force_color.as_deref(), io::stdout().is_terminal()));
+}
+
+// Every colored bit of output goes through here
+fn paint(text: &str, code: &str) -> String {
+    if COLOR.get().copied().unwrap_or(false) { format!("\x1b[{}m{}\x1b[0m", code, text) } else { text.to_string() }
+}
+
+// --- --events-file ---
+// One JSON object per line, appended as each step happens: resolved, downloading, copied,
+// installed, removed and error. Every line carries `event` and `ts` (unix seconds).
+static EVENTS: OnceLock<Mutex<fs::File>> = OnceLock::new();
+
+fn init_events(opts: &Opts) -> Result<(), String> {
+    let Some(path) = &opts.events_file else { return Ok(()) };
+    let file = fs::OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|e| format!("Failed to open events file {}: {}", path, e))?;
+    let _ = EVENTS.set(Mutex::new(file));
+    Ok(())
+}
+
+// --- --summary ---
+// What a command changed, accumulated as it goes by install_step, install_url and the removals
+#[derive(Debug, Clone, Serialize)]
+struct OperationReport {
+    installed: usize,   // packages with at least one binary written
+    unchanged: usize,   // reinstalls where every binary was already byte-identical
+    removed: usize,
+    binaries: usize,    // written plus deleted
+    bytes_written: u64,
+    bytes_freed: u64,
+    elapsed_ms: u64,
+}
+
+// Kept per thread: a command installs and removes on its main thread (only downloads fan out),
+// so this is the command's report, and concurrent tests don't count each other's work
+thread_local! {
+    static REPORT: std::cell::RefCell<OperationReport> = const { std::cell::RefCell::new(OperationReport {
+        installed: 0, unchanged: 0, removed: 0, binaries: 0, bytes_written: 0, bytes_freed: 0, elapsed_ms: 0,
+    }) };
+}
+
+fn record(update: impl FnOnce(&mut OperationReport)) {
+    REPORT.with_borrow_mut(update);
+}
+
+// What has been recorded so far, timed from the start of the command
+fn report_snapshot() -> OperationReport {
+    let mut report = REPORT.with_borrow(OperationReport::clone);
+    report.elapsed_ms = OUTPUT.get().map_or(0, |output| output.started.elapsed().as_millis() as u64);
+    report
+}
+
+// The text form; with --json the report is the summary field of the command's document
+fn print_report(report: &OperationReport) {
+    println!("Summary: {} installed, {} unchanged, {} removed; {} binaries, {} written, {} freed, in {:.1}s",
+        report.installed, report.unchanged, report.removed, report.binaries,
+        human_size(report.bytes_written), human_size(report.bytes_freed), report.elapsed_ms as f64 / 1000.0);
+}
+
+// --- Warnings ---
+// Collected while a command runs and printed together when it ends, so they don't scroll past
+// between progress lines. With --json they become a field of the command's JSON document.
+#[derive(Debug, Serialize)]
+struct Warning {
+    kind: &'static str, // cycle, already-installed, deprecated, security, requires-env, disabled-repo, index
+    message: String,
+}
+
+// Per thread like the report: warnings come from resolution and installs, never from download workers
+thread_local! {
+    static WARNINGS: std::cell::RefCell<Vec<Warning>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+// The same index can be loaded several times in one command, so repeats are dropped
+fn warn(kind: &'static str, message: String) {
+    WARNINGS.with_borrow_mut(|warnings| {
+        if warnings.iter().any(|w| w.kind == kind && w.message == message) { return; }
+        emit("warning", serde_json::json!({ "kind": kind, "message": message }));
+        warnings.push(Warning { kind, message });
+    });
+}
+
+fn take_warnings() -> Vec<Warning> {
+    WARNINGS.take()
+}
+
+// Warnings not folded into a JSON document: as text, kept off stdout when that carries JSON
+fn print_warnings() {
+    for line in warning_lines(&take_warnings()) {
+        if json_output() { eprintln!("{}", line); } else { println!("{}", line); }
+    }
+}
+
+// A count, then one indented line per warning; nothing at all without warnings
+fn warning_lines(warnings: &[Warning]) -> Vec<String> {
+    if warnings.is_empty() { return Vec::new(); }
+    let count = if warnings.len() == 1 { "1 warning".to_string() } else { format!("{} warnings", warnings.len()) };
+    std::iter::once(format!("{} {}:", paint("⚠", YELLOW), count)).chain(warnings.iter().map(|w| format!("  {}", w.message))).collect()
+}
+
+// --- JSON output ---
+// Under --json, stdout carries exactly one JSON document (or NDJSON with --stream), so anything
+// else a command has to say goes to stderr or into that document
+struct Output {
+    json: bool,
+    summary: bool, // --summary
+    quiet: bool,
+    started: Instant,
+}
+
+static OUTPUT: OnceLock<Output> = OnceLock::new();
+static JSON_PRINTED: AtomicBool = AtomicBool::new(false);
+static SUMMARY_PRINTED: AtomicBool = AtomicBool::new(false);
+
+fn init_output(opts: &Opts) {
+    let _ = OUTPUT.set(Output { json: opts.json, summary: opts.summary, quiet: opts.quiet, started: Instant::now() });
+}
+
+fn json_output() -> bool {
+    OUTPUT.get().is_some_and(|output| output.json)
+}
+
+// `value` with the warnings and, when there is one, the --summary report as extra fields.
+// Only an object has room for them; anything else is returned as it is.
+fn with_extras(mut value: serde_json::Value, warnings: &[Warning], summary: Option<&OperationReport>) -> serde_json::Value {
+    if let Some(object) = value.as_object_mut() {
+        if !warnings.is_empty() { object.insert("warnings".to_string(), serde_json::json!(warnings)); }
+        if let Some(report) = summary { object.insert("summary".to_string(), serde_json::json!(report)); }
+    }
+    value
+}
+
+// The command's document, with the warnings raised so far and the --summary report folded in
+fn json_document(value: serde_json::Value) -> serde_json::Value {
+    JSON_PRINTED.store(true, AtomicOrdering::Relaxed);
+    if !value.is_object() { return value; }
+    let summary = OUTPUT.get().is_some_and(|output| output.summary).then(report_snapshot);
+    if summary.is_some() { SUMMARY_PRINTED.store(true, AtomicOrdering::Relaxed); }
+    with_extras(value, &take_warnings(), summary.as_ref())
+}
+
+fn print_json(value: &impl Serialize) {
+    println!("{}", serde_json::to_string_pretty(&json_document(serde_json::to_value(value).unwrap())).unwrap());
+}
+
+// For a map keyed by package names, where an extra field could pass for a package; the
+// warnings and summary then go to stderr
+fn print_json_as_is(value: &impl Serialize) {
+    JSON_PRINTED.store(true, AtomicOrdering::Relaxed);
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+// A command that printed no document of its own still owes --json one when there is something to report
+fn finish_output() {
+    if json_output() && !JSON_PRINTED.load(AtomicOrdering::Relaxed) {
+        let document = json_document(serde_json::json!({}));
+        if document.as_object().is_some_and(|object| !object.is_empty()) { println!("{}", document); }
+    }
+    print_warnings();
+    // Still unprinted when the command had no document, or one that isn't an object
+    let Some(output) = OUTPUT.get().filter(|output| output.summary) else { return };
+    if SUMMARY_PRINTED.swap(true, AtomicOrdering::Relaxed) { return; }
+    let report = report_snapshot();
+    if output.json { eprintln!("{}", serde_json::json!({ "summary": report })); } else if !output.quiet { print_report(&report); }
+}
+
+// Written and flushed line by line, so a reader tailing the file sees each step as it happens
+fn emit(event: &str, fields: serde_json::Value) {
+    let Some(file) = EVENTS.get() else { return };
+    let line = with_fields(serde_json::json!({ "event": event, "ts": now_secs() }), fields);
+    let mut file = file.lock().unwrap();
+    let _ = writeln!(file, "{}", line);
+    let _ = file.flush();
+}
+
+// --- Store layout ---
+// Every path bpm reads or writes lives under the store root
+// The two install roots: --user (the default) and --system. Each has its own installed DB,
+// bins and config; repos are its own too unless its config sets repos_from.
+// --store or BPM_STORE points at any other directory instead.
+const USER_STORE: &str = "C:/Users/User/Bpm-Store";
+const SYSTEM_STORE: &str = "C:/ProgramData/Bpm-Store";
+
+struct Store {
+    root: PathBuf,
+    repos_root: PathBuf, // repo directories, repos.json and cached indexes; usually the same as root
+    state_dir: PathBuf,  // installed DB, bins, holds and packages.db: root, or profiles/<name>/ under it
+    installed: Box<dyn InstalledStore>,
+    config: Config, // the root's config.toml, shared by its profiles
+}
+
+fn installed_store(dir: &Path) -> Box<dyn InstalledStore> {
+    #[cfg(not(feature = "sqlite"))]
+    return Box::new(JsonStore { path: dir.join("installed.json") });
+    #[cfg(feature = "sqlite")]
+    return Box::new(SqliteStore { path: dir.join("installed.sqlite"), legacy_json: dir.join("installed.json") });
+}
+
+impl Store {
+    fn new(root: impl Into<PathBuf>) -> Self {
+        let root = root.into();
+        Store { repos_root: root.clone(), state_dir: root.clone(), installed: installed_store(&root), config: Config::default(), root }
+    }
+    // Switch to a profile: its own installed packages and bins, the root's repos and config
+    fn use_profile(&mut self, name: &str) {
+        self.state_dir = self.profile_dir(name);
+        self.installed = installed_store(&self.state_dir);
+    }
+    fn profile_dir(&self, name: &str) -> PathBuf { self.root.join("profiles").join(name) }
+    // The store of a root, reading its repos from wherever its config points
+    fn open(system: bool) -> Self {
+        Store::open_in(system, Path::new(USER_STORE), Path::new(SYSTEM_STORE))
+    }
+    // The store this run works on: --store/BPM_STORE if given, else the root --user/--system picks
+    fn open_for(opts: &Opts) -> Self {
+        match opts.store.as_deref() {
+            Some(dir) => Store::open_at(Path::new(dir), Path::new(USER_STORE), Path::new(SYSTEM_STORE)),
+            None => Store::open(opts.system),
+        }
+    }
+    fn open_in(system: bool, user_root: &Path, system_root: &Path) -> Self {
+        Store::open_at(if system { system_root } else { user_root }, user_root, system_root)
+    }
+    fn open_at(root: &Path, user_root: &Path, system_root: &Path) -> Self {
+        let mut store = Store::new(root);
+        store.config = load_config(&store);
+        match store.config.repos_from.as_deref() {
+            Some("system") => store.repos_root = system_root.to_path_buf(),
+            Some("user") => store.repos_root = user_root.to_path_buf(),
+            Some(other) => println!("{} Ignoring repos_from = {:?}, expected \"user\" or \"system\"", paint("⚠", YELLOW), other),
+            None => {}
+        }
+        store
+    }
+    fn registry_path(&self) -> PathBuf { self.repos_root.join("repos.json") }
+    fn config_path(&self) -> PathBuf { self.root.join("config.toml") }
+    // Kept apart from the installed DB so holds outlive reinstalls and DB rebuilds
+    fn holds_path(&self) -> PathBuf { self.state_dir.join("holds.json") }
+    fn bins_dir(&self) -> PathBuf { self.state_dir.join("bins") }
+    fn packages_db_path(&self) -> PathBuf { self.state_dir.join("packages.db") }
+    // packages.mri, or packages.mri.gz when only the compressed index exists
+    fn repo_index_path(&self, repo_name: &str) -> PathBuf {
+        let plain = self.repos_root.join(repo_name).join("packages.mri");
+        let gzipped = self.repos_root.join(repo_name).join("packages.mri.gz");
+        if !plain.exists() && gzipped.exists() { gzipped } else { plain }
+    }
+    fn cache_dir(&self, repo_name: &str) -> PathBuf { self.repos_root.join("cache").join(repo_name) }
+    fn cached_index_path(&self, repo_name: &str) -> PathBuf { self.cache_dir(repo_name).join("packages.mri") }
+    // The cached index as it was before the last refresh that changed it
+    fn previous_index_path(&self, repo_name: &str) -> PathBuf { self.cache_dir(repo_name).join("packages.prev.mri") }
+}
+
+// --- Installed DB ---
+// Backends only need to load and save the whole DB; the single-package methods
+// default to a full rewrite and are overridden where a backend can update in place
+trait InstalledStore {
+    fn load(&self) -> InstalledDb;
+    fn save(&self, db: &InstalledDb);
+
+    fn get(&self, name: &str) -> Option<InstalledPackage> {
+        self.load().remove(name)
+    }
+    fn insert(&self, name: &str, pkg: InstalledPackage) {
+        let mut db = self.load();
+        db.insert(name.to_string(), pkg);
+        self.save(&db);
+    }
+    fn remove(&self, name: &str) -> Option<InstalledPackage> {
+        let mut db = self.load();
+        let pkg = db.remove(name)?;
+        self.save(&db);
+        Some(pkg)
+    }
+    fn iter(&self) -> std::collections::btree_map::IntoIter<String, InstalledPackage> {
+        self.load().into_iter()
+    }
+    // `load` falls back to an empty DB on damage; this says whether that happened
+    fn check(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// installed.json, rewritten in full on every change
+struct JsonStore {
+    path: PathBuf,
+}
+
+impl InstalledStore for JsonStore {
+    fn check(&self) -> Result<(), String> {
+        let Ok(content) = fs::read_to_string(&self.path) else { return Ok(()) };
+        serde_json::from_str::<InstalledDb>(&content).map(|_| ())
+            .map_err(|e| format!("{} does not parse: {}", self.path.display(), e))
+    }
 
-            visited.remove(&key); // cleanup after install
+    fn load(&self) -> InstalledDb {
+        if self.path.exists() {
+            let content = fs::read_to_string(&self.path).unwrap();
+            serde_json::from_str(&content).unwrap_or_default()
         } else {
-            println!("Version {} not found for package {}", ver, package);
+            InstalledDb::new()
+        }
+    }
+
+    fn save(&self, db: &InstalledDb) {
+        let content = serde_json::to_string_pretty(db).unwrap();
+        fs::write(&self.path, content).unwrap();
+    }
+}
+
+// installed.sqlite, one row per package holding its JSON record.
+// An existing installed.json is imported when the database is first created.
+#[cfg(feature = "sqlite")]
+struct SqliteStore {
+    path: PathBuf,
+    legacy_json: PathBuf,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    fn conn(&self) -> rusqlite::Connection {
+        let fresh = !self.path.exists();
+        let conn = rusqlite::Connection::open(&self.path).unwrap();
+        conn.execute("CREATE TABLE IF NOT EXISTS installed (name TEXT PRIMARY KEY, data TEXT NOT NULL)", []).unwrap();
+        if fresh && self.legacy_json.exists() {
+            let legacy = JsonStore { path: self.legacy_json.clone() }.load();
+            for (name, pkg) in &legacy { Self::put(&conn, name, pkg); }
         }
+        conn
+    }
+
+    fn put(conn: &rusqlite::Connection, name: &str, pkg: &InstalledPackage) {
+        conn.execute(
+            "INSERT OR REPLACE INTO installed (name, data) VALUES (?1, ?2)",
+            (name, serde_json::to_string(pkg).unwrap()),
+        ).unwrap();
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl InstalledStore for SqliteStore {
+    fn load(&self) -> InstalledDb {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT name, data FROM installed").unwrap();
+        stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))).unwrap()
+            .filter_map(Result::ok)
+            .filter_map(|(name, data)| Some((name, serde_json::from_str(&data).ok()?)))
+            .collect()
+    }
+
+    fn save(&self, db: &InstalledDb) {
+        let mut conn = self.conn();
+        let tx = conn.transaction().unwrap();
+        tx.execute("DELETE FROM installed", []).unwrap();
+        for (name, pkg) in db { Self::put(&tx, name, pkg); }
+        tx.commit().unwrap();
+    }
+
+    fn get(&self, name: &str) -> Option<InstalledPackage> {
+        let data: String = self.conn()
+            .query_row("SELECT data FROM installed WHERE name = ?1", [name], |row| row.get(0))
+            .ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    fn insert(&self, name: &str, pkg: InstalledPackage) {
+        Self::put(&self.conn(), name, &pkg);
+    }
+
+    fn remove(&self, name: &str) -> Option<InstalledPackage> {
+        let pkg = self.get(name)?;
+        self.conn().execute("DELETE FROM installed WHERE name = ?1", [name]).unwrap();
+        Some(pkg)
+    }
+}
+
+fn sha256_bytes(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn sha256_file(path: &Path) -> Option<String> {
+    Some(sha256_bytes(&fs::read(path).ok()?))
+}
+
+// Modification time in nanoseconds, used to skip rehashing untouched files
+fn mtime_of(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_nanos() as u64)
+}
+
+// "repo:package[:version]", or a bare "package" with no repo
+fn parse_pkg_arg(arg: &str) -> Result<(Option<String>, String, Option<String>), String> {
+    let parts: Vec<&str> = arg.split(':').collect();
+    if parts.len() == 1 { return Ok((None, check_name("package", parts[0])?.to_string(), None)); }
+    if parts.len() > 3 { return Err(format!("Invalid package {:?}, expected [repo:]package[:version]", arg)); }
+    let repo = check_repo_name(parts[0])?.to_string();
+    let package = check_name("package", parts[1])?.to_string();
+    let version = if parts.len() > 2 { Some(check_name("version", parts[2])?.to_string()) } else { None };
+    Ok((Some(repo), package, version))
+}
+
+// Repo, package and version names end up in file paths and DB keys, so anything that
+// could escape a directory or hide in a path is rejected
+fn check_name<'a>(kind: &str, name: &'a str) -> Result<&'a str, String> {
+    if name.is_empty()
+        || name == "."
+        || name.contains(['/', '\\'])
+        || name.contains("..")
+        || name.chars().any(|c| c.is_control() || c.is_whitespace())
+    {
+        return Err(format!("Invalid {} name {:?}", kind, name));
+    }
+    Ok(name)
+}
+
+// A repo may be scoped, `org/repo`, which lives in the nested directory <store>/org/repo/.
+// Only one level of scope, and each part follows the usual name rules.
+fn check_repo_name(name: &str) -> Result<&str, String> {
+    let parts: Vec<&str> = name.split('/').collect();
+    if parts.len() > 2 || parts.iter().any(|part| check_name("repo", part).is_err()) {
+        return Err(format!("Invalid repo name {:?}", name));
+    }
+    Ok(name)
+}
+
+// A binary may sit in a subdirectory of its package, but never outside it
+fn check_binary_path(bin: &str) -> Result<(), String> {
+    let path = Path::new(bin);
+    if bin.is_empty() || bin.chars().any(char::is_control) || !path.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+        return Err(format!("Invalid binary path {:?}", bin));
+    }
+    Ok(())
+}
+
+// Every directory in the store holding a packages.mri, highest priority first, then by name.
+// That is the order used when a choice has to be made.
+// Remote repos registered in repos.json are included as well.
+fn list_repos(store: &Store) -> Vec<String> {
+    let dir_names = |dir: &Path| -> Vec<String> {
+        fs::read_dir(dir).into_iter().flatten().flatten()
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect()
+    };
+    let mut repos = Vec::new();
+    for name in dir_names(&store.repos_root) {
+        if store.repo_index_path(&name).is_file() { repos.push(name); continue; }
+        // A directory without an index may be a scope holding org/repo directories; the cache, which
+        // mirrors remote repos by name, and the profiles are not
+        if name == "cache" || name == "profiles" { continue; }
+        repos.extend(dir_names(&store.repos_root.join(&name)).into_iter()
+            .map(|repo| format!("{}/{}", name, repo))
+            .filter(|scoped| store.repo_index_path(scoped).is_file()));
+    }
+    let registry = load_registry(store);
+    repos.extend(registry.iter().filter(|(_, entry)| entry.url.is_some()).map(|(name, _)| name.clone()));
+    repos.sort();
+    repos.dedup();
+    repos.sort_by_key(|name| std::cmp::Reverse(repo_priority(&registry, name)));
+    repos
+}
+
+fn repo_priority(registry: &Registry, name: &str) -> i64 {
+    registry.get(name).and_then(|entry| entry.priority).unwrap_or(0)
+}
+
+fn load_registry(store: &Store) -> Registry {
+    let path = store.registry_path();
+    if path.exists() {
+        let content = fs::read_to_string(&path).unwrap();
+        serde_json::from_str(&content).unwrap_or_default()
     } else {
-        println!("Package {} not found in repo {}", package, repo_name);
+        Registry::new()
     }
 }
 
-// Remove package
-fn remove_package(bpm_store: &Path, package: &str) {
-    let mut db = load_installed(&bpm_store.join("installed.json"));
-    if let Some(pkg) = db.remove(package) {
-        for bin in &pkg.binaries { let _ = fs::remove_file(bin); }
-        save_installed(&bpm_store.join("installed.json"), &db);
-        println!("Removed package {}", package);
-    } else { println!("Package {} is not installed.", package); }
+// Written to a temp file and renamed over repos.json, so a crash never leaves it half-written
+fn save_registry(store: &Store, registry: &Registry) {
+    let content = serde_json::to_string_pretty(registry).unwrap();
+    let tmp = tmp_sibling(&store.registry_path());
+    fs::write(&tmp, content).unwrap();
+    fs::rename(&tmp, store.registry_path()).unwrap();
 }
 
-// Update package
-fn update_package(bpm_store: &Path, repo_name: &str, package: &str) {
-    remove_package(bpm_store, package);
-    install_package(bpm_store, repo_name, package, None);
+// --- Holds ---
+// A held package is left at its installed version by /u, /upgrade and /diff --apply
+fn load_holds(store: &Store) -> BTreeSet<String> {
+    fs::read_to_string(store.holds_path()).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
 }
 
-// List installed packages
-fn list_installed(bpm_store: &Path) {
-    let db = load_installed(&bpm_store.join("installed.json"));
-    if db.is_empty() { println!("No packages installed."); }
-    else {
-        println!("Installed packages:");
-        for (name, pkg) in db {
-            println!("{} ({}): {:?}", name, pkg.version, pkg.binaries);
+fn save_holds(store: &Store, holds: &BTreeSet<String>) {
+    let content = serde_json::to_string_pretty(holds).unwrap();
+    fs::write(store.holds_path(), content).unwrap();
+}
+
+fn hold_command(store: &Store, args: &[String], hold: bool) {
+    let mut holds = load_holds(store);
+    if hold && args.first().is_some_and(|arg| arg == "--list") {
+        if holds.is_empty() { println!("No packages are held."); }
+        for name in &holds {
+            match store.installed.get(name) {
+                Some(pkg) => println!("{} {} ({})", name, pkg.version, pkg.repo),
+                None => println!("{} (not installed)", name),
+            }
+        }
+        return;
+    }
+    if args.is_empty() {
+        println!("Usage: bpm {} <package>...", if hold { "/hold" } else { "/unhold" });
+        return;
+    }
+    for name in args {
+        let changed = if hold { holds.insert(name.clone()) } else { holds.remove(name) };
+        match (hold, changed) {
+            (true, true) => println!("Holding {}", name),
+            (true, false) => println!("{} is already held", name),
+            (false, true) => println!("Released {}", name),
+            (false, false) => println!("{} is not held", name),
         }
     }
+    save_holds(store, &holds);
 }
 
-// CLI entry
-fn main() {
-    let bpm_store = PathBuf::from("C:/Users/User/Bpm-Store");
-    let args: Vec<String> = std::env::args().collect();
+fn repo_enabled(registry: &Registry, repo_name: &str) -> bool {
+    registry.get(repo_name).is_none_or(|entry| entry.enabled)
+}
 
-    if args.len() < 2 {
-        println!("Usage: bpm /i|/r|/u|/l <repo:package[:version]> | /v, /h = help");
-        return;
+// Explicit repo:package installs from a disabled repo need --force
+fn check_repo_enabled(store: &Store, repo_name: &str, opts: &Opts) -> bool {
+    if repo_enabled(&load_registry(store), repo_name) { return true; }
+    if opts.force {
+        warn("disabled-repo", format!("Repo {} is disabled, installed from anyway (--force)", repo_name));
+        true
+    } else {
+        println!("Repo {} is disabled. Enable it with /repo enable {} or pass --force.", repo_name, repo_name);
+        false
     }
+}
 
-    match args[1].as_str() {
-        "/i" => {
-            if args.len() < 3 { println!("Usage: bpm /i <repo:package[:version]>"); return; }
-            let (repo, package, version) = parse_pkg_arg(&args[2]);
-            install_package(&bpm_store, &repo, &package, version.as_deref());
-        }
-        "/r" => {
-            if args.len() < 3 { println!("Usage: bpm /r <package>"); return; }
-            remove_package(&bpm_store, &args[2]);
+// Reads an index, gunzipping it first if it starts with the gzip magic bytes
+fn load_index(path: &Path) -> Result<Repo, String> {
+    let raw = fs::read(path).map_err(|_| format!("Failed to read {}", path.display()))?;
+    parse_index(raw, &path.display())
+}
+
+// `source` only labels error messages: a path, or a URL for /repo ping
+fn parse_index(raw: Vec<u8>, source: &dyn std::fmt::Display) -> Result<Repo, String> {
+    let bytes = if raw.starts_with(&[0x1f, 0x8b]) {
+        let mut out = Vec::new();
+        GzDecoder::new(raw.as_slice()).read_to_end(&mut out)
+            .map_err(|e| format!("Failed to decompress {}: {}", source, e))?;
+        out
+    } else {
+        raw
+    };
+    let toml_content = String::from_utf8(bytes).map_err(|_| format!("{} is not valid UTF-8", source))?;
+    let mut table: toml::Table = toml::from_str(&toml_content)
+        .map_err(|e| format!("Failed to parse {}: {}", source, e))?;
+
+    let schema_version = match table.remove("schema_version") {
+        None => 1,
+        Some(toml::Value::Integer(v)) if v >= 1 => v as u32,
+        Some(other) => return Err(format!("Invalid schema_version {} in {}", other, source)),
+    };
+
+    let mut repo = if schema_version <= INDEX_SCHEMA_VERSION {
+        Repo::deserialize(toml::Value::Table(table))
+            .map_err(|e| format!("Failed to parse {}: {}", source, e))?
+    } else {
+        warn("index", format!("{} uses index schema v{}, this bpm understands v{}; loaded what it could",
+            source, schema_version, INDEX_SCHEMA_VERSION));
+        let mut packages = BTreeMap::new();
+        for (name, value) in table {
+            match BTreeMap::<String, PackageVersion>::deserialize(value) {
+                Ok(versions) => { packages.insert(name, versions); }
+                Err(e) => warn("index", format!("Skipped package {} of {}: {}", name, source, e.to_string().trim().replace('\n', " "))),
+            }
         }
-        "/u" => {
-            if args.len() < 3 { println!("Usage: bpm /u <repo:package>"); return; }
-            let (repo, package, _) = parse_pkg_arg(&args[2]);
-            update_package(&bpm_store, &repo, &package);
+        Repo { schema_version, packages }
+    };
+    repo.schema_version = schema_version;
+    Ok(repo)
+}
+
+// Where a repo's index lives: the cached copy for remote repos, the store directory otherwise
+fn index_path(store: &Store, repo_name: &str) -> PathBuf {
+    match load_registry(store).get(repo_name).and_then(|entry| entry.url.as_ref()) {
+        Some(_) => store.cached_index_path(repo_name),
+        None => store.repo_index_path(repo_name),
+    }
+}
+
+fn load_repo(store: &Store, repo_name: &str) -> Option<Repo> {
+    load_index(&index_path(store, repo_name)).ok()
+}
+
+fn is_remote(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// --- Pick the repo for a bare package name, asking when several provide it ---
+fn select_repo(store: &Store, package: &str, opts: &Opts) -> Option<String> {
+    let registry = load_registry(store);
+    // The default repo answers first when it has the package; only otherwise are the others searched
+    if let Some(default) = store.config.default_repo.as_ref().filter(|name| repo_enabled(&registry, name))
+        && load_repo(store, default).is_some_and(|repo| {
+            !matches!(match_name("Package", repo.packages.keys().map(String::as_str), package, &store.config), Ok(None))
+        })
+    {
+        return Some(default.clone());
+    }
+    let candidates: Vec<String> = list_repos(store).into_iter()
+        .filter(|name| repo_enabled(&registry, name))
+        .filter(|name| load_repo(store, name).is_some_and(|repo| {
+            // An ambiguous name counts as found, so the install reports the ambiguity
+            !matches!(match_name("Package", repo.packages.keys().map(String::as_str), package, &store.config), Ok(None))
+        }))
+        .collect();
+
+    match candidates.len() {
+        0 => { println!("Package {} not found in any repo", package); None }
+        1 => Some(candidates[0].clone()),
+        _ if opts.yes || !io::stdin().is_terminal() => {
+            say(opts, &format!("Multiple repos provide {}, using {} (priority order)", package, candidates[0]));
+            Some(candidates[0].clone())
         }
-        "/l" => list_installed(&bpm_store),
-        "/v" => println!("bpm ver: {}", BPM_VERSION),
-        "/h" => {
-            println!("Blur Package Manager | Help Menu\n/i = install\n /r = remove\n  /u = update\n   /l = list installed packages\n    /v = shows version\n     /h = shows this menu");
+        _ => {
+            let items: Vec<String> = candidates.iter().map(|repo| format!("{}:{}", repo, package)).collect();
+            prompt_choice(&format!("Multiple repos provide {}:", package), &items).map(|i| candidates[i].clone())
         }
-        _ => println!("Unknown command. Use /i, /r, /u, /l"),
+    }
+}
+
+// Exact names always win. Otherwise, unless case_sensitive is set, a name equal to one candidate
+// ignoring case means that candidate, spelled as the index or store has it; equal to several is an error.
+fn match_name<'a>(kind: &str, candidates: impl IntoIterator<Item = &'a str>, wanted: &str, config: &Config) -> Result<Option<&'a str>, String> {
+    let wanted_folded = wanted.to_lowercase();
+    let mut folded = Vec::new();
+    for candidate in candidates {
+        if candidate == wanted { return Ok(Some(candidate)); }
+        if !config.case_sensitive && candidate.to_lowercase() == wanted_folded { folded.push(candidate); }
+    }
+    match folded.as_slice() {
+        [] => Ok(None),
+        [one] => Ok(Some(one)),
+        many => Err(format!("{} name {} is ambiguous: {} differ only by case", kind, wanted, many.join(", "))),
+    }
+}
+
+// Yes/no question; --yes answers it, and without a terminal the answer is no
+fn confirm(question: &str, opts: &Opts) -> bool {
+    if opts.yes { return true; }
+    if !io::stdin().is_terminal() {
+        println!("{} Pass --yes to confirm.", question);
+        return false;
+    }
+    print!("{} [y/N]: ", question);
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).unwrap();
+    matches!(line.trim(), "y" | "Y" | "yes")
+}
+
+// Numbered menu; returns the index picked
+fn prompt_choice(header: &str, candidates: &[String]) -> Option<usize> {
+    println!("{}", header);
+    for (i, item) in candidates.iter().enumerate() {
+        println!("  {}) {}", i + 1, item);
+    }
+    print!("Select [1-{}]: ", candidates.len());
+    io::stdout().flush().unwrap();
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).unwrap();
+    match line.trim().parse::<usize>() {
+        Ok(n) if n >= 1 && n <= candidates.len() => Some(n - 1),
+        _ => { println!("Invalid selection."); None }
+    }
+}
+
+// --- Progress output ---
+// Animated bar on a terminal only; piped/CI output, --quiet and --no-progress get no control codes
+fn progress_hidden(opts: &Opts) -> bool {
+    opts.quiet || opts.json || opts.no_progress || !io::stdout().is_terminal()
+}
+
+fn progress_bar(len: u64, msg: &str, config: &Config, opts: &Opts) -> ProgressBar {
+    if progress_hidden(opts) {
+        if !opts.quiet && !opts.json { println!("{}...", msg); }
+        ProgressBar::hidden()
+    } else {
+        // Warn before the bar is drawn, and only once per run
+        let style = progress_style(config.progress_style.as_deref()).unwrap_or_else(|e| {
+            static WARNED: OnceLock<()> = OnceLock::new();
+            if WARNED.set(()).is_ok() { eprintln!("{} {}; using the default progress style", paint("⚠", YELLOW), e); }
+            progress_style(None).unwrap()
+        });
+        let pb = create_progress_bar(len, msg);
+        pb.set_style(style);
+        pb
+    }
+}
+
+// calcbits' own layout, except the message is a {wide_msg} that indicatif trims to fit narrow terminals
+const DEFAULT_PROGRESS_TEMPLATE: &str =
+    "[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({percent}%) {wide_msg} ETA:{eta_precise} {per_sec}";
+
+// calcbits' save_to_db always draws its own "Saving to DB" bar, so where bars are off the
+// same plain entry is written here instead
+fn save_binary_to_db(store: &Store, name: &str, data: &[u8], opts: &Opts) -> io::Result<()> {
+    let db_file = store.packages_db_path();
+    if !progress_hidden(opts) { return save_to_db(&db_file.to_string_lossy(), name, data, false); }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(db_file)?;
+    write_db_entry(&mut file, name, data)
+}
+
+// calcbits' non-quantum layout: hex bytes, 16 to a line
+fn write_db_entry(out: &mut impl Write, name: &str, data: &[u8]) -> io::Result<()> {
+    writeln!(out, "---ENTRY---")?;
+    writeln!(out, "NAME:{}", name)?;
+    writeln!(out, "SIZE:{}", data.len())?;
+    write!(out, "DATA: ")?;
+    for (i, b) in data.iter().enumerate() {
+        write!(out, "{:02X} ", b)?;
+        if (i + 1) % 16 == 0 { writeln!(out)?; }
+    }
+    writeln!(out, "\n---END---")
+}
+
+// progress_style from the config: a preset name, or any indicatif template
+fn progress_style(spec: Option<&str>) -> Result<ProgressStyle, String> {
+    let template = match spec.unwrap_or("default") {
+        "default" => DEFAULT_PROGRESS_TEMPLATE,
+        "minimal" => "{bar:20} {percent:>3}% {wide_msg}",
+        "spinner" => "{spinner} {wide_msg}",
+        custom if custom.contains('{') => custom,
+        other => return Err(format!("Unknown progress_style {:?}, expected default, minimal, spinner or a template", other)),
+    };
+    ProgressStyle::with_template(template)
+        .map(|style| style.progress_chars("=> "))
+        .map_err(|e| format!("Invalid progress_style template: {}", e))
+}
+
+// Spinner for work of unknown length; unlike the bar it prints nothing off a terminal
+fn spinner(msg: &str, opts: &Opts) -> ProgressBar {
+    if progress_hidden(opts) { return ProgressBar::hidden(); }
+    let pb = ProgressBar::new_spinner();
+    pb.set_message(msg.to_string());
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+fn finish_progress(pb: &ProgressBar, msg: String, opts: &Opts) {
+    if !pb.is_hidden() { pb.finish_with_message(msg); }
+    else if !opts.quiet && !opts.json { println!("{}", msg); }
+}
+
+// --- Network ---
+const DEFAULT_NETWORK_TIMEOUT: u64 = 30; // seconds
+
+fn net_error(url: &str, e: &reqwest::Error, config: &Config, opts: &Opts) -> String {
+    if e.is_timeout() {
+        format!("Timed out after {}s fetching {}", config.timeout(opts), url)
+    } else {
+        format!("Failed to fetch {}: {}", url, e)
+    }
+}
+
+// Credentials for a private repo. Deliberately not Debug, so they can't end up in output.
+enum Auth {
+    Bearer(String),
+    Basic(String, String),
+}
+
+// BPM_REPO_TOKEN_<NAME> wins, then the repo's token_env, then its token_file
+fn repo_auth(store: &Store, repo_name: &str) -> Result<Option<Auth>, String> {
+    let registry = load_registry(store);
+    let entry = registry.get(repo_name);
+    let convention = format!("BPM_REPO_TOKEN_{}", repo_name.to_uppercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_"));
+    let secret = if let Some(token) = std::env::var(&convention).ok().filter(|t| !t.is_empty()) {
+        token
+    } else if let Some(var) = entry.and_then(|e| e.token_env.as_ref()) {
+        std::env::var(var).map_err(|_| format!("Repo {} reads its token from ${}, which is not set", repo_name, var))?
+    } else if let Some(file) = entry.and_then(|e| e.token_file.as_ref()) {
+        fs::read_to_string(file).map_err(|_| format!("Failed to read the token file of repo {}", repo_name))?.trim().to_string()
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(match entry.and_then(|e| e.username.clone()) {
+        Some(user) => Auth::Basic(user, secret),
+        None => Auth::Bearer(secret),
+    }))
+}
+
+fn http_get(url: &str, auth: Option<&Auth>, config: &Config, opts: &Opts) -> Result<reqwest::blocking::Response, String> {
+    let resp = http_send(url, auth, config, opts)?;
+    if !resp.status().is_success() {
+        return Err(format!("Failed to fetch {}: HTTP {}", url, resp.status()));
+    }
+    Ok(resp)
+}
+
+// Any answer from the server, whatever its status
+fn http_send(url: &str, auth: Option<&Auth>, config: &Config, opts: &Opts) -> Result<reqwest::blocking::Response, String> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(config.timeout(opts)))
+        .build()
+        .map_err(|e| format!("Failed to set up HTTP client: {}", e))?;
+    let request = match auth {
+        Some(Auth::Bearer(token)) => client.get(url).bearer_auth(token),
+        Some(Auth::Basic(user, password)) => client.get(url).basic_auth(user, Some(password)),
+        None => client.get(url),
+    };
+    request.send().map_err(|e| net_error(url, &e, config, opts))
+}
+
+fn download(url: &str, auth: Option<&Auth>, config: &Config, opts: &Opts) -> Result<Vec<u8>, String> {
+    download_with(url, auth, config, opts, |pb| pb)
+}
+
+// `wrap` gets the new bar before any progress, e.g. to add it to a MultiProgress
+fn download_with(url: &str, auth: Option<&Auth>, config: &Config, opts: &Opts, wrap: impl FnOnce(ProgressBar) -> ProgressBar) -> Result<Vec<u8>, String> {
+    emit("downloading", serde_json::json!({ "url": url }));
+    let mut resp = http_get(url, auth, config, opts)?;
+
+    let pb = wrap(progress_bar(resp.content_length().unwrap_or(0), &format!("Downloading {}", url), config, opts));
+    let mut data = Vec::new();
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        // Body read errors are io::Errors wrapping the reqwest error (e.g. a timeout)
+        let n = resp.read(&mut buf).map_err(|e| match e.get_ref().and_then(|inner| inner.downcast_ref::<reqwest::Error>()) {
+            Some(inner) => net_error(url, inner, config, opts),
+            None => format!("Failed to fetch {}: {}", url, e),
+        })?;
+        if n == 0 { break; }
+        data.extend_from_slice(&buf[..n]);
+        pb.inc(n as u64);
+    }
+    finish_progress(&pb, format!("Downloaded {} bytes", data.len()), opts);
+    Ok(data)
+}
+
+// --- Phase 1: resolve the full install plan without touching the store ---
+#[derive(Debug, Default, Serialize)]
+struct PlanStep {
+    package: String,
+    version: String,
+    repo: String,
+    explicit: bool, // false for packages pulled in as dependencies
+    size: u64,      // bytes to copy
+    #[serde(skip)]
+    path: String,
+    #[serde(skip)]
+    binaries: Vec<String>,
+    #[serde(skip)]
+    checksums: BTreeMap<String, String>,
+    #[serde(skip)]
+    requires_env: Vec<String>,
+    #[serde(skip)]
+    mode: Option<u32>,
+    #[serde(skip)]
+    index_sha256: Option<String>,
+    #[serde(skip)]
+    index_schema: u32,
+    #[serde(skip)]
+    dependencies: Vec<String>, // resolved "name:version", filled in by the resolver
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    replaces: Vec<String>, // installed packages this step supersedes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deprecated: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Plan {
+    install: Vec<PlanStep>, // in install order, dependencies first
+    external: Vec<String>,  // dependencies provided outside bpm (--assume-installed)
+    conflicts: Vec<String>,
+    total_size: u64,
+    #[serde(skip)]
+    contested: Vec<Contest>, // one per conflict, for --interactive
+}
+
+// Both sides of a version conflict, as (repo, version)
+#[derive(Debug, Clone)]
+struct Contest {
+    package: String,
+    planned: (String, String),
+    wanted: (String, String),
+}
+
+// The one line printed before a plan is installed
+fn plan_summary(plan: &Plan) -> String {
+    let deps = plan.install.iter().filter(|step| !step.explicit).count();
+    format!("Will install {} packages ({} requested, {} dependencies), {} bytes",
+        plan.install.len(), plan.install.len() - deps, deps, plan.total_size)
+}
+
+// Status lines go to stderr in --json mode so stdout stays parseable
+fn say(opts: &Opts, msg: &str) {
+    if opts.json { eprintln!("{}", msg); } else { println!("{}", msg); }
+}
+
+// --- Versions and dependency specs ---
+// Dot-separated segments, numeric where possible, so 0.10.0 > 0.9.0
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_parts, b_parts): (Vec<&str>, Vec<&str>) = (a.split('.').collect(), b.split('.').collect());
+    for i in 0..a_parts.len().max(b_parts.len()) {
+        let (x, y) = (a_parts.get(i).copied().unwrap_or("0"), b_parts.get(i).copied().unwrap_or("0"));
+        let ord = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.cmp(y),
+        };
+        if ord != Ordering::Equal { return ord; }
+    }
+    Ordering::Equal
+}
+
+// "1.2.0" means exactly that version; ">=1.0", ">1.0", "<=2", "<2" and "=1.0" are constraints
+fn version_matches(version: &str, constraint: &str) -> bool {
+    let ord = |wanted: &str| compare_versions(version, wanted.trim());
+    if let Some(v) = constraint.strip_prefix(">=") { ord(v) != Ordering::Less }
+    else if let Some(v) = constraint.strip_prefix("<=") { ord(v) != Ordering::Greater }
+    else if let Some(v) = constraint.strip_prefix('>') { ord(v) == Ordering::Greater }
+    else if let Some(v) = constraint.strip_prefix('<') { ord(v) == Ordering::Less }
+    else if let Some(v) = constraint.strip_prefix('=') { ord(v) == Ordering::Equal }
+    else { version == constraint }
+}
+
+fn latest_version<T>(versions: &BTreeMap<String, T>) -> Option<&str> {
+    versions.keys().max_by(|a, b| compare_versions(a, b)).map(String::as_str)
+}
+
+fn no_versions(package: &str, repo_name: &str) -> String {
+    format!("Package {} has no versions defined in repo {}", package, repo_name)
+}
+
+// Highest version satisfying the constraint, or the latest when there is none.
+// An exact version is returned as-is so a missing one is reported by name.
+fn select_version<'a, T>(versions: &'a BTreeMap<String, T>, constraint: Option<&'a str>) -> Option<&'a str> {
+    match constraint {
+        None => latest_version(versions),
+        Some(c) if !c.starts_with(['>', '<', '=']) => Some(c),
+        Some(c) => versions.keys().filter(|v| version_matches(v, c))
+            .max_by(|a, b| compare_versions(a, b)).map(String::as_str),
+    }
+}
+
+// `${VAR}` in index paths is replaced from the environment; `$$` is a literal `$`
+fn expand_env(input: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = input;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(r) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('{') {
+            let Some((name, tail)) = r.split_once('}') else {
+                return Err(format!("Unterminated ${{ in \"{}\"", input));
+            };
+            let value = std::env::var(name).map_err(|_| format!("Environment variable ${{{}}} is not set", name))?;
+            out.push_str(&value);
+            rest = tail;
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// Entries of `binaries` with `*`, `?` or `[` are globs relative to the package path, expanded to the
+// files they match; plain names pass through untouched
+fn expand_binaries(path: &str, binaries: &[String]) -> Result<Vec<String>, String> {
+    let mut out = Vec::new();
+    for entry in binaries {
+        if !entry.contains(['*', '?', '[']) { out.push(entry.clone()); continue; }
+        if is_remote(path) { return Err(format!("Binary glob {} needs a local package path", entry)); }
+        let pattern = format!("{}/{}", glob::Pattern::escape(path.trim_end_matches(['/', '\\'])), entry);
+        let paths = glob::glob(&pattern).map_err(|e| format!("Invalid binary glob {}: {}", entry, e))?;
+        let mut matched: Vec<String> = paths.filter_map(Result::ok)
+            .filter(|file| file.is_file())
+            .filter_map(|file| file.strip_prefix(path).ok().map(|rel| rel.to_string_lossy().replace('\\', "/")))
+            .collect();
+        if matched.is_empty() { return Err(format!("Binary glob {} matches nothing", entry)); }
+        matched.sort();
+        out.extend(matched);
+    }
+    Ok(out)
+}
+
+// A dependency: "name", "name:version" or "repo/name[:version]" to pin the repo
+struct DepSpec<'a> {
+    repo: Option<&'a str>,
+    name: &'a str,
+    version: Option<&'a str>,
+}
+
+fn parse_dep(dep: &str) -> Result<DepSpec<'_>, String> {
+    let (head, version) = match dep.split_once(':') {
+        Some((head, version)) => (head, Some(version)),
+        None => (dep, None),
+    };
+    // The package is after the last slash, so scoped repos work: org/repo/name
+    let (repo, name) = match head.rsplit_once('/') {
+        Some((repo, name)) => (Some(repo), name),
+        None => (None, head),
+    };
+    let bad = |part: &str| part.is_empty() || part.contains(['/', ':']);
+    if bad(name) || repo.is_some_and(|repo| check_repo_name(repo).is_err()) || version.is_some_and(|version| version.is_empty() || version.contains(':')) {
+        return Err(format!("Ambiguous dependency \"{}\", expected name[:version] or repo/name[:version]", dep));
+    }
+    Ok(DepSpec { repo, name, version })
+}
+
+// `name` or `repo/name` pointing back at the package that lists it, whatever the version.
+// Names are matched the way the resolver would look them up in `repo`, so a differently
+// cased spelling counts unless case_sensitive is set.
+fn is_self_dependency(spec: &DepSpec, repo_name: &str, package: &str, repo: &Repo, config: &Config) -> bool {
+    let same_repo = spec.repo.is_none_or(|wanted| match_name("Repo", [repo_name], wanted, config).ok().flatten().is_some());
+    same_repo && match_name("Package", repo.packages.keys().map(String::as_str), spec.name, config).ok().flatten() == Some(package)
+}
+
+// Where the resolver reads indexes and package files from. The store is the real source; keeping
+// it behind a trait lets resolution run against in-memory repos as well.
+trait RepoProvider {
+    fn repo(&self, name: &str) -> Result<Repo, String>;
+    fn index_sha256(&self, name: &str) -> Option<String>;
+    // The local filesystem, with ${VAR}s from the environment, unless a provider knows better
+    fn package_files(&self, key: &str, pkg: &PackageVersion) -> Result<PackageFiles, String> {
+        local_package_files(key, pkg)
+    }
+}
+
+// What a package version's path and binary globs come to
+struct PackageFiles {
+    path: String,
+    binaries: Vec<String>,
+    size: u64, // bytes to copy
+}
+
+fn local_package_files(key: &str, pkg: &PackageVersion) -> Result<PackageFiles, String> {
+    let path = expand_env(&pkg.path).map_err(|e| format!("{} in the path of {}", e, key))?;
+    let binaries = expand_binaries(&path, &pkg.binaries).map_err(|e| format!("{} in {}", e, key))?;
+    let size = binaries.iter()
+        .map(|bin| fs::metadata(Path::new(&path).join(bin)).map(|m| m.len()).unwrap_or(0))
+        .sum();
+    Ok(PackageFiles { path, binaries, size })
+}
+
+// What the resolver has to say while it works. It prints nothing itself; the CLI shows these as
+// a spinner, NDJSON lines, warnings and -vv traces, and `()` ignores them. Missing packages are
+// errors, not events.
+trait ResolveEvents {
+    fn planned(&mut self, _step: &PlanStep, _count: usize) {}
+    fn warning(&mut self, _kind: &'static str, _message: String) {}
+    fn trace(&mut self, _line: String) {}
+}
+
+impl ResolveEvents for () {}
+
+struct CliEvents {
+    progress: ProgressBar,
+    stream: bool, // print each step as NDJSON the moment it is planned
+}
+
+impl CliEvents {
+    fn new(opts: &Opts) -> Self {
+        CliEvents { progress: spinner("Resolving dependencies...", opts), stream: false }
+    }
+}
+
+impl ResolveEvents for CliEvents {
+    fn planned(&mut self, step: &PlanStep, count: usize) {
+        if self.stream { println!("{}", stream_line("node", serde_json::to_value(step).unwrap())); }
+        self.progress.set_message(format!("Resolving dependencies... {} packages", count));
+    }
+    fn warning(&mut self, kind: &'static str, message: String) { warn(kind, message); }
+    fn trace(&mut self, line: String) { eprintln!("trace: {}", line); }
+}
+
+// Clears the spinner however resolution ends
+impl Drop for CliEvents {
+    fn drop(&mut self) { self.progress.finish_and_clear(); }
+}
+
+impl RepoProvider for Store {
+    fn repo(&self, name: &str) -> Result<Repo, String> {
+        let index = index_path(self, name);
+        if !index.exists() && load_registry(self).get(name).is_some_and(|entry| entry.url.is_some()) {
+            return Err(format!("Index for remote repo {} is not cached yet, run /repo refresh {}", name, name));
+        }
+        load_index(&index)
+    }
+    fn index_sha256(&self, name: &str) -> Option<String> { sha256_file(&index_path(self, name)) }
+}
+
+// Walks the dependency graph depth-first, dependencies before dependents
+// Far beyond any sane chain, low enough to stop a runaway index
+const DEFAULT_MAX_DEPTH: usize = 64;
+
+struct Resolver<'a> {
+    repos: &'a dyn RepoProvider,
+    db: InstalledDb,
+    config: &'a Config,
+    opts: &'a Opts,
+    plan: Plan,
+    chain: Vec<String>,       // current dependency chain, for cycle and depth checks
+    overrides: BTreeMap<String, (String, String)>, // package -> (repo, version) forced by an --interactive conflict choice
+    events: &'a mut dyn ResolveEvents,
+}
+
+fn resolve_plan(
+    store: &Store,
+    repo_name: &str,
+    package: &str,
+    version: Option<&str>,
+    opts: &Opts,
+) -> Result<Plan, String> {
+    resolve_interactive(store, store, repo_name, package, version, opts)
+}
+
+// Resolution on its own: reads indexes and package files only through `repos`, treats `db` as
+// what is already installed, forces the `overrides` versions, and reports only through `events`.
+// `config` is the resolving store's, for case_sensitive and assume_installed.
+// Nothing is written or printed; the installer executes the returned plan.
+#[allow(clippy::too_many_arguments)]
+fn resolve(
+    repos: &dyn RepoProvider,
+    db: InstalledDb,
+    overrides: &BTreeMap<String, (String, String)>,
+    repo_name: &str,
+    package: &str,
+    version: Option<&str>,
+    config: &Config,
+    opts: &Opts,
+    events: &mut dyn ResolveEvents,
+) -> Result<Plan, String> {
+    let mut resolver = Resolver::with_repos(repos, db, config, opts, events);
+    resolver.overrides = overrides.clone();
+    resolver.visit(repo_name, package, version, true)?;
+    Ok(resolver.finish())
+}
+
+// The CLI's resolution: progress on the terminal, and with --interactive each conflict turned
+// into a choice, resolving again with that version forced
+fn resolve_interactive(
+    store: &Store,
+    repos: &dyn RepoProvider,
+    repo_name: &str,
+    package: &str,
+    version: Option<&str>,
+    opts: &Opts,
+) -> Result<Plan, String> {
+    let mut choose = |header: &str, items: &[String]| if opts.yes { Some(0) } else { prompt_choice(header, items) };
+    resolve_choosing(store, repos, repo_name, package, version, opts, &mut choose)
+}
+
+// The --interactive loop with the question asked through `choose`; None keeps the conflict
+fn resolve_choosing(
+    store: &Store,
+    repos: &dyn RepoProvider,
+    repo_name: &str,
+    package: &str,
+    version: Option<&str>,
+    opts: &Opts,
+    choose: &mut dyn FnMut(&str, &[String]) -> Option<usize>,
+) -> Result<Plan, String> {
+    let db = store.installed.load();
+    let mut overrides = BTreeMap::new();
+    loop {
+        let mut events = CliEvents::new(opts);
+        events.stream = streaming(opts);
+        let plan = resolve(repos, db.clone(), &overrides, repo_name, package, version, &store.config, opts, &mut events)?;
+        drop(events);
+        let Some(Contest { package: name, planned, wanted }) = plan.contested.first().filter(|_| opts.interactive).cloned() else {
+            return Ok(plan);
+        };
+        let items = [
+            format!("{}:{}:{} (planned first)", planned.0, name, planned.1),
+            format!("{}:{}:{}", wanted.0, name, wanted.1),
+        ];
+        let header = format!("Conflict: {} is needed at both {} and {}. Which should every dependent get?", name, planned.1, wanted.1);
+        let Some(i) = choose(&header, &items) else { return Ok(plan); };
+        let choice = if i == 0 { planned } else { wanted };
+        say(opts, &format!("Forcing {}:{}:{} for this install", choice.0, name, choice.1));
+        overrides.insert(name, choice);
+    }
+}
+
+impl<'a> Resolver<'a> {
+    fn new(store: &'a Store, events: &'a mut dyn ResolveEvents, opts: &'a Opts) -> Self {
+        Resolver::with_repos(store, store.installed.load(), &store.config, opts, events)
+    }
+
+    fn with_repos(repos: &'a dyn RepoProvider, db: InstalledDb, config: &'a Config, opts: &'a Opts, events: &'a mut dyn ResolveEvents) -> Self {
+        Resolver {
+            repos,
+            db,
+            config,
+            opts,
+            plan: Plan::default(),
+            chain: Vec::new(),
+            overrides: BTreeMap::new(),
+            events,
+        }
+    }
+
+    fn finish(mut self) -> Plan {
+        let mut plan = std::mem::take(&mut self.plan);
+        plan.total_size = plan.install.iter().map(|step| step.size).sum();
+        plan
+    }
+
+    // Whether an installed package can stand in for a dependency: the version constraint and repo pin both hold
+    fn satisfies(&self, installed: &InstalledPackage, spec: &DepSpec) -> bool {
+        let same_repo = |repo: &str| repo == installed.repo || (!self.config.case_sensitive && repo.eq_ignore_ascii_case(&installed.repo));
+        spec.version.is_none_or(|constraint| version_matches(&installed.version, constraint))
+            && spec.repo.is_none_or(same_repo)
+    }
+
+    // A missing dependency names its dependent; anything missing is an error, since installing
+    // without it would leave something broken or install nothing at all
+    fn missing(&self, msg: String) -> Result<(), String> {
+        match self.chain.last() {
+            Some(dependent) => Err(format!("{}, required by {}", msg, dependent)),
+            None => Err(msg),
+        }
+    }
+
+    // One -vv line per version decision: what was asked for, by whom, what was on offer and why the winner won
+    fn trace(&mut self, repo_name: &str, package: &str, versions: &BTreeMap<String, PackageVersion>, constraint: Option<&str>, selected: Option<&str>) {
+        let mut candidates: Vec<&str> = versions.keys().map(String::as_str).collect();
+        candidates.sort_by(|a, b| compare_versions(a, b));
+        let wanted_by = self.chain.last().map_or("the command line", String::as_str);
+        let reason = match (constraint, selected) {
+            (_, None) => "nothing matches".to_string(),
+            (None, Some(_)) => "latest".to_string(),
+            (Some(c), Some(_)) if !c.starts_with(['>', '<', '=']) => "pinned".to_string(),
+            (Some(c), Some(v)) => {
+                let rejected: Vec<&str> = candidates.iter().copied()
+                    .filter(|candidate| compare_versions(candidate, v) == Ordering::Greater).collect();
+                if rejected.is_empty() { format!("highest matching {}", c) }
+                else { format!("highest matching {}; newer {} excluded", c, rejected.join(", ")) }
+            }
+        };
+        let line = format!("{} from {} wanted by {} as {}: candidates {}; chose {} ({})",
+            package, repo_name, wanted_by, constraint.unwrap_or("any version"), candidates.join(", "),
+            selected.unwrap_or("none"), reason);
+        self.events.trace(line);
+        if let Some(planned) = self.plan.install.iter().find(|step| step.package == package) {
+            let line = format!("{} is already planned at {}, that plan stands", package, planned.version);
+            self.events.trace(line);
+        }
+    }
+
+    // The one spelling of a dependency that every lookup uses, however the dependent cased it:
+    // its repo's, else an installed package's. `repo` is the dependent's, already loaded.
+    fn dep_name(&self, repo: &Repo, repo_name: &str, dep_repo: &str, name: &str) -> Result<String, String> {
+        let other;
+        let packages = if dep_repo == repo_name { &repo.packages } else {
+            other = self.repos.repo(dep_repo).map(|repo| repo.packages).unwrap_or_default();
+            &other
+        };
+        let found = match match_name("Package", packages.keys().map(String::as_str), name, self.config)? {
+            Some(found) => Some(found),
+            None => match_name("Package", self.db.keys().map(String::as_str), name, self.config)?,
+        };
+        Ok(found.unwrap_or(name).to_string())
+    }
+
+    fn visit(&mut self, repo_name: &str, package: &str, version: Option<&str>, explicit: bool) -> Result<(), String> {
+        // A version picked with --interactive wins over whatever this dependent asked for
+        let forced = self.overrides.get(package).cloned();
+        let repo_name = forced.as_ref().map_or(repo_name, |(repo, _)| repo.as_str());
+        let version = forced.as_ref().map(|(_, version)| version.as_str()).or(version);
+        let repo = self.repos.repo(repo_name)?;
+        // Planned and recorded under the index's own spelling, whatever was typed
+        let canonical = match_name("Package", repo.packages.keys().map(String::as_str), package, self.config)?.map(str::to_string);
+        let package = canonical.as_deref().unwrap_or(package);
+
+        if let Some(versions) = repo.packages.get(package) {
+            // A bare `[name]` table is a broken index, not a version that happens not to match
+            if versions.is_empty() { return Err(no_versions(package, repo_name)); }
+            let selected = select_version(versions, version);
+            if self.opts.trace { self.trace(repo_name, package, versions, version, selected); }
+            let Some(ver) = selected else {
+                return self.missing(format!("No version of {} matches {}", package, version.unwrap_or_default()));
+            };
+            if let Some(pkg) = versions.get(ver) {
+                // --- Detect cycle ---
+                let key = format!("{}:{}", package, ver);
+                if let Some(start) = self.chain.iter().position(|k| *k == key) {
+                    let cycle = format!("{} -> {}", self.chain[start..].join(" -> "), key);
+                    if !self.opts.allow_cycles { return Err(format!("Dependency cycle: {}", cycle)); }
+                    self.events.warning("cycle", format!("Circular dependency: {}", cycle));
+                    return Ok(());
+                }
+
+                // --- Shared dependency already planned (diamond) ---
+                if let Some(planned) = self.plan.install.iter().find(|step| step.package == package) {
+                    if planned.version != ver {
+                        let conflict = format!("{} is needed at {} but {} is already planned", package, ver, planned.version);
+                        self.plan.conflicts.push(conflict);
+                        self.plan.contested.push(Contest {
+                            package: package.to_string(),
+                            planned: (planned.repo.clone(), planned.version.clone()),
+                            wanted: (repo_name.to_string(), ver.to_string()),
+                        });
+                    }
+                    return Ok(());
+                }
+                self.chain.push(key.clone());
+                let max_depth = self.opts.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+                if self.chain.len() > max_depth {
+                    return Err(format!("Dependency chain deeper than {}: {}", max_depth, self.chain.join(" -> ")));
+                }
+
+                // --- 1. Dependencies go first ---
+                let mut resolved = Vec::new();
+                for dep in &pkg.dependencies {
+                    let spec = parse_dep(dep)?;
+                    // A packaging bug rather than a real cycle, so --allow-cycles doesn't cover it
+                    if is_self_dependency(&spec, repo_name, package, &repo, self.config) {
+                        return Err(format!("{} depends on itself (\"{}\" in its dependencies)", key, dep));
+                    }
+                    let dep_repo = spec.repo.unwrap_or(repo_name);
+                    let name = self.dep_name(&repo, repo_name, dep_repo, spec.name)?;
+                    if self.config.assumed_installed(self.opts, &name) {
+                        if !self.plan.external.contains(&name) { self.plan.external.push(name.clone()); }
+                        resolved.push(name);
+                        continue;
+                    } else if !self.db.get(&name).is_some_and(|installed| self.satisfies(installed, &spec)) {
+                        // Not installed, or installed at a version or from a repo this dependent doesn't accept
+                        self.visit(dep_repo, &name, spec.version, false)?;
+                    } else {
+                        let kept = &self.db[&name].version;
+                        let (warning, line) = (format!("Dependency {} of {} already installed, kept at {}", name, key, kept),
+                            format!("{} wanted by {}: already installed at {}, kept", name, key, kept));
+                        self.events.warning("already-installed", warning);
+                        if self.opts.trace { self.events.trace(line); }
+                    }
+                    let version = self.plan.install.iter().find(|step| step.package == name).map(|step| &step.version)
+                        .or_else(|| self.db.get(&name).map(|pkg| &pkg.version));
+                    if let Some(version) = version { resolved.push(format!("{}:{}", name, version)); }
+                }
+
+                // --- 2. Then the package itself ---
+                let mut step = plan_step(self.repos, self.repos.index_sha256(repo_name), &repo, repo_name, package, ver, explicit)?;
+                step.dependencies = resolved;
+                step.replaces.retain(|name| self.db.contains_key(name));
+                self.events.planned(&step, self.plan.install.len() + 1);
+                self.plan.install.push(step);
+                self.chain.pop();
+            } else {
+                self.missing(format!("Version {} not found for package {}", ver, package))?;
+            }
+        } else {
+            self.missing(format!("Package {} not found in repo {}", package, repo_name))?;
+        }
+        Ok(())
+    }
+}
+
+// The plan entry for one version of a package, with its path and binary globs expanded by `repos`
+fn plan_step(repos: &dyn RepoProvider, index_sha256: Option<String>, repo: &Repo, repo_name: &str, package: &str, ver: &str, explicit: bool) -> Result<PlanStep, String> {
+    check_repo_name(repo_name)?;
+    for (kind, name) in [("package", package), ("version", ver)] { check_name(kind, name)?; }
+    let key = format!("{}:{}", package, ver);
+    let pkg = repo.packages.get(package).and_then(|versions| versions.get(ver))
+        .ok_or_else(|| format!("{} is not in repo {}", key, repo_name))?;
+    let PackageFiles { path, binaries, size } = repos.package_files(&key, pkg)?;
+    for bin in &binaries { check_binary_path(bin).map_err(|e| format!("{} in {}", e, key))?; }
+    Ok(PlanStep {
+        package: package.to_string(),
+        version: ver.to_string(),
+        repo: repo_name.to_string(),
+        explicit,
+        size,
+        path,
+        binaries,
+        checksums: pkg.checksums.clone(),
+        requires_env: pkg.requires_env.clone(),
+        mode: pkg.mode,
+        index_sha256,
+        index_schema: repo.schema_version,
+        dependencies: Vec::new(),
+        replaces: pkg.replaces.iter().filter(|name| *name != package).cloned().collect(),
+        deprecated: pkg.deprecated.clone(),
+    })
+}
+
+// --interactive can resolve several times over, which would repeat nodes, so it never streams
+fn streaming(opts: &Opts) -> bool {
+    opts.stream && opts.dry_run && opts.json && !opts.interactive
+}
+
+// An NDJSON line: the object's own fields plus `type`
+fn stream_line(kind: &str, value: serde_json::Value) -> serde_json::Value {
+    with_fields(serde_json::json!({ "type": kind }), value)
+}
+
+// `base` with the fields of another JSON object added
+fn with_fields(mut base: serde_json::Value, fields: serde_json::Value) -> serde_json::Value {
+    if let (Some(base), serde_json::Value::Object(fields)) = (base.as_object_mut(), fields) { base.extend(fields); }
+    base
+}
+
+// The last NDJSON line, after every node
+fn stream_summary(plan: &Plan) -> serde_json::Value {
+    stream_line("summary", serde_json::json!({
+        "packages": plan.install.len(),
+        "external": plan.external,
+        "conflicts": plan.conflicts,
+        "total_size": plan.total_size,
+    }))
+}
+
+fn print_plan(plan: &Plan, opts: &Opts) {
+    // The nodes went out during resolution; what's left is the summary
+    if streaming(opts) {
+        println!("{}", json_document(stream_summary(plan)));
+        return;
+    }
+    if opts.json {
+        print_json(plan);
+        return;
+    }
+    println!("Would install:");
+    for step in &plan.install {
+        let kind = if step.explicit { "explicit" } else { "dependency" };
+        let replaces = if step.replaces.is_empty() { String::new() } else { format!(", replaces {}", step.replaces.join(", ")) };
+        let deprecated = if step.deprecated.is_some() { ", deprecated" } else { "" };
+        println!("  {}:{} from {} ({}, {} bytes{}{})", step.package, step.version, step.repo, kind, step.size, replaces, deprecated);
+    }
+    for name in &plan.external { println!("  {} (external, not managed by bpm)", name); }
+    for conflict in &plan.conflicts { println!("Conflict: {}", conflict); }
+    println!("Total: {} bytes", plan.total_size);
+}
+
+// Flat, de-duplicated list of everything a package pulls in, ignoring what is installed
+fn list_deps(store: &Store, repo_name: &str, package: &str, version: Option<&str>, opts: &Opts) -> Result<(), String> {
+    let mut events = CliEvents::new(opts);
+    let mut resolver = Resolver::new(store, &mut events, opts);
+    resolver.db.clear();
+    resolver.visit(repo_name, package, version, true)?;
+    let plan = resolver.finish();
+    if let Some(conflict) = plan.conflicts.first() { return Err(conflict.clone()); }
+
+    let mut deps: Vec<String> = plan.install.iter().filter(|step| !step.explicit)
+        .map(|step| format!("{}:{}", step.package, step.version))
+        .collect();
+    deps.sort();
+    if opts.json {
+        print_json(&deps);
+    } else if deps.is_empty() {
+        println!("{} has no dependencies.", package);
+    } else {
+        for dep in deps { println!("{}", dep); }
+    }
+    Ok(())
+}
+
+// --- Phase 2: execute the plan ---
+fn install_package(
+    store: &Store,
+    repo_name: &str,
+    package: &str,
+    version: Option<&str>,
+    opts: &Opts,
+) -> Result<(), String> {
+    let plan = resolve_plan(store, repo_name, package, version, opts)?;
+    install_plan(store, &plan, opts)
+}
+
+fn install_plan(store: &Store, plan: &Plan, opts: &Opts) -> Result<(), String> {
+    if opts.dry_run { print_plan(plan, opts); return Ok(()); }
+    if !plan.conflicts.is_empty() {
+        for conflict in &plan.conflicts { println!("Conflict: {}", conflict); }
+        return Err("Conflicting versions, nothing installed.".to_string());
+    }
+    // Catch a bad --as, and two binaries that would land on the same file, before anything is downloaded or copied.
+    // Files of installed packages count too, except those of packages this plan reinstalls or replaces.
+    let superseded: BTreeSet<&str> = plan.install.iter()
+        .flat_map(|step| std::iter::once(&step.package).chain(&step.replaces).map(String::as_str))
+        .collect();
+    let installed_owners: BTreeMap<PathBuf, String> = store.installed.iter()
+        .filter(|(name, _)| !superseded.contains(name.as_str()))
+        .flat_map(|(name, pkg)| pkg.binaries.into_iter().map(move |bin| (PathBuf::from(bin), name.clone())))
+        .collect();
+    let mut owners: BTreeMap<PathBuf, &str> = BTreeMap::new();
+    for step in &plan.install {
+        let aliases = step_aliases(step, store.installed.get(&step.package).as_ref(), opts)?;
+        for bin in &step.binaries {
+            let dest = bin_dest(store, step, bin, &aliases, opts);
+            if let Some(other) = installed_owners.get(&dest) {
+                return Err(format!("{} would overwrite {}, which belongs to installed package {}; nothing installed (remove {} first, or use --as)",
+                    step.package, dest.display(), other, other));
+            }
+            match owners.insert(dest.clone(), &step.package) {
+                Some(other) if other == step.package => return Err(format!("{} installs {} twice, nothing installed.", other, dest.display())),
+                Some(other) => return Err(format!("{} and {} both install {}, nothing installed.", other, step.package, dest.display())),
+                None => {}
+            }
+        }
+    }
+    if !opts.quiet { say(opts, &plan_summary(plan)); }
+    for step in &plan.install {
+        let unmet = unmet_requirements(&step.requires_env);
+        if unmet.is_empty() { continue; }
+        let msg = format!("{}:{} requires {}", step.package, step.version, unmet.join(", "));
+        if store.config.strict_env { return Err(format!("{}, nothing installed.", msg)); }
+        warn("requires-env", msg);
+    }
+    let replaced: Vec<String> = plan.install.iter()
+        .flat_map(|step| step.replaces.iter().map(move |old| format!("{} (replaced by {})", old, step.package)))
+        .collect();
+    if !replaced.is_empty() {
+        println!("Will remove: {}", replaced.join(", "));
+        if !confirm("Remove the replaced packages?", opts) { return Err("Replaced packages kept, nothing installed.".to_string()); }
+    }
+    emit("resolved", serde_json::json!({
+        "packages": plan.install.iter().map(|step| format!("{}:{}", step.package, step.version)).collect::<Vec<_>>(),
+        "bytes": plan.total_size,
+    }));
+    check_deprecated(plan, &store.config, opts)?;
+    check_security(store, plan)?;
+    prefetch(store, plan, opts)?;
+    for step in &plan.install {
+        if !step.explicit { println!("Installing dependency {}...", step.package); }
+        install_step(store, step, opts)?;
+        for old in &step.replaces { remove_replaced(store, old, &step.package); }
+    }
+    Ok(())
+}
+
+// Binaries without a published checksum, and remote indexes, which can't carry a signature
+fn check_security(store: &Store, plan: &Plan) -> Result<(), String> {
+    if store.config.security == Security::Permissive { return Ok(()); }
+    let registry = load_registry(store);
+    let (mut issues, mut remote) = (Vec::new(), false);
+    for step in &plan.install {
+        let unchecked: Vec<&str> = step.binaries.iter().filter(|bin| !step.checksums.contains_key(*bin)).map(String::as_str).collect();
+        if !unchecked.is_empty() {
+            issues.push(format!("{}:{} has no checksum for {}", step.package, step.version, unchecked.join(", ")));
+        }
+        if registry.get(&step.repo).is_some_and(|entry| entry.url.is_some()) {
+            issues.push(format!("{}:{} comes from remote repo {}, whose index is not signed", step.package, step.version, step.repo));
+            remote = true;
+        }
+    }
+    // Strict can't be satisfied by any remote repo today, so the error says why rather than leave it to the list
+    security_verdict(&issues, &store.config)
+        .map_err(|e| if remote { format!("{} (bpm can't check index signatures yet, so strict refuses every remote repo)", e) } else { e })
+}
+
+// Always a warning; security = strict refuses unless --allow-deprecated
+fn check_deprecated(plan: &Plan, config: &Config, opts: &Opts) -> Result<(), String> {
+    let deprecated: Vec<String> = plan.install.iter()
+        .filter_map(|step| step.deprecated.as_ref().map(|why| format!("{}:{} is deprecated: {}", step.package, step.version, why)))
+        .collect();
+    if deprecated.is_empty() { return Ok(()); }
+    if config.security == Security::Strict && !opts.allow_deprecated {
+        for msg in &deprecated { println!("{} {}", paint("Refused:", RED), msg); }
+        return Err("security = strict: nothing installed (--allow-deprecated to install anyway)".to_string());
+    }
+    for msg in deprecated { warn("deprecated", msg); }
+    Ok(())
+}
+
+fn security_verdict(issues: &[String], config: &Config) -> Result<(), String> {
+    match config.security {
+        Security::Strict if !issues.is_empty() => {
+            for issue in issues { println!("{} {}", paint("Refused:", RED), issue); }
+            Err("security = strict: nothing installed".to_string())
+        }
+        Security::Warn => {
+            for issue in issues { warn("security", issue.clone()); }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Only once its successor is in place. Binaries the successor now owns are left alone, and a
+// package installed by name passes that on to its successor.
+fn remove_replaced(store: &Store, old: &str, new: &str) {
+    let (Some(old_pkg), Some(mut new_pkg)) = (store.installed.get(old), store.installed.get(new)) else { return; };
+    for bin in old_pkg.binaries.iter().filter(|bin| !new_pkg.binaries.contains(bin)) {
+        let size = fs::metadata(bin).map(|m| m.len()).unwrap_or(0);
+        if fs::remove_file(bin).is_ok() { record(|report| { report.binaries += 1; report.bytes_freed += size; }); }
+        prune_empty_dirs(Path::new(bin), &old_pkg.repo, old);
+    }
+    store.installed.remove(old);
+    if old_pkg.explicit && !new_pkg.explicit {
+        new_pkg.explicit = true;
+        store.installed.insert(new, new_pkg);
+    }
+    emit("removed", serde_json::json!({ "package": old, "version": old_pkg.version, "replaced_by": new }));
+    record(|report| report.removed += 1);
+    println!("Removed {} (replaced by {})", old, new);
+}
+
+// --- /freeze and /i --locked ---
+// A lockfile is TOML: one `[[package]]` per node of the closure, in install order,
+// with the exact version and the sha256 of every binary
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LockedPackage {
+    name: String,
+    repo: String,
+    version: String,
+    #[serde(default)]
+    explicit: bool,
+    #[serde(default)]
+    checksums: BTreeMap<String, String>, // binary -> sha256
+}
+
+fn freeze(store: &Store, repo_name: &str, package: &str, version: Option<&str>, opts: &Opts) -> Result<(), String> {
+    let mut events = CliEvents::new(opts);
+    let mut resolver = Resolver::new(store, &mut events, opts);
+    resolver.db.clear();
+    resolver.visit(repo_name, package, version, true)?;
+    let plan = resolver.finish();
+    if let Some(conflict) = plan.conflicts.first() { return Err(conflict.clone()); }
+
+    let mut lock = Lockfile::default();
+    for step in &plan.install {
+        let mut checksums = BTreeMap::new();
+        for bin in &step.binaries {
+            let src = source_file(store, step, bin, opts)?;
+            let sum = sha256_file(&src).ok_or_else(|| format!("Failed to read {}", src.display()))?;
+            checksums.insert(bin.clone(), sum);
+        }
+        lock.package.push(LockedPackage {
+            name: step.package.clone(),
+            repo: step.repo.clone(),
+            version: step.version.clone(),
+            explicit: step.explicit,
+            checksums,
+        });
+    }
+    let content = toml::to_string(&lock).map_err(|e| e.to_string())?;
+    match &opts.out {
+        Some(path) => {
+            fs::write(path, content).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+            println!("Locked {} packages to {}", lock.package.len(), path);
+        }
+        None => print!("{}", content),
+    }
+    Ok(())
+}
+
+// Install exactly the pinned versions, bypassing resolution. A version that left its repo,
+// a changed binary list or a changed checksum is an error.
+fn install_locked(store: &Store, path: &str, opts: &Opts) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|_| format!("Failed to read {}", path))?;
+    let lock: Lockfile = toml::from_str(&content).map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+    let mut plan = Plan::default();
+    for locked in &lock.package {
+        let index = index_path(store, &locked.repo);
+        let repo = load_index(&index)?;
+        let mut step = plan_step(store, sha256_file(&index), &repo, &locked.repo, &locked.name, &locked.version, locked.explicit)
+            .map_err(|e| format!("{}, refusing to install from {}", e, path))?;
+        if step.binaries.iter().collect::<BTreeSet<_>>() != locked.checksums.keys().collect() {
+            return Err(format!("The binaries of {}:{} changed since {} was written", locked.name, locked.version, path));
+        }
+        step.checksums = locked.checksums.clone();
+        // Dependencies are whatever the lock pinned for them
+        step.dependencies = repo.packages[&locked.name][&locked.version].dependencies.iter()
+            .filter_map(|dep| parse_dep(dep).ok())
+            .filter_map(|spec| lock.package.iter().find(|other| other.name == spec.name))
+            .map(|other| format!("{}:{}", other.name, other.version))
+            .collect();
+        step.replaces.retain(|name| store.installed.get(name).is_some());
+        plan.install.push(step);
+    }
+    plan.total_size = plan.install.iter().map(|step| step.size).sum();
+    install_plan(store, &plan, opts)
+}
+
+// Download every remote binary the plan needs into the cache, --parallel N at a time (default: CPUs).
+// Any failure stops the remaining downloads and deletes the ones this run fetched.
+fn prefetch(store: &Store, plan: &Plan, opts: &Opts) -> Result<(), String> {
+    let jobs: Vec<(&str, String, PathBuf)> = plan.install.iter()
+        .filter(|step| is_remote(&step.path))
+        .flat_map(|step| step.binaries.iter().map(move |bin| {
+            (step.repo.as_str(), format!("{}/{}", step.path.trim_end_matches('/'), bin), local_source(store, step, bin))
+        }))
+        .filter(|(_, _, cached)| !cached.exists())
+        .collect();
+    if jobs.is_empty() { return Ok(()); }
+    let mut auths = BTreeMap::new();
+    for (repo, _, _) in &jobs {
+        if !auths.contains_key(repo) { auths.insert(*repo, repo_auth(store, repo)?); }
+    }
+
+    let workers = opts.parallel
+        .unwrap_or_else(|| std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
+        .clamp(1, jobs.len());
+    let queue = Mutex::new(jobs.iter());
+    let fetched = Mutex::new(Vec::new());
+    let failure: Mutex<Option<String>> = Mutex::new(None);
+    let multi = MultiProgress::new();
+    let config = &store.config;
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                if failure.lock().unwrap().is_some() { break; }
+                let Some((repo, url, cached)) = queue.lock().unwrap().next() else { break; };
+                let result = download_with(url, auths[repo].as_ref(), config, opts, |pb| multi.add(pb))
+                    .and_then(|data| write_cached(cached, &data));
+                match result {
+                    Ok(()) => fetched.lock().unwrap().push(cached),
+                    Err(e) => { failure.lock().unwrap().get_or_insert(e); }
+                }
+            });
+        }
+    });
+
+    if let Some(e) = failure.into_inner().unwrap() {
+        for cached in fetched.into_inner().unwrap() { let _ = fs::remove_file(cached); }
+        return Err(format!("{}, nothing installed.", e));
+    }
+    Ok(())
+}
+
+// Entries of requires_env that aren't satisfied: unset env vars and `tool:<name>` not found on PATH
+fn unmet_requirements(requires_env: &[String]) -> Vec<String> {
+    requires_env.iter().filter(|req| match req.strip_prefix("tool:") {
+        Some(tool) => !on_path(tool),
+        None => std::env::var_os(req).is_none_or(|v| v.is_empty()),
+    }).cloned().collect()
+}
+
+fn on_path(tool: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else { return false };
+    std::env::split_paths(&path).any(|dir| {
+        dir.join(tool).is_file() || dir.join(format!("{}{}", tool, std::env::consts::EXE_SUFFIX)).is_file()
+    })
+}
+
+// /i with one or more packages; fail-fast unless --keep-going, which reports a summary instead
+fn install_all(store: &Store, specs: &[String], opts: &Opts) {
+    if opts.refresh {
+        // A bare name could come from any repo, so then every remote one is fetched
+        let named: Option<Vec<String>> = specs.iter()
+            .map(|spec| parse_pkg_arg(spec).ok().and_then(|(repo, _, _)| repo))
+            .collect();
+        exit_on_error(refresh_before_resolve(store, named, opts));
+    }
+    let (succeeded, failed) = install_each(store, specs, opts);
+    if !failed.is_empty() && !opts.keep_going { finish_output(); std::process::exit(1); }
+    if specs.len() > 1 && !opts.dry_run && !opts.check {
+        println!("Installed: {}", if succeeded.is_empty() { "none".to_string() } else { succeeded.join(", ") });
+        if !failed.is_empty() { println!("Failed: {}", failed.join(", ")); }
+    }
+    if !failed.is_empty() { finish_output(); std::process::exit(1); }
+}
+
+// (succeeded, failed) specs, stopping at the first failure unless --keep-going
+fn install_each<'a>(store: &Store, specs: &'a [String], opts: &Opts) -> (Vec<&'a str>, Vec<&'a str>) {
+    let (mut succeeded, mut failed) = (Vec::new(), Vec::new());
+    for spec in specs {
+        let parsed = parse_pkg_arg(spec).and_then(|(repo, package, version)| match repo {
+            Some(repo) => {
+                let repos = list_repos(store);
+                let canonical = match_name("Repo", repos.iter().map(String::as_str), &repo, &store.config)?.map(str::to_string);
+                Ok((Some(canonical.unwrap_or(repo)), package, version))
+            }
+            None => Ok((None, package, version)),
+        });
+        let ok = match parsed {
+            Err(e) => { println!("{} {}", paint("Error:", RED), e); false }
+            Ok((Some(repo), _, _)) if !check_repo_enabled(store, &repo, opts) => false,
+            Ok((repo, package, version)) => match repo.or_else(|| select_repo(store, &package, opts)) {
+                Some(repo) => {
+                    let result = if opts.check {
+                        check_closure(store, &repo, &package, version.as_deref(), opts)
+                    } else {
+                        install_package(store, &repo, &package, version.as_deref(), opts)
+                    };
+                    match result {
+                        Ok(()) => true,
+                        Err(e) => {
+                            emit("error", serde_json::json!({ "package": package, "message": e }));
+                            println!("{} {}", paint("Error:", RED), e);
+                            false
+                        }
+                    }
+                }
+                None => false,
+            },
+        };
+        if ok { succeeded.push(spec.as_str()); continue; }
+        failed.push(spec.as_str());
+        if !opts.keep_going { break; }
+    }
+    (succeeded, failed)
+}
+
+// /i --archive: a .tar.gz holding binaries plus a package.mri fragment describing them. Its
+// packages form the pseudo-repo "archive"; dependencies may also name real repos.
+struct ArchiveRepos<'a> {
+    store: &'a Store,
+    dir: PathBuf, // where the archive was extracted
+}
+
+impl RepoProvider for ArchiveRepos<'_> {
+    fn repo(&self, name: &str) -> Result<Repo, String> {
+        if name != "archive" { return self.store.repo(name); }
+        let mut repo = load_index(&self.dir.join("package.mri"))?;
+        // Paths in the fragment are relative to the archive root
+        for pkg in repo.packages.values_mut().flat_map(BTreeMap::values_mut) {
+            pkg.path = self.dir.join(&pkg.path).to_string_lossy().to_string();
+        }
+        Ok(repo)
+    }
+    fn index_sha256(&self, name: &str) -> Option<String> {
+        if name == "archive" { sha256_file(&self.dir.join("package.mri")) } else { self.store.index_sha256(name) }
+    }
+}
+
+// Installs that didn't come from a repo: `/i --url` and `/i --archive`
+fn is_pseudo_repo(repo: &str) -> bool { repo == "url" || repo == "archive" }
+
+fn install_archive(store: &Store, source: &str, opts: &Opts) -> Result<(), String> {
+    let data = if is_remote(source) {
+        download(source, None, &store.config, opts)?
+    } else {
+        fs::read(source).map_err(|_| format!("Failed to read {}", source))?
+    };
+    let sum = sha256_bytes(&data);
+    if let Some(expected) = &opts.sha256 && !expected.eq_ignore_ascii_case(&sum) {
+        return Err(format!("Checksum mismatch for {}: expected {}, got {}", source, expected, sum));
+    }
+    // Extracted once per distinct archive; unpack refuses entries that would land outside dir
+    let dir = store.root.join("cache").join("archive").join(&sum[..12]);
+    if !dir.join("package.mri").is_file() {
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        tar::Archive::new(GzDecoder::new(&data[..])).unpack(&dir)
+            .map_err(|e| format!("Failed to extract {}: {}", source, e))?;
+        if !dir.join("package.mri").is_file() { return Err(format!("{} has no package.mri at its root", source)); }
+    }
+
+    let repos = ArchiveRepos { store, dir };
+    let fragment = repos.repo("archive")?;
+    let package = match (opts.name.as_deref(), fragment.packages.len()) {
+        (Some(name), _) if fragment.packages.contains_key(name) => name.to_string(),
+        (Some(name), _) => return Err(format!("{} has no package {}", source, name)),
+        (None, 1) => fragment.packages.keys().next().unwrap().clone(),
+        (None, _) => return Err(format!("{} describes {} packages; pick one with --name", source, fragment.packages.len())),
+    };
+    let plan = resolve_interactive(store, &repos, "archive", &package, None, opts)?;
+    install_plan(store, &plan, opts)?;
+    for step in plan.install.iter().filter(|step| step.repo == "archive") {
+        if let Some(mut pkg) = store.installed.get(&step.package) {
+            pkg.source_url = Some(source.to_string());
+            store.installed.insert(&step.package, pkg);
+        }
+    }
+    Ok(())
+}
+
+// /i --url: one downloaded binary, recorded as a package of the pseudo-repo "url"
+fn install_url(store: &Store, url: &str, name: Option<&str>, opts: &Opts) -> Result<(), String> {
+    let file = url.trim_end_matches('/').rsplit('/').next().unwrap_or_default();
+    let name = name.unwrap_or(file);
+    check_name("package", name).map_err(|e| format!("{}; pass --name to name the package from {}", e, url))?;
+    if let Some(pkg) = store.installed.get(name) && pkg.repo != "url" {
+        return Err(format!("{} is already installed from {}, remove it first", name, pkg.repo));
+    }
+    let dest = match store.config.prefix(opts) {
+        Some(prefix) => Path::new(prefix).join("url").join(name).join(name),
+        None => store.bins_dir().join(name),
+    };
+    if opts.dry_run { println!("Would download {} to {}", url, dest.display()); return Ok(()); }
+    if opts.sha256.is_none() { security_verdict(&[format!("{} has no --sha256 to check it against", url)], &store.config)?; }
+
+    let data = download(url, None, &store.config, opts)?;
+    let sum = sha256_bytes(&data);
+    if let Some(expected) = &opts.sha256 && !expected.eq_ignore_ascii_case(&sum) {
+        return Err(format!("Checksum mismatch for {}: expected {}, got {}", url, expected, sum));
+    }
+    fs::create_dir_all(dest.parent().unwrap()).map_err(|e| e.to_string())?;
+    place_bytes(&data, &dest, store.config.mode.unwrap_or(DEFAULT_BIN_MODE))?;
+
+    let dest_str = dest.to_string_lossy().to_string();
+    let version = sum[..12].to_string();
+    store.installed.insert(name, InstalledPackage {
+        repo: "url".to_string(),
+        version: version.clone(),
+        binaries: vec![dest_str.clone()],
+        checksums: BTreeMap::from([(dest_str.clone(), sum)]),
+        verified_mtimes: mtime_of(&dest).map(|mtime| BTreeMap::from([(dest_str, mtime)])).unwrap_or_default(),
+        index_sha256: None,
+        index_schema: None,
+        source_url: Some(url.to_string()),
+        installed_at: Some(now_secs()),
+        size: Some(data.len() as u64),
+        aliases: BTreeMap::new(),
+        explicit: true,
+        dependencies: Vec::new(),
+        previous: None, // a URL serves whatever it serves now, there is nothing to roll back to
+    });
+    emit("installed", serde_json::json!({ "package": name, "version": version, "repo": "url" }));
+    record(|report| { report.installed += 1; report.binaries += 1; report.bytes_written += data.len() as u64; });
+    println!("Installed {} from {}", name, url);
+    Ok(())
+}
+
+// /i --check: resolve strictly and confirm every binary of the closure is on disk, without installing.
+// Remote indexes must already be cached and remote binaries already downloaded.
+fn check_closure(store: &Store, repo_name: &str, package: &str, version: Option<&str>, opts: &Opts) -> Result<(), String> {
+    let mut events = CliEvents::new(opts);
+    let mut resolver = Resolver::new(store, &mut events, opts);
+    resolver.visit(repo_name, package, version, true)?;
+    let plan = resolver.finish();
+    if let Some(conflict) = plan.conflicts.first() { return Err(conflict.clone()); }
+
+    let mut missing = Vec::new();
+    for step in &plan.install {
+        for bin in &step.binaries {
+            let local = local_source(store, step, bin);
+            if !local.is_file() { missing.push(format!("{}:{} {} ({})", step.package, step.version, bin, local.display())); }
+        }
+    }
+    if !missing.is_empty() {
+        for entry in &missing { println!("Missing: {}", entry); }
+        return Err(format!("{} binaries needed by {} are not available locally", missing.len(), package));
+    }
+    println!("{}: all {} packages available locally", package, plan.install.len());
+    Ok(())
+}
+
+// Copies keep the source's permissions, and downloads have none, so binaries get an explicit mode.
+// Windows has no mode bits; there this does nothing.
+const DEFAULT_BIN_MODE: u32 = 0o755;
+
+// Binaries are written to a `.tmp` sibling, checked, and only then renamed over the final
+// name, so a crash mid-copy never leaves a torn file that looks installed
+fn place_file(src: &Path, dest: &Path, mode: u32) -> Result<String, String> {
+    let (tmp, mut file) = claim_tmp(dest)?;
+    let placed = fs::File::open(src).and_then(|mut from| io::copy(&mut from, &mut file))
+        .map_err(|e| format!("Failed to copy {} to {}: {}", src.display(), tmp.display(), e))
+        .and_then(|copied| {
+            let expected = sha256_file(src).ok_or_else(|| format!("Failed to read {}", src.display()))?;
+            let len = fs::metadata(src).map(|m| m.len()).unwrap_or(0);
+            match sha256_file(&tmp) {
+                Some(sum) if copied == len && sum == expected => Ok(sum),
+                _ => Err(format!("Copy of {} to {} came out different from the source", src.display(), tmp.display())),
+            }
+        });
+    drop(file);
+    commit_tmp(&tmp, dest, placed, mode)
+}
+
+fn place_bytes(data: &[u8], dest: &Path, mode: u32) -> Result<String, String> {
+    let (tmp, mut file) = claim_tmp(dest)?;
+    let placed = file.write_all(data).map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))
+        .and_then(|_| match sha256_file(&tmp) {
+            Some(sum) if sum == sha256_bytes(data) => Ok(sum),
+            _ => Err(format!("{} came out different from what was downloaded", tmp.display())),
+        });
+    drop(file);
+    commit_tmp(&tmp, dest, placed, mode)
+}
+
+fn tmp_sibling(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    dest.with_file_name(name)
+}
+
+// The `.tmp` sibling doubles as a lock on its destination: it is only ever created exclusively, so
+// two bpm runs placing the same file fail cleanly instead of interleaving their writes. One left
+// untouched for STALE_TMP_SECS is what a crashed run left behind and is taken over.
+const STALE_TMP_SECS: u64 = 60;
+
+fn claim_tmp(dest: &Path) -> Result<(PathBuf, fs::File), String> {
+    let tmp = tmp_sibling(dest);
+    for _ in 0..2 {
+        match fs::OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(file) => return Ok((tmp, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let idle = fs::metadata(&tmp).and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok());
+                if idle.is_some_and(|idle| idle.as_secs() < STALE_TMP_SECS) { break; }
+                let _ = fs::remove_file(&tmp);
+            }
+            Err(e) => return Err(format!("Failed to write {}: {}", tmp.display(), e)),
+        }
+    }
+    Err(format!("{} is being written by another bpm run; try again once it finishes", dest.display()))
+}
+
+fn commit_tmp(tmp: &Path, dest: &Path, placed: Result<String, String>, mode: u32) -> Result<String, String> {
+    let result = placed.and_then(|sum| {
+        set_mode(tmp, mode)?;
+        fs::rename(tmp, dest).map_err(|e| format!("Failed to move {} into place: {}", dest.display(), e))?;
+        Ok(sum)
+    });
+    if result.is_err() { let _ = fs::remove_file(tmp); }
+    result
+}
+
+fn set_mode(path: &Path, mode: u32) -> Result<(), String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Failed to set permissions on {}: {}", path.display(), e))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+// Where a binary is read from: the package's own path, or the download cache for remote packages
+fn local_source(store: &Store, step: &PlanStep, bin: &str) -> PathBuf {
+    if !is_remote(&step.path) { return Path::new(&step.path).join(bin); }
+    store.cache_dir(&step.repo).join("bins").join(&step.package).join(&step.version).join(bin)
+}
+
+// Local path of a binary to copy; binaries of remote packages are downloaded into the cache first
+fn source_file(store: &Store, step: &PlanStep, bin: &str, opts: &Opts) -> Result<PathBuf, String> {
+    let src = local_source(store, step, bin);
+    if is_remote(&step.path) && !src.exists() {
+        let data = download(&format!("{}/{}", step.path.trim_end_matches('/'), bin), repo_auth(store, &step.repo)?.as_ref(), &store.config, opts)?;
+        write_cached(&src, &data)?;
+    }
+    Ok(src)
+}
+
+// A downloaded binary goes into the cache under a temporary name first, so an interrupted run
+// never leaves a partial file that later installs would take as already fetched
+fn write_cached(cached: &Path, data: &[u8]) -> Result<(), String> {
+    fs::create_dir_all(cached.parent().unwrap()).map_err(|e| e.to_string())?;
+    let part = cached.with_extension("part");
+    fs::write(&part, data).map_err(|e| format!("Failed to write {}: {}", part.display(), e))?;
+    fs::rename(&part, cached).map_err(|e| format!("Failed to write {}: {}", cached.display(), e))
+}
+
+// Installed names for a step's binaries: from --as for the requested package, otherwise whatever
+// the previous install used. `--as name` needs a single binary; `--as bin=name` picks one.
+fn step_aliases(step: &PlanStep, previous: Option<&InstalledPackage>, opts: &Opts) -> Result<BTreeMap<String, String>, String> {
+    let Some(spec) = opts.as_name.as_deref().filter(|_| step.explicit) else {
+        let mut aliases = previous.map(|pkg| pkg.aliases.clone()).unwrap_or_default();
+        aliases.retain(|bin, _| step.binaries.contains(bin));
+        return Ok(aliases);
+    };
+    let (bin, name) = match spec.split_once('=') {
+        Some((bin, name)) if step.binaries.iter().any(|b| b == bin) => (bin.to_string(), name),
+        Some((bin, _)) => return Err(format!("{} has no binary {}", step.package, bin)),
+        None if step.binaries.len() == 1 => (step.binaries[0].clone(), spec),
+        None => return Err(format!("{} has {} binaries; use --as <binary>=<name>", step.package, step.binaries.len())),
+    };
+    check_name("binary", name)?;
+    Ok(BTreeMap::from([(bin, name.to_string())]))
+}
+
+// Flat bins/ by default; with a prefix, <prefix>/<repo>/<package>/ keeps the package's own layout
+fn bin_dest(store: &Store, step: &PlanStep, bin: &str, aliases: &BTreeMap<String, String>, opts: &Opts) -> PathBuf {
+    let filename = match aliases.get(bin) {
+        Some(alias) => std::ffi::OsStr::new(alias),
+        None => Path::new(bin).file_name().unwrap(),
+    };
+    match store.config.prefix(opts) {
+        Some(prefix) => Path::new(prefix).join(&step.repo).join(&step.package).join(bin).with_file_name(filename),
+        None => store.bins_dir().join(filename),
+    }
+}
+
+fn install_step(store: &Store, step: &PlanStep, opts: &Opts) -> Result<(), String> {
+    let bins_dir = store.bins_dir();
+    fs::create_dir_all(&bins_dir).map_err(|e| format!("Store directory is not writable: {} ({})", bins_dir.display(), e))?;
+
+    let installing_message = format!("Installing {} ({} binaries)", step.package, step.binaries.len());
+    let pb = progress_bar(step.binaries.len() as u64, &installing_message, &store.config, opts);
+    let mut installed_bins = Vec::new();
+    let mut checksums = BTreeMap::new();
+    let mut verified_mtimes = BTreeMap::new();
+    let mut unchanged = Vec::new();
+    let mut written = 0;
+    let previous = store.installed.get(&step.package);
+    let aliases = step_aliases(step, previous.as_ref(), opts)?;
+
+    // Fetch and check every source before copying any, so a bad binary leaves nothing half-installed
+    let mut sources = Vec::new();
+    for bin in &step.binaries {
+        let src = source_file(store, step, bin, opts)?;
+        if let Some(expected) = step.checksums.get(bin) && sha256_file(&src).as_ref() != Some(expected) {
+            return Err(format!("Checksum mismatch for {} in {}:{}, refusing to install", bin, step.package, step.version));
+        }
+        sources.push(src);
+    }
+
+    for (bin, src) in step.binaries.iter().zip(sources) {
+        let dest = bin_dest(store, step, bin, &aliases, opts);
+        let filename = dest.file_name().ok_or_else(|| format!("Invalid destination {} for {}", dest.display(), bin))?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Store directory is not writable: {} ({})", parent.display(), e))?;
+        }
+        let dest_str = dest.to_string_lossy().to_string();
+
+        // Reinstall/update of a byte-identical binary: leave the existing file alone
+        let recorded = previous.as_ref().and_then(|pkg| pkg.checksums.get(&dest_str));
+        if let Some(sum) = recorded && sha256_file(&src).as_ref() == Some(sum) && sha256_file(&dest).as_ref() == Some(sum) {
+            checksums.insert(dest_str.clone(), sum.clone());
+            if let Some(mtime) = mtime_of(&dest) { verified_mtimes.insert(dest_str.clone(), mtime); }
+            unchanged.push(filename.to_string_lossy().to_string());
+            installed_bins.push(dest_str);
+            pb.inc(1);
+            continue;
+        }
+
+        let sum = place_file(&src, &dest, step.mode.or(store.config.mode).unwrap_or(DEFAULT_BIN_MODE))?;
+        emit("copied", serde_json::json!({ "package": step.package, "path": dest_str }));
+        written += fs::metadata(&dest).map(|m| m.len()).unwrap_or(0);
+
+        // Save binary to DB
+        if store.config.save_db(opts) {
+            let _ = save_binary_to_db(store, &filename.to_string_lossy(), &fs::read(&dest).unwrap(), opts);
+        }
+
+        checksums.insert(dest_str.clone(), sum);
+        if let Some(mtime) = mtime_of(&dest) { verified_mtimes.insert(dest_str.clone(), mtime); }
+        installed_bins.push(dest_str);
+        pb.inc(1);
+    }
+    finish_progress(&pb, format!("Installed {} successfully!", step.package), opts);
+    if !unchanged.is_empty() && !opts.quiet { println!("Unchanged: {}", unchanged.join(", ")); }
+
+    // Binaries the previous version had but this one doesn't
+    for old in previous.iter().flat_map(|pkg| &pkg.binaries) {
+        if installed_bins.contains(old) { continue; }
+        let size = fs::metadata(old).map(|m| m.len()).unwrap_or(0);
+        if fs::remove_file(old).is_ok() { record(|report| { report.binaries += 1; report.bytes_freed += size; }); }
+    }
+
+    let size = installed_bins.iter().filter_map(|bin| fs::metadata(bin).ok()).map(|m| m.len()).sum();
+    store.installed.insert(&step.package, InstalledPackage {
+        repo: step.repo.clone(),
+        version: step.version.clone(),
+        binaries: installed_bins,
+        checksums,
+        verified_mtimes,
+        index_sha256: step.index_sha256.clone(),
+        index_schema: Some(step.index_schema),
+        source_url: None,
+        installed_at: Some(now_secs()),
+        size: Some(size),
+        aliases,
+        // Once asked for by name, a package stays explicit even if later reinstalled as a dependency
+        explicit: step.explicit || previous.as_ref().is_some_and(|pkg| pkg.explicit),
+        dependencies: step.dependencies.clone(),
+        previous: previous_of(previous.as_ref(), &step.repo, &step.version),
+    });
+    emit("installed", serde_json::json!({ "package": step.package, "version": step.version, "repo": step.repo }));
+    record(|report| {
+        if unchanged.len() == step.binaries.len() { report.unchanged += 1; } else { report.installed += 1; }
+        report.binaries += step.binaries.len() - unchanged.len();
+        report.bytes_written += written;
+    });
+    Ok(())
+}
+
+// Remove package, optionally only if the installed version matches
+// An installed package by that exact name wins; otherwise a name with *, ? or [ is a glob over
+// the installed names, and the whole match list is confirmed before anything is removed.
+// Either way, packages that something left installed still depends on need --force.
+fn remove_matching(store: &Store, spec: &str, version: Option<&str>, opts: &Opts) {
+    let db = store.installed.load();
+    if db.contains_key(spec) || !spec.contains(['*', '?', '[']) {
+        let removing = db.get(spec).is_some_and(|pkg| version.is_none_or(|ver| pkg.version == ver));
+        if removing && !opts.force && refuse_needed(&db, &[spec.to_string()]) { return; }
+        remove_package(store, spec, version);
+        return;
+    }
+    let pattern = match glob::Pattern::new(spec) {
+        Ok(pattern) => pattern,
+        Err(e) => { println!("{} Invalid pattern {}: {}", paint("Error:", RED), spec, e); return; }
+    };
+    let matched: Vec<String> = db.keys().filter(|name| pattern.matches(name)).cloned().collect();
+    if matched.is_empty() { println!("No installed packages match {}.", spec); return; }
+    println!("Packages matching {}:", spec);
+    for name in &matched { println!("  {}", name); }
+    if !opts.force && refuse_needed(&db, &matched) { return; }
+    if !confirm(&format!("Remove these {} packages?", matched.len()), opts) { return; }
+    for name in &matched { remove_package(store, name, version); }
+}
+
+// Lists what still needs any of `removing` and returns true if something does. Dependents that
+// are being removed too don't count.
+fn refuse_needed(db: &InstalledDb, removing: &[String]) -> bool {
+    let needed: Vec<(&String, Vec<&str>)> = removing.iter()
+        .map(|name| (name, dependents_of(db, name).into_iter().filter(|dependent| !removing.iter().any(|r| r == dependent)).collect::<Vec<_>>()))
+        .filter(|(_, dependents)| !dependents.is_empty())
+        .collect();
+    for (name, dependents) in &needed { println!("{} is needed by {}", name, dependents.join(", ")); }
+    if !needed.is_empty() { println!("{} Nothing removed. Pass --force to remove anyway.", paint("Error:", RED)); }
+    !needed.is_empty()
+}
+
+// Installed packages whose recorded dependencies name `package`
+fn dependents_of<'a>(db: &'a InstalledDb, package: &str) -> Vec<&'a str> {
+    db.iter()
+        .filter(|(_, pkg)| pkg.dependencies.iter().any(|dep| dep.split_once(':').is_some_and(|(dep, _)| dep == package)))
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+fn remove_package(store: &Store, package: &str, version: Option<&str>) {
+    if let (Some(pkg), Some(ver)) = (store.installed.get(package), version) && pkg.version != ver {
+        println!("Package {} is installed at version {}, not {}.", package, pkg.version, ver);
+        return;
+    }
+    if let Some(pkg) = store.installed.remove(package) {
+        for bin in &pkg.binaries {
+            let size = fs::metadata(bin).map(|m| m.len()).unwrap_or(0);
+            if fs::remove_file(bin).is_ok() { record(|report| { report.binaries += 1; report.bytes_freed += size; }); }
+            prune_empty_dirs(Path::new(bin), &pkg.repo, package);
+        }
+        emit("removed", serde_json::json!({ "package": package, "version": pkg.version }));
+        record(|report| report.removed += 1);
+        println!("Removed package {}", package);
+    } else { println!("Package {} is not installed.", package); }
+}
+
+// /clean: remove dependency-only packages that nothing installed depends on any more.
+// Removing one can leave its own dependencies unneeded, so the list is built to a fixed point first.
+fn clean_unneeded(store: &Store, opts: &Opts) {
+    let unneeded = unneeded_packages(&store.installed.load(), &opts.exclude);
+    if unneeded.is_empty() { println!("No unneeded packages."); return; }
+    println!("Packages no longer needed by anything installed:");
+    for name in &unneeded { println!("  {}", name); }
+    if opts.dry_run || !confirm(&format!("Remove these {} packages?", unneeded.len()), opts) { return; }
+    for name in &unneeded { remove_package(store, name, None); }
+}
+
+// Excluded packages are never listed, and count as staying installed, so what they depend on is kept too
+fn unneeded_packages(db: &InstalledDb, exclude: &[String]) -> Vec<String> {
+    let mut kept: BTreeSet<&str> = db.keys().map(String::as_str).collect();
+    let mut unneeded = Vec::new();
+    loop {
+        let depended: BTreeSet<&str> = kept.iter().flat_map(|name| &db[*name].dependencies)
+            .map(|dep| dep.split_once(':').map_or(dep.as_str(), |(name, _)| name))
+            .collect();
+        let round: Vec<&str> = kept.iter().copied()
+            .filter(|name| !db[*name].explicit && !depended.contains(name) && !exclude.iter().any(|ex| ex == name))
+            .collect();
+        if round.is_empty() { return unneeded; }
+        for name in round { kept.remove(name); unneeded.push(name.to_string()); }
+    }
+}
+
+// With a prefix, binaries live under <prefix>/<repo>/<package>/. Once one is deleted, drop the
+// directories it leaves empty, up to but never including <prefix>. Flat bins/ has none to clean.
+fn prune_empty_dirs(bin: &Path, repo: &str, package: &str) {
+    let package_dir = bin.ancestors().skip(1).find(|dir| {
+        dir.file_name() == Some(package.as_ref()) && dir.parent().is_some_and(|parent| parent.ends_with(repo))
+    });
+    // Scoped repos (org/repo) add a level between the prefix and the package
+    let Some(root) = package_dir.and_then(|dir| dir.ancestors().nth(1 + Path::new(repo).components().count())) else { return };
+    for dir in bin.ancestors().skip(1).take_while(|dir| *dir != root) {
+        // remove_dir refuses non-empty directories, which is where a shared ancestor stops us
+        if fs::remove_dir(dir).is_err() { break; }
+    }
+}
+
+// Release notes for every version after `from`, up to and including `to`
+fn print_changelog(versions: &BTreeMap<String, PackageVersion>, from: &str, to: &str) {
+    let span = changelog_span(versions, from, to);
+    for (version, notes) in &span { println!("  {}: {}", version, notes); }
+    if span.is_empty() { println!("  (no release notes)"); }
+}
+
+// (version, notes) for the versions in (from, to] that have notes, oldest first
+fn changelog_span<'a>(versions: &'a BTreeMap<String, PackageVersion>, from: &str, to: &str) -> Vec<(&'a str, &'a str)> {
+    let mut span: Vec<(&str, &str)> = versions.iter()
+        .filter(|(v, _)| compare_versions(v, from) == Ordering::Greater && compare_versions(v, to) != Ordering::Greater)
+        .filter_map(|(version, pkg)| pkg.changelog.as_deref().map(|notes| (version.as_str(), notes.trim())))
+        .collect();
+    span.sort_by(|a, b| compare_versions(a.0, b.0));
+    span
+}
+
+fn latest_in_repo(store: &Store, repo_name: &str, package: &str) -> Option<String> {
+    let repo = load_repo(store, repo_name)?;
+    latest_version(repo.packages.get(package)?).map(str::to_string)
+}
+
+// Repo to update a package from: the one it was installed from, unless --switch-repo moves it.
+// None means it isn't installed yet, so the usual repo selection applies.
+fn update_repo(store: &Store, package: &str, requested: Option<String>, opts: &Opts) -> Result<Option<String>, String> {
+    let Some(installed) = store.installed.get(package) else { return Ok(opts.switch_repo.clone().or(requested)); };
+    let repo = match (&opts.switch_repo, requested) {
+        (Some(switch), _) => switch.clone(),
+        (None, Some(requested)) if requested != installed.repo => {
+            return Err(format!("{} was installed from {}; pass --switch-repo {} to update it from there",
+                package, installed.repo, requested));
+        }
+        _ => installed.repo,
+    };
+    if load_repo(store, &repo).is_none_or(|index| !index.packages.contains_key(package)) {
+        return Err(format!("Repo {} does not provide {}; pass --switch-repo <repo> to update it from another repo", repo, package));
+    }
+    Ok(Some(repo))
+}
+
+// Update package
+fn update_package(store: &Store, repo_name: &str, package: &str, opts: &Opts) -> Result<(), String> {
+    let installed = store.installed.get(package);
+    if installed.is_some() && load_holds(store).contains(package) && !opts.force {
+        return Err(format!("{} is held; /unhold it or pass --force to update it anyway", package));
+    }
+    if let (Some(installed), Some(repo)) = (installed, load_repo(store, repo_name))
+        && let Some(versions) = repo.packages.get(package)
+        && let Some(latest) = latest_version(versions)
+        && compare_versions(latest, &installed.version) == Ordering::Greater
+    {
+        println!("Changes in {} {} -> {}:", package, installed.version, latest);
+        print_changelog(versions, &installed.version, latest);
+    }
+    // Installing over the old version only recopies binaries that changed
+    install_package(store, repo_name, package, None, opts)
+}
+
+// /rollback: reinstall whatever was installed before the current version
+fn rollback_package(store: &Store, package: &str, opts: &Opts) -> Result<(), String> {
+    let pkg = store.installed.get(package).ok_or_else(|| format!("Package {} is not installed", package))?;
+    let Some(prev) = &pkg.previous else {
+        return Err(format!("No earlier version of {} is recorded; one is kept when it is updated or reinstalled at another version", package));
+    };
+    if load_holds(store).contains(package) && !opts.force {
+        return Err(format!("{} is held; /unhold it or pass --force to roll it back anyway", package));
+    }
+    if is_pseudo_repo(&prev.repo) {
+        return Err(format!("{} {} came from {}, which can't be installed again by version", package, prev.version, prev.repo));
+    }
+    println!("Rolling back {} {} -> {} ({})", package, pkg.version, prev.version, prev.repo);
+    install_package(store, &prev.repo, package, Some(&prev.version), opts)
+}
+
+// Upgrade every installed package that has a newer version in its repo
+fn upgrade_all(store: &Store, opts: &Opts) {
+    let db = store.installed.load();
+    let registry = load_registry(store);
+    let holds = load_holds(store);
+    let (mut upgraded, mut skipped, mut current) = (Vec::new(), Vec::new(), Vec::new());
+    let (mut disabled, mut failed, mut gone, mut held) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    if let Some(repo) = &opts.repo {
+        if !list_repos(store).contains(repo) && !db.values().any(|pkg| pkg.repo == *repo) {
+            exit_on_error(Err(format!("Repo {} not found", repo)));
+        }
+        println!("Only packages installed from {}:", repo);
+    }
+
+    // Excludes and holds still apply within the --repo scope; packages from other repos aren't mentioned
+    for (name, pkg) in db.iter().filter(|(_, pkg)| opts.repo.as_ref().is_none_or(|repo| pkg.repo == *repo)) {
+        if opts.exclude.contains(name) { skipped.push(name.as_str()); continue; }
+        if holds.contains(name) { held.push(name.as_str()); continue; }
+        if !repo_enabled(&registry, &pkg.repo) { disabled.push(name.as_str()); continue; }
+        match latest_in_repo(store, &pkg.repo, name) {
+            Some(latest) if compare_versions(&latest, &pkg.version) == Ordering::Greater => {
+                println!("Upgrading {} {} -> {}", name, pkg.version, latest);
+                match update_package(store, &pkg.repo, name, opts) {
+                    Ok(()) => upgraded.push(name.as_str()),
+                    Err(e) if opts.keep_going => {
+                        emit("error", serde_json::json!({ "package": name, "message": e }));
+                        println!("{} {}", paint("Error:", RED), e);
+                        failed.push(name.as_str());
+                    }
+                    Err(e) => exit_on_error(Err(e)),
+                }
+            }
+            None if !is_pseudo_repo(&pkg.repo) => gone.push(name.as_str()),
+            _ => current.push(name.as_str()),
+        }
+    }
+
+    println!("Upgraded: {}", if upgraded.is_empty() { "none".to_string() } else { upgraded.join(", ") });
+    println!("Up to date: {}", if current.is_empty() { "none".to_string() } else { current.join(", ") });
+    if !skipped.is_empty() { println!("Skipped (excluded): {}", skipped.join(", ")); }
+    if !held.is_empty() { println!("Skipped (held): {}", held.join(", ")); }
+    if !disabled.is_empty() { println!("Skipped (repo disabled): {}", disabled.join(", ")); }
+    if !gone.is_empty() { println!("Skipped (no longer in their repo): {}", gone.join(", ")); }
+    if !failed.is_empty() { println!("Failed: {}", failed.join(", ")); finish_output(); std::process::exit(1); }
+}
+
+// List installed packages with a newer version available, with what changed
+fn list_outdated(store: &Store) {
+    let registry = load_registry(store);
+    let mut any = false;
+    for (name, pkg) in store.installed.iter() {
+        if !repo_enabled(&registry, &pkg.repo) { continue; }
+        let Some(repo) = load_repo(store, &pkg.repo) else { continue; };
+        let Some(versions) = repo.packages.get(&name) else { continue; };
+        if let Some(latest) = latest_version(versions) && compare_versions(latest, &pkg.version) == Ordering::Greater {
+            println!("{} {} -> {} ({})", name, pkg.version, latest, pkg.repo);
+            print_changelog(versions, &pkg.version, latest);
+            any = true;
+        }
+    }
+    if !any { println!("All packages are up to date."); }
+}
+
+// --- /repo subcommands ---
+fn repo_command(store: &Store, args: &[String], opts: &Opts) {
+    let mut registry = load_registry(store);
+    match (args.first().map(String::as_str), args.get(1)) {
+        (Some("list"), _) => {
+            let repos = list_repos(store);
+            if repos.is_empty() { println!("No repos found."); }
+            print_repo_list(&registry, &repos, &store.config);
+        }
+        (Some("add"), Some(name)) => {
+            let Some(url) = args.get(2).filter(|url| is_remote(url)) else {
+                println!("Usage: bpm /repo add <name> <http(s) url of packages.mri>");
+                return;
+            };
+            if let Err(e) = check_repo_name(name) { println!("{} {}", paint("Error:", RED), e); return; }
+            if list_repos(store).contains(name) { println!("Repo {} already exists.", name); return; }
+            registry.insert(name.clone(), RepoEntry { url: Some(url.clone()), ..RepoEntry::default() });
+            save_registry(store, &registry);
+            println!("Added remote repo {}.", name);
+            if let Err(e) = refresh_repo(store, name, opts) { println!("{} {}", paint("Error:", RED), e); }
+        }
+        (Some("remove"), Some(name)) => {
+            if registry.get(name).is_none_or(|entry| entry.url.is_none()) {
+                println!("{} is not a remote repo; local repos are directories in the store.", name);
+                return;
+            }
+            registry.remove(name);
+            save_registry(store, &registry);
+            let _ = fs::remove_dir_all(store.cache_dir(name));
+            println!("Removed repo {}.", name);
+        }
+        (Some("refresh"), name) => {
+            let name_given = name;
+            let names = match name {
+                Some(name) if !list_repos(store).contains(name) => { println!("Repo {} not found.", name); return; }
+                Some(name) => vec![name.clone()],
+                None => list_repos(store),
+            };
+            for name in names {
+                // Refreshing everything skips indexes still fresh under cache_ttl; naming a repo (or --force) always fetches
+                if name_given.is_none() && !opts.force && !index_stale(index_age(store, &name), store.config.cache_ttl) {
+                    println!("{}: cached index is still fresh (cache_ttl), skipped", name);
+                    continue;
+                }
+                if let Err(e) = refresh_repo(store, &name, opts) { println!("{}: {}", name, e); }
+            }
+        }
+        (Some(action @ ("enable" | "disable")), Some(name)) => {
+            if !list_repos(store).contains(name) { println!("Repo {} not found.", name); return; }
+            registry.entry(name.clone()).or_default().enabled = action == "enable";
+            save_registry(store, &registry);
+            println!("Repo {} {}d.", name, action);
+        }
+        (Some("stats"), Some(name)) => {
+            if !list_repos(store).contains(name) { println!("Repo {} not found.", name); return; }
+            match load_index(&index_path(store, name)) {
+                Ok(repo) => print_repo_stats(&repo_stats(store, name, &repo), opts),
+                Err(e) => println!("{} {}", paint("Error:", RED), e),
+            }
+        }
+        (Some("verify"), Some(name)) => {
+            if !list_repos(store).contains(name) { println!("Repo {} not found.", name); return; }
+            exit_on_error(repo_verify(store, name));
+        }
+        (Some("ping"), Some(name)) => {
+            if !list_repos(store).contains(name) { println!("Repo {} not found.", name); return; }
+            let report = repo_ping(store, name, opts);
+            print_ping(&report, opts);
+            if report.error.is_some() { std::process::exit(1); }
+        }
+        (Some("set-priority"), Some(name)) => {
+            let Some(priority) = args.get(2).and_then(|n| n.parse::<i64>().ok()) else {
+                println!("Usage: bpm /repo set-priority <name> <n>  (a whole number; higher comes first)");
+                return;
+            };
+            if !list_repos(store).contains(name) { println!("Repo {} not found.", name); return; }
+            registry.entry(name.clone()).or_default().priority = Some(priority).filter(|&n| n != 0);
+            save_registry(store, &registry);
+            println!("Repo {} now has priority {}. Repo order:", name, priority);
+            print_repo_list(&registry, &list_repos(store), &store.config);
+        }
+        (Some("auth"), Some(name)) => {
+            if !list_repos(store).contains(name) { println!("Repo {} not found.", name); return; }
+            let entry = registry.entry(name.clone()).or_default();
+            match args.get(2).map(String::as_str) {
+                Some("none") => { entry.token_env = None; entry.token_file = None; entry.username = None; }
+                Some(spec) if spec.starts_with("env:") => { entry.token_env = Some(spec[4..].to_string()); entry.token_file = None; }
+                Some(spec) if spec.starts_with("file:") => { entry.token_file = Some(spec[5..].to_string()); entry.token_env = None; }
+                _ => { println!("Usage: bpm /repo auth <name> env:<VAR> | file:<path> | none [username]"); return; }
+            }
+            if args.get(2).is_some_and(|spec| spec != "none") { entry.username = args.get(3).cloned(); }
+            save_registry(store, &registry);
+            println!("Updated credentials for repo {}.", name);
+        }
+        (Some("index-diff"), Some(name)) => {
+            if let Err(e) = index_diff(store, name, opts) { println!("{} {}", paint("Error:", RED), e); }
+        }
+        (Some("scan"), _) => repo_scan(store, opts),
+        (Some("default"), name) => exit_on_error(repo_default(store, name.map(String::as_str))),
+        (Some("edit"), Some(name)) => exit_on_error(repo_edit(store, name, opts)),
+        (Some("clone"), Some(name)) => match args.get(2) {
+            Some(new_name) => exit_on_error(repo_clone(store, name, new_name)),
+            None => println!("Usage: bpm /repo clone <name> <new name>"),
+        },
+        (Some("export"), path) => exit_on_error(repo_export(store, path.map(String::as_str))),
+        (Some("import"), Some(source)) => exit_on_error(repo_import(store, source, opts)),
+        _ => println!("Usage: bpm /repo list | add <name> <url> | remove <name> | refresh [name] | enable <name> | disable <name> | stats <name> | index-diff <name> | verify <name> | ping <name> | set-priority <name> <n> | auth <name> <source> | edit <name> [--url <url>] [--priority <n>] [--key <path|none>] | clone <name> <new name> | default [<name> | --clear] | scan | export [file] | import <file|url>"),
+    }
+}
+
+// Local repos are whatever directories hold a packages.mri; this gives the ones dropped in by hand
+// an entry in repos.json, so they show up in /repo export and carry settings like any other
+fn repo_scan(store: &Store, opts: &Opts) {
+    let mut registry = load_registry(store);
+    let found: Vec<String> = list_repos(store).into_iter()
+        .filter(|name| registry.get(name).is_none_or(|entry| entry.url.is_none()))
+        .collect();
+    let (known, new): (Vec<String>, Vec<String>) = found.iter().cloned().partition(|name| registry.contains_key(name));
+    let (mut unreadable, mut candidates) = (Vec::new(), Vec::new());
+    for name in new {
+        match load_index(&store.repo_index_path(&name)) {
+            Ok(_) => candidates.push(name),
+            Err(e) => { println!("{}: {}", name, e.lines().next().unwrap_or_default()); unreadable.push(name); }
+        }
+    }
+    println!("Found {} local repos: {} already registered, {} new, {} with an unreadable index.",
+        found.len(), known.len(), candidates.len(), unreadable.len());
+    if candidates.is_empty() { return; }
+    println!("New: {}", candidates.join(", "));
+    if opts.dry_run { return; }
+    if !confirm("Register them?", opts) { println!("Registered: none"); return; }
+    for name in &candidates { registry.insert(name.clone(), RepoEntry::default()); }
+    save_registry(store, &registry);
+    println!("Registered: {}", candidates.join(", "));
+}
+
+// Without a name, shows the default repo; --clear unsets it
+fn repo_default(store: &Store, name: Option<&str>) -> Result<(), String> {
+    match name {
+        None => match &store.config.default_repo {
+            Some(default) => println!("Default repo: {}", default),
+            None => println!("No default repo set."),
+        },
+        Some("--clear") => {
+            write_config_key(store, "default_repo", None)?;
+            println!("Default repo cleared.");
+        }
+        Some(name) => {
+            if !list_repos(store).iter().any(|repo| repo == name) { return Err(format!("Repo {} not found", name)); }
+            write_config_key(store, "default_repo", Some(toml::Value::String(name.to_string())))?;
+            println!("Default repo is now {}.", name);
+            if !repo_enabled(&load_registry(store), name) {
+                println!("{} {} is disabled, so bare names skip it until it is enabled again.", paint("⚠", YELLOW), name);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Changes only the fields given, keeping enabled, credentials and the rest. A new url must serve an
+// index that parses before anything is saved; the cached index then belongs to the old url, so
+// fetched_at is cleared (the cache counts as stale) and a refresh is offered.
+fn repo_edit(store: &Store, name: &str, opts: &Opts) -> Result<(), String> {
+    if !list_repos(store).contains(&name.to_string()) { return Err(format!("Repo {} not found", name)); }
+    let mut registry = load_registry(store);
+    let old = registry.get(name);
+    let old_url = old.and_then(|entry| entry.url.clone());
+    let old_priority = old.and_then(|entry| entry.priority).unwrap_or(0);
+    let old_key = old.and_then(|entry| entry.key.clone());
+    let new_url = opts.url.as_ref().filter(|url| old_url.as_ref() != Some(*url));
+    let new_priority = opts.priority.filter(|&n| n != old_priority);
+    if opts.url.is_none() && opts.priority.is_none() && opts.key.is_none() {
+        return Err("Nothing to change; pass --url <url>, --priority <n> and/or --key <path|none>".to_string());
+    }
+    if let Some(url) = new_url {
+        if old_url.is_none() { return Err(format!("{} is a local repo; only remote repos have a url", name)); }
+        if !is_remote(url) { return Err(format!("{} is not an http(s) url", url)); }
+    }
+    // Stored absolute, since it is read from whatever directory bpm runs in later
+    let key = match opts.key.as_deref() {
+        None => None,
+        Some("none") => Some(None),
+        Some(path) if !Path::new(path).is_file() => return Err(format!("Key file {} not found", path)),
+        Some(path) => Some(Some(std::path::absolute(path).map_err(|e| e.to_string())?.to_string_lossy().to_string())),
+    };
+    let new_key = key.filter(|key| *key != old_key);
+    if new_url.is_none() && new_priority.is_none() && new_key.is_none() { println!("Repo {} already has those settings.", name); return Ok(()); }
+
+    if let Some(url) = new_url { println!("url:      {} -> {}", old_url.as_deref().unwrap_or_default(), url); }
+    if let Some(priority) = new_priority { println!("priority: {} -> {}", old_priority, priority); }
+    if let Some(key) = &new_key {
+        println!("key:      {} -> {}", old_key.as_deref().unwrap_or("none"), key.as_deref().unwrap_or("none"));
+    }
+    if opts.dry_run { return Ok(()); }
+    if !confirm(&format!("Update repo {}?", name), opts) { println!("Repo {} left unchanged.", name); return Ok(()); }
+    if let Some(url) = new_url {
+        download(url, repo_auth(store, name)?.as_ref(), &store.config, opts).and_then(|raw| parse_index(raw, url))
+            .map_err(|e| format!("{}; repo {} left unchanged", e, name))?;
+    }
+
+    let entry = registry.entry(name.to_string()).or_default();
+    if let Some(priority) = new_priority { entry.priority = Some(priority).filter(|&n| n != 0); }
+    if let Some(key) = new_key { entry.key = key; }
+    if let Some(url) = new_url {
+        entry.url = Some(url.clone());
+        entry.fetched_at = None;
+    }
+    save_registry(store, &registry);
+    println!("Updated repo {}.", name);
+    if new_url.is_some() && store.cached_index_path(name).exists() {
+        println!("{} The cached index of {} came from the old url and is now stale.", paint("⚠", YELLOW), name);
+        if confirm("Refresh it now?", opts) { refresh_repo(store, name, opts)?; }
+        else { println!("Run bpm /repo refresh {} to fetch the new one.", name); }
+    }
+    Ok(())
+}
+
+// The new entry is the old one under another name: url, priority, enabled and credential locations.
+// The cached index is copied along, so the clone resolves right away.
+fn repo_clone(store: &Store, name: &str, new_name: &str) -> Result<(), String> {
+    check_repo_name(new_name)?;
+    let mut registry = load_registry(store);
+    let repos = list_repos(store);
+    if !repos.iter().any(|repo| repo == name) { return Err(format!("Repo {} not found", name)); }
+    if repos.iter().any(|repo| repo == new_name) || registry.contains_key(new_name) {
+        return Err(format!("Repo {} already exists", new_name));
+    }
+    let Some(entry) = registry.get(name).filter(|entry| entry.url.is_some()).cloned() else {
+        return Err(format!("{} is a local repo; copy its directory to clone it", name));
+    };
+    let cached = store.cached_index_path(name);
+    let fetched_at = if cached.is_file() {
+        let dest = store.cached_index_path(new_name);
+        fs::create_dir_all(dest.parent().unwrap()).map_err(|e| e.to_string())?;
+        fs::copy(&cached, &dest).map_err(|e| format!("Failed to copy {} to {}: {}", cached.display(), dest.display(), e))?;
+        entry.fetched_at
+    } else {
+        None
+    };
+    registry.insert(new_name.to_string(), RepoEntry { fetched_at, ..entry });
+    save_registry(store, &registry);
+    println!("Cloned repo {} as {}.", name, new_name);
+    Ok(())
+}
+
+// Every repo with a url or non-default settings, as TOML
+fn repo_export(store: &Store, path: Option<&str>) -> Result<(), String> {
+    let repos = load_registry(store).into_iter()
+        .map(|(name, entry)| (name, RepoListEntry {
+            url: entry.url, enabled: entry.enabled, priority: entry.priority,
+            token_env: entry.token_env, token_file: entry.token_file, username: entry.username, key: entry.key,
+        }))
+        .collect();
+    let content = toml::to_string(&RepoList { repos }).map_err(|e| e.to_string())?;
+    match path {
+        Some(path) => {
+            fs::write(path, content).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+            println!("Exported repo list to {}", path);
+        }
+        None => print!("{}", content),
+    }
+    Ok(())
+}
+
+// Registers every repo in a list written by /repo export (TOML, or JSON of the same shape).
+// Repos already present are skipped unless --update; without a url the repo must already exist locally.
+fn repo_import(store: &Store, source: &str, opts: &Opts) -> Result<(), String> {
+    let content = if is_remote(source) {
+        String::from_utf8(download(source, None, &store.config, opts)?).map_err(|_| format!("{} is not text", source))?
+    } else {
+        fs::read_to_string(source).map_err(|e| format!("Failed to read {}: {}", source, e))?
+    };
+    let list: RepoList = if content.trim_start().starts_with('{') {
+        serde_json::from_str(&content).map_err(|e| format!("Failed to parse {}: {}", source, e))?
+    } else {
+        toml::from_str(&content).map_err(|e| format!("Failed to parse {}: {}", source, e))?
+    };
+    for (name, entry) in &list.repos {
+        check_repo_name(name)?;
+        if let Some(url) = entry.url.as_ref().filter(|url| !is_remote(url)) {
+            return Err(format!("Repo {} has url {:?}, which is not http(s)", name, url));
+        }
+    }
+    let mut registry = load_registry(store);
+    let existing = list_repos(store);
+    let (mut fetch, mut added, mut updated, mut skipped) = (Vec::new(), 0, 0, 0);
+    for (name, entry) in list.repos {
+        let present = existing.contains(&name);
+        if entry.url.is_none() && !present {
+            println!("{}: local repo not in this store, skipped", name);
+            skipped += 1;
+            continue;
+        }
+        if present && !opts.update {
+            println!("{}: already present, skipped (--update to overwrite its settings)", name);
+            skipped += 1;
+            continue;
+        }
+        let fetched_at = registry.get(&name).filter(|old| old.url == entry.url).and_then(|old| old.fetched_at);
+        if entry.url.is_some() && fetched_at.is_none() { fetch.push(name.clone()); }
+        if present { updated += 1; println!("{}: updated", name); } else { added += 1; println!("{}: added", name); }
+        registry.insert(name, RepoEntry {
+            enabled: entry.enabled, url: entry.url, fetched_at,
+            token_env: entry.token_env, token_file: entry.token_file, username: entry.username,
+            priority: entry.priority.filter(|&n| n != 0), key: entry.key,
+        });
+    }
+    if opts.dry_run {
+        println!("Dry run: {} would be added, {} updated, {} skipped.", added, updated, skipped);
+        return Ok(());
+    }
+    save_registry(store, &registry);
+    // Indexes are fetched once everything is saved, so one unreachable repo does not lose the rest
+    for name in &fetch {
+        if let Err(e) = refresh_repo(store, name, opts) { println!("{}: {}", name, e); }
+    }
+    println!("{} added, {} updated, {} skipped.", added, updated, skipped);
+    Ok(())
+}
+
+fn print_repo_list(registry: &Registry, repos: &[String], config: &Config) {
+    for name in repos {
+        let mut details = vec![if repo_enabled(registry, name) { "enabled".to_string() } else { "disabled".to_string() }];
+        if config.default_repo.as_ref() == Some(name) { details.push("default".to_string()); }
+        if let Some(url) = registry.get(name).and_then(|entry| entry.url.as_ref()) { details.push(format!("remote {}", url)); }
+        let priority = repo_priority(registry, name);
+        if priority != 0 { details.push(format!("priority {}", priority)); }
+        if let Some(key) = registry.get(name).and_then(|entry| entry.key.as_ref()) { details.push(format!("key {}", key)); }
+        println!("{} ({})", name, details.join(", "));
+    }
+}
+
+fn count_versions(repo: &Repo) -> (usize, usize) {
+    (repo.packages.len(), repo.packages.values().map(BTreeMap::len).sum())
+}
+
+#[derive(Debug, Default, Serialize)]
+struct RepoStats {
+    packages: usize,
+    versions: usize,
+    without_checksums: Vec<String>,      // packages with a version that declares no checksums
+    unresolved_dependencies: Vec<String>, // "package:version -> dependency" that match nothing
+    newest_version: Option<String>,
+    oldest_version: Option<String>,
+}
+
+fn repo_stats(store: &Store, name: &str, repo: &Repo) -> RepoStats {
+    let (packages, versions) = count_versions(repo);
+    let mut other_repos = BTreeMap::new();
+    let mut stats = RepoStats { packages, versions, ..RepoStats::default() };
+    for (package, versions) in &repo.packages {
+        if versions.values().any(|pkg| pkg.binaries.iter().any(|bin| !pkg.checksums.contains_key(bin))) {
+            stats.without_checksums.push(package.clone());
+        }
+        for (ver, pkg) in versions {
+            for dep in &pkg.dependencies {
+                let resolved = parse_dep(dep).is_ok_and(|spec| {
+                    let target = match spec.repo {
+                        Some(other) if other != name => other_repos.entry(other.to_string())
+                            .or_insert_with(|| load_repo(store, other)).as_ref(),
+                        _ => Some(repo),
+                    };
+                    target.and_then(|target| target.packages.get(spec.name))
+                        .and_then(|versions| select_version(versions, spec.version).filter(|v| versions.contains_key(*v)))
+                        .is_some()
+                });
+                if !resolved { stats.unresolved_dependencies.push(format!("{}:{} -> {}", package, ver, dep)); }
+            }
+        }
+    }
+    let all = repo.packages.values().flat_map(BTreeMap::keys);
+    stats.newest_version = all.clone().max_by(|a, b| compare_versions(a, b)).cloned();
+    stats.oldest_version = all.min_by(|a, b| compare_versions(a, b)).cloned();
+    stats
+}
+
+fn print_repo_stats(stats: &RepoStats, opts: &Opts) {
+    if opts.json {
+        print_json(stats);
+        return;
+    }
+    println!("Packages:            {}", stats.packages);
+    println!("Versions:            {}", stats.versions);
+    println!("Without checksums:   {}", stats.without_checksums.len());
+    for package in &stats.without_checksums { println!("  {}", package); }
+    println!("Unresolved deps:     {}", stats.unresolved_dependencies.len());
+    for dep in &stats.unresolved_dependencies { println!("  {}", dep); }
+    println!("Newest version:      {}", stats.newest_version.as_deref().unwrap_or("-"));
+    println!("Oldest version:      {}", stats.oldest_version.as_deref().unwrap_or("-"));
+}
+
+// /repo verify: trust checks without installing. Every declared checksum must name a real
+// binary and be a sha256, and every binary on hand must match it.
+fn repo_verify(store: &Store, name: &str) -> Result<(), String> {
+    let index = index_path(store, name);
+    let repo = load_index(&index)?;
+    println!("{}    index parses ({})", paint("ok", GREEN), index.display());
+    let (mut passed, mut failed, mut unchecked) = (0, 0, 0);
+    // bpm can't check index signatures yet. A repo with a key expects one, so that fails; without a
+    // key nothing was checked, which is reported but not counted as a failure.
+    match load_registry(store).get(name).and_then(|entry| entry.key.clone()) {
+        Some(key) => {
+            println!("{}  signature unverified: bpm can't check index signatures yet (key {})", paint("FAIL", RED), key);
+            failed += 1;
+        }
+        None => println!("{}  signature unverified: no key configured (/repo edit {} --key <path>)", paint("WARN", YELLOW), name),
+    }
+    let mut by_folded: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for package in repo.packages.keys() { by_folded.entry(package.to_lowercase()).or_default().push(package); }
+    for names in by_folded.values().filter(|names| names.len() > 1) {
+        println!("{}  {} differ only by case; installs by name can't tell them apart", paint("FAIL", RED), names.join(", "));
+        failed += 1;
+    }
+    for (package, versions) in &repo.packages {
+        if versions.is_empty() { println!("{}  {}", paint("FAIL", RED), no_versions(package, name)); failed += 1; }
+        for ver in versions.keys() {
+            let step = match plan_step(store, None, &repo, name, package, ver, false) {
+                Ok(step) => step,
+                Err(e) => { println!("{}  {}:{}: {}", paint("FAIL", RED), package, ver, e); failed += 1; continue; }
+            };
+            let deps = &repo.packages[package][ver].dependencies;
+            if let Some(dep) = deps.iter().find(|dep| parse_dep(dep).is_ok_and(|spec| is_self_dependency(&spec, name, package, &repo, &store.config))) {
+                println!("{}  {}:{}: depends on itself ({:?})", paint("FAIL", RED), package, ver, dep);
+                failed += 1;
+            }
+            for (bin, sum) in &step.checksums {
+                let key = format!("{}:{} {}", package, ver, bin);
+                if !step.binaries.contains(bin) {
+                    println!("{}  {}: checksum for a binary the package doesn't have", paint("FAIL", RED), key);
+                    failed += 1;
+                } else if sum.len() != 64 || !sum.chars().all(|c| c.is_ascii_hexdigit()) {
+                    println!("{}  {}: {:?} is not a sha256", paint("FAIL", RED), key, sum);
+                    failed += 1;
+                } else if let Some(actual) = sha256_file(&local_source(store, &step, bin)) {
+                    if actual.eq_ignore_ascii_case(sum) { passed += 1; } else { println!("{}  {}: checksum mismatch", paint("FAIL", RED), key); failed += 1; }
+                } else {
+                    unchecked += 1; // remote binary not downloaded yet
+                }
+            }
+        }
+    }
+    println!("{} checksums match, {} failed, {} binaries not downloaded", passed, failed, unchecked);
+    if failed > 0 { return Err(format!("Repo {} failed verification", name)); }
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize)]
+struct PingReport {
+    repo: String,
+    source: String, // index URL, or the packages.mri path of a local repo
+    reachable: bool,
+    status: Option<u16>,     // HTTP status, remote repos only
+    latency_ms: Option<u64>, // until the response headers arrived
+    bytes: Option<usize>,
+    packages: Option<usize>,
+    versions: Option<usize>,
+    error: Option<String>,
+}
+
+// /repo ping: can the index be fetched and parsed right now? Nothing is cached or written.
+fn repo_ping(store: &Store, name: &str, opts: &Opts) -> PingReport {
+    let url = load_registry(store).get(name).and_then(|entry| entry.url.clone());
+    let mut report = PingReport { repo: name.to_string(), ..PingReport::default() };
+    let raw = match url {
+        Some(url) => {
+            report.source = url.clone();
+            let started = Instant::now();
+            repo_auth(store, name).and_then(|auth| {
+                let mut resp = http_send(&url, auth.as_ref(), &store.config, opts)?;
+                report.latency_ms = Some(started.elapsed().as_millis() as u64);
+                report.status = Some(resp.status().as_u16());
+                report.reachable = true;
+                if !resp.status().is_success() { return Err(format!("Failed to fetch {}: HTTP {}", url, resp.status())); }
+                let mut data = Vec::new();
+                resp.read_to_end(&mut data).map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+                Ok(data)
+            })
+        }
+        None => {
+            let path = store.repo_index_path(name);
+            report.source = path.display().to_string();
+            let read = fs::read(&path).map_err(|_| format!("Failed to read {}", path.display()));
+            report.reachable = read.is_ok();
+            read
+        }
+    };
+    match raw.and_then(|raw| { report.bytes = Some(raw.len()); parse_index(raw, &report.source) }) {
+        Ok(repo) => {
+            let (packages, versions) = count_versions(&repo);
+            report.packages = Some(packages);
+            report.versions = Some(versions);
+        }
+        Err(e) => report.error = Some(e),
+    }
+    report
+}
+
+fn print_ping(report: &PingReport, opts: &Opts) {
+    if opts.json {
+        print_json(report);
+        return;
+    }
+    println!("{}: {}", report.repo, report.source);
+    let answered = match (report.status, report.latency_ms) {
+        (Some(status), Some(ms)) => format!("HTTP {} in {} ms", status, ms),
+        _ if report.reachable => "file found".to_string(),
+        _ => "no answer".to_string(),
+    };
+    let (mark, colour) = if report.reachable { ("ok", GREEN) } else { ("FAIL", RED) };
+    println!("{}  reachable ({})", paint(mark, colour), answered);
+    match (report.packages, report.versions, &report.error) {
+        (Some(packages), Some(versions), _) => println!("{}    index parses ({} bytes, {} packages, {} versions)",
+            paint("ok", GREEN), report.bytes.unwrap_or(0), packages, versions),
+        (_, _, Some(e)) => println!("{}  {}", paint("FAIL", RED), e),
+        _ => {}
+    }
+}
+
+// Seconds since a remote repo's index was last fetched, if it is cached
+fn index_age(store: &Store, name: &str) -> Option<u64> {
+    if !store.cached_index_path(name).exists() { return None; }
+    load_registry(store).get(name)?.fetched_at.map(|at| now_secs().saturating_sub(at))
+}
+
+// Without a cache_ttl nothing is ever fresh, so a refresh always fetches
+fn index_stale(age: Option<u64>, ttl: Option<u64>) -> bool {
+    match (age, ttl) {
+        (Some(age), Some(ttl)) => age >= ttl,
+        _ => true,
+    }
+}
+
+// Re-download a remote repo's index into the cache, reporting what changed
+fn refresh_repo(store: &Store, name: &str, opts: &Opts) -> Result<(), String> {
+    let mut registry = load_registry(store);
+    let Some(url) = registry.get(name).and_then(|entry| entry.url.clone()) else {
+        println!("{}: local repo, nothing to refresh", name);
+        return Ok(());
+    };
+    if opts.dry_run {
+        let cache = match index_age(store, name) {
+            _ if !store.cached_index_path(name).exists() => "not cached yet".to_string(),
+            Some(age) if index_stale(Some(age), store.config.cache_ttl) => format!("cached {}, stale", time_ago(age)),
+            Some(age) => format!("cached {}, fresh", time_ago(age)),
+            None => "cached, age unknown".to_string(),
+        };
+        say(opts, &format!("{}: would fetch {} ({})", name, url, cache));
+        return Ok(());
+    }
+
+    let cached = store.cached_index_path(name);
+    let old = load_index(&cached).ok();
+    let data = download(&url, repo_auth(store, name)?.as_ref(), &store.config, opts)?;
+
+    // Only replace the cache once the new index is known to parse
+    fs::create_dir_all(store.cache_dir(name)).map_err(|e| e.to_string())?;
+    let tmp = cached.with_extension("mri.tmp");
+    fs::write(&tmp, &data).map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+    let new = match load_index(&tmp) {
+        Ok(repo) => repo,
+        Err(e) => { let _ = fs::remove_file(&tmp); return Err(e); }
+    };
+    let changed = fs::read(&cached).is_ok_and(|current| current != data);
+    if changed { let _ = fs::rename(&cached, store.previous_index_path(name)); }
+    fs::rename(&tmp, &cached).map_err(|e| format!("Failed to update {}: {}", cached.display(), e))?;
+
+    registry.entry(name.to_string()).or_default().fetched_at = Some(now_secs());
+    save_registry(store, &registry);
+
+    let (packages, versions) = count_versions(&new);
+    match old.map(|old| count_versions(&old)) {
+        None => say(opts, &format!("{}: fetched {} packages, {} versions", name, packages, versions)),
+        Some(_) if !changed => say(opts, &format!("{}: up to date ({} packages, {} versions)", name, packages, versions)),
+        Some((old_packages, old_versions)) => say(opts, &format!("{}: {} -> {} packages, {} -> {} versions (see /repo index-diff {})",
+            name, old_packages, packages, old_versions, versions, name)),
+    }
+    Ok(())
+}
+
+// --refresh: the given repos, or every enabled one when None; local repos are skipped quietly
+fn refresh_before_resolve(store: &Store, repos: Option<Vec<String>>, opts: &Opts) -> Result<(), String> {
+    let registry = load_registry(store);
+    let names = match repos {
+        Some(names) => {
+            let known = list_repos(store);
+            names.iter().filter_map(|name| match_name("Repo", known.iter().map(String::as_str), name, &store.config).ok().flatten())
+                .map(str::to_string).collect()
+        }
+        None => list_repos(store).into_iter().filter(|name| repo_enabled(&registry, name)).collect::<Vec<_>>(),
+    };
+    for name in names {
+        if registry.get(&name).is_some_and(|entry| entry.url.is_some()) {
+            refresh_repo(store, &name, opts).map_err(|e| format!("Failed to refresh {}: {}", name, e))?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize)]
+struct IndexDiff {
+    added: BTreeMap<String, String>,   // package -> latest version
+    removed: BTreeMap<String, String>, // package -> latest version it had
+    bumped: BTreeMap<String, Bump>,    // package -> latest version before and after
+}
+
+#[derive(Debug, Serialize)]
+struct Bump {
+    from: String,
+    to: String,
+}
+
+fn diff_indexes(old: &Repo, new: &Repo) -> IndexDiff {
+    let latest = |versions| latest_version(versions).unwrap_or_default().to_string();
+    let mut diff = IndexDiff::default();
+    for (name, versions) in &new.packages {
+        match old.packages.get(name) {
+            None => { diff.added.insert(name.clone(), latest(versions)); }
+            Some(old_versions) if latest(old_versions) != latest(versions) => {
+                diff.bumped.insert(name.clone(), Bump { from: latest(old_versions), to: latest(versions) });
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, versions) in &old.packages {
+        if !new.packages.contains_key(name) { diff.removed.insert(name.clone(), latest(versions)); }
+    }
+    diff
+}
+
+// What the last refresh of a remote repo changed
+fn index_diff(store: &Store, name: &str, opts: &Opts) -> Result<(), String> {
+    let previous = store.previous_index_path(name);
+    if !previous.exists() { return Err(format!("No earlier index of {} recorded yet; one is kept when a /repo refresh changes it", name)); }
+    let diff = diff_indexes(&load_index(&previous)?, &load_index(&store.cached_index_path(name))?);
+    if opts.json {
+        print_json(&diff);
+        return Ok(());
+    }
+    if diff.added.is_empty() && diff.removed.is_empty() && diff.bumped.is_empty() {
+        println!("{}: no package changes in the last refresh", name);
+    }
+    for (package, version) in &diff.added { println!("{} {} {}", paint("+", GREEN), package, version); }
+    for (package, Bump { from, to }) in &diff.bumped { println!("{} {} {} -> {}", paint("~", YELLOW), package, from, to); }
+    for (package, version) in &diff.removed { println!("{} {} {}", paint("-", RED), package, version); }
+    Ok(())
+}
+
+// --- /doctor ---
+// Names with an entry in packages.db, which calcbits appends as `NAME:<file>` lines
+fn packages_db_names(store: &Store) -> Vec<String> {
+    fs::read_to_string(store.packages_db_path()).unwrap_or_default().lines()
+        .filter_map(|line| line.strip_prefix("NAME:").map(str::to_string))
+        .collect()
+}
+
+// Packages whose recorded binaries don't all exist, left by a crash mid-install in stores from
+// before binaries were placed atomically, or by files deleted behind bpm's back
+fn partial_installs(db: &InstalledDb) -> Vec<(String, Vec<String>)> {
+    db.iter().filter_map(|(name, pkg)| {
+        let missing: Vec<String> = pkg.binaries.iter().filter(|bin| !Path::new(bin).is_file()).cloned().collect();
+        (!missing.is_empty()).then(|| (name.clone(), missing))
+    }).collect()
+}
+
+// /doctor --fix: complete the install, roll it back, or keep what is there. --yes completes.
+fn repair_partial(store: &Store, name: &str, pkg: &InstalledPackage, missing: &[String], opts: &Opts) -> bool {
+    let choices = [
+        format!("Complete: reinstall {} {} from {}", name, pkg.version, pkg.repo),
+        format!("Roll back: remove what is left of {}", name),
+        "Keep it, only drop the missing binaries from the installed DB".to_string(),
+    ];
+    let choice = if opts.yes {
+        Some(0)
+    } else if !io::stdin().is_terminal() {
+        println!("{} is partially installed. Pass --yes to reinstall it.", name);
+        None
+    } else {
+        prompt_choice(&format!("{} is partially installed. What should be done?", name), &choices)
+    };
+    let result = match choice {
+        None => return false,
+        Some(0) => match (pkg.repo.as_str(), &pkg.source_url) {
+            ("url", Some(url)) => install_url(store, url, Some(name), opts),
+            ("archive", _) => Err(format!("{} came from an archive; install it again with /i --archive", name)),
+            _ => install_package(store, &pkg.repo, name, Some(&pkg.version), opts),
+        },
+        Some(1) => { remove_package(store, name, None); Ok(()) }
+        Some(_) => {
+            let mut db = store.installed.load();
+            if let Some(pkg) = db.get_mut(name) {
+                pkg.binaries.retain(|bin| !missing.contains(bin));
+                pkg.checksums.retain(|bin, _| !missing.contains(bin));
+                pkg.verified_mtimes.retain(|bin, _| !missing.contains(bin));
+                if pkg.binaries.is_empty() { db.remove(name); }
+            }
+            store.installed.save(&db);
+            Ok(())
+        }
+    };
+    match result {
+        Ok(()) => true,
+        Err(e) => { println!("{} {}: {}", paint("Error:", RED), name, e); false }
+    }
+}
+
+// --- /cache ---
+// Cached indexes are cache/<repo>/packages.mri and the packages.prev.mri kept for index-diff;
+// everything else under cache/ (remote binaries, extracted archives) counts as binaries
+fn is_cached_index(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == "packages.mri" || name == "packages.prev.mri")
+}
+
+// The root's own cache, plus the one its shared repos' indexes are cached in
+fn cache_dirs(store: &Store) -> BTreeSet<PathBuf> {
+    BTreeSet::from([store.root.join("cache"), store.repos_root.join("cache")])
+}
+
+fn cache_files(store: &Store) -> Vec<(PathBuf, u64)> {
+    cache_dirs(store).iter()
+        .flat_map(|dir| glob::glob(&format!("{}/**/*", glob::Pattern::escape(&dir.to_string_lossy())))).flatten().flatten()
+        .filter_map(|path| fs::metadata(&path).ok().filter(|m| m.is_file()).map(|m| (path, m.len())))
+        .collect()
+}
+
+fn cache_command(store: &Store, args: &[String], opts: &Opts) -> Result<(), String> {
+    let files = cache_files(store);
+    let (indexes, binaries): (Vec<_>, Vec<_>) = files.into_iter().partition(|(path, _)| is_cached_index(path));
+    let total = |files: &[(PathBuf, u64)]| files.iter().map(|(_, size)| size).sum::<u64>();
+    match args.first().map(String::as_str) {
+        Some("info") => {
+            println!("Indexes:  {} files, {}", indexes.len(), human_size(total(&indexes)));
+            println!("Binaries: {} files, {}", binaries.len(), human_size(total(&binaries)));
+            println!("Total:    {}", human_size(total(&indexes) + total(&binaries)));
+        }
+        Some("clear") => {
+            // Neither flag clears both
+            let (clear_indexes, clear_binaries) = (opts.indexes || !opts.binaries, opts.binaries || !opts.indexes);
+            let mut doomed = Vec::new();
+            if clear_indexes { doomed.extend(&indexes); }
+            if clear_binaries { doomed.extend(&binaries); }
+            let freed: u64 = doomed.iter().map(|(_, size)| size).sum();
+            if opts.dry_run {
+                println!("Would remove {} files, freeing {}", doomed.len(), human_size(freed));
+                return Ok(());
+            }
+            for (path, _) in &doomed {
+                fs::remove_file(path).map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+            }
+            // Drop directories the removals emptied, deepest first; remove_dir leaves non-empty ones alone
+            let dirs: BTreeSet<PathBuf> = doomed.iter().flat_map(|(path, _)| path.ancestors().skip(1).map(Path::to_path_buf))
+                .filter(|dir| cache_dirs(store).iter().any(|cache| dir.starts_with(cache)))
+                .collect();
+            let mut dirs: Vec<PathBuf> = dirs.into_iter().collect();
+            dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+            for dir in dirs { let _ = fs::remove_dir(dir); }
+            println!("Removed {} files, freed {}", doomed.len(), human_size(freed));
+        }
+        _ => println!("Usage: bpm /cache info | clear [--indexes|--binaries] [--dry-run]"),
+    }
+    Ok(())
+}
+
+// --- /gc ---
+// packages.db is calcbits' append-only text format: entries run from an ENTRY marker line to an
+// END marker line, each with a NAME: line. Installs save each binary under its file name.
+struct DbEntry {
+    name: String,
+    text: String,
+}
+
+// Anything outside a complete entry, like one cut off by an interrupted write, is left out
+fn read_packages_db(content: &str) -> Vec<DbEntry> {
+    let mut entries = Vec::new();
+    let mut current: Option<DbEntry> = None;
+    for line in content.split_inclusive('\n') {
+        let marker = line.trim_end();
+        match current.as_mut() {
+            None if marker == "---ENTRY---" || marker == "###ENTRY###" => {
+                current = Some(DbEntry { name: String::new(), text: line.to_string() });
+            }
+            None => {}
+            Some(entry) => {
+                entry.text.push_str(line);
+                if let Some(name) = marker.strip_prefix("NAME:") { entry.name = name.to_string(); }
+                if marker == "---END---" || marker == "###END###" { entries.extend(current.take()); }
+            }
+        }
+    }
+    entries
+}
+
+// Drop every packages.db entry no installed binary refers to, plus older copies of ones that are
+fn gc(store: &Store, opts: &Opts) -> Result<(), String> {
+    let path = store.packages_db_path();
+    let Ok(content) = fs::read_to_string(&path) else { println!("No packages.db, nothing to collect."); return Ok(()); };
+    let entries = read_packages_db(&content);
+    let referenced: BTreeSet<String> = store.installed.iter()
+        .flat_map(|(_, pkg)| pkg.binaries)
+        .filter_map(|bin| Path::new(&bin).file_name().map(|name| name.to_string_lossy().to_string()))
+        .collect();
+
+    // Walk newest first so the latest save of each name is the one kept
+    let mut seen = BTreeSet::new();
+    let mut keep = vec![false; entries.len()];
+    for (i, entry) in entries.iter().enumerate().rev() {
+        keep[i] = referenced.contains(&entry.name) && seen.insert(entry.name.as_str());
+    }
+    let kept: String = entries.iter().zip(&keep).filter(|(_, keep)| **keep).map(|(entry, _)| entry.text.as_str()).collect();
+    let removed = keep.iter().filter(|keep| !**keep).count();
+    let reclaimed = content.len() - kept.len();
+
+    if opts.dry_run {
+        println!("Would remove {} of {} entries from packages.db, reclaiming {}", removed, entries.len(), human_size(reclaimed as u64));
+        return Ok(());
+    }
+    if reclaimed == 0 { println!("packages.db has nothing to collect."); return Ok(()); }
+    let tmp = path.with_extension("db.tmp");
+    fs::write(&tmp, kept).map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+    fs::rename(&tmp, &path).map_err(|e| format!("Failed to replace {}: {}", path.display(), e))?;
+    println!("Removed {} of {} entries from packages.db, reclaimed {}", removed, entries.len(), human_size(reclaimed as u64));
+    Ok(())
+}
+
+// Whole-store consistency check; with --fix, each category of problem offers its own repair
+fn doctor(store: &Store, opts: &Opts) -> Result<(), String> {
+    let mut problems = 0;
+    if let Err(e) = store.installed.check() {
+        println!("Installed DB: {}", e);
+        return Err("Installed DB is damaged, fix or restore it before anything else".to_string());
+    }
+    let db = store.installed.load();
+    let repos = list_repos(store);
+    let in_packages_db = packages_db_names(store);
+
+    let partial = partial_installs(&db);
+    let (mut modified, mut unregistered, mut unsaved) = (Vec::new(), Vec::new(), Vec::new());
+    for (name, pkg) in &db {
+        if !is_pseudo_repo(&pkg.repo) && !repos.contains(&pkg.repo) { unregistered.push((name.clone(), pkg.repo.clone())); }
+        for bin in &pkg.binaries {
+            let path = Path::new(bin);
+            if !path.is_file() { continue; }
+            if pkg.checksums.get(bin).is_some_and(|sum| sha256_file(path).as_ref() != Some(sum)) {
+                modified.push((name.clone(), bin.clone()));
+            }
+            let file = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            // Without packages.db, a binary missing from it is the point rather than a problem
+            if store.config.save_db(opts) && !in_packages_db.contains(&file) { unsaved.push(bin.clone()); }
+        }
+    }
+    let owned: Vec<PathBuf> = db.values().flat_map(|pkg| &pkg.binaries).map(normalize_path).collect();
+    let orphans: Vec<PathBuf> = fs::read_dir(store.bins_dir()).into_iter().flatten().flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && !owned.contains(&normalize_path(path)))
+        .collect();
+
+    if !partial.is_empty() {
+        problems += partial.len();
+        println!("Partially installed packages (recorded binaries missing):");
+        for (name, bins) in &partial { println!("  {} {}: {}", name, db[name].version, bins.join(", ")); }
+        if opts.fix {
+            for (name, bins) in &partial {
+                if repair_partial(store, name, &db[name], bins, opts) { problems -= 1; }
+            }
+        }
+    }
+    if !modified.is_empty() {
+        problems += modified.len();
+        println!("Binaries that no longer match their checksum:");
+        for (name, bin) in &modified { println!("  {} ({})", bin, name); }
+        if opts.fix && confirm("Reinstall the affected packages?", opts) {
+            let mut names: Vec<&String> = modified.iter().map(|(name, _)| name).collect();
+            names.dedup();
+            for name in names {
+                let pkg = &db[name];
+                match install_package(store, &pkg.repo, name, Some(&pkg.version), opts) {
+                    Ok(()) => problems -= modified.iter().filter(|(n, _)| n == name).count(),
+                    Err(e) => println!("{} {}: {}", paint("Error:", RED), name, e),
+                }
+            }
+        }
+    }
+    if !unregistered.is_empty() {
+        problems += unregistered.len();
+        println!("Packages from repos that no longer exist (re-add the repo or remove the package):");
+        for (name, repo) in &unregistered { println!("  {} ({})", name, repo); }
+    }
+    if !unsaved.is_empty() {
+        problems += unsaved.len();
+        println!("Installed binaries missing from packages.db:");
+        for bin in &unsaved { println!("  {}", bin); }
+        if opts.fix && confirm("Save them to packages.db?", opts) {
+            for bin in &unsaved {
+                let file = Path::new(bin).file_name().unwrap_or_default().to_string_lossy();
+                if fs::read(bin).is_ok_and(|data| save_binary_to_db(store, &file, &data, opts).is_ok()) { problems -= 1; }
+            }
+        }
+    }
+    if !orphans.is_empty() {
+        problems += orphans.len();
+        println!("Files in bins/ not owned by any package:");
+        for path in &orphans { println!("  {}", path.display()); }
+        if opts.fix && confirm("Delete them?", opts) {
+            for path in &orphans { if fs::remove_file(path).is_ok() { problems -= 1; } }
+        }
+    }
+
+    if problems == 0 { println!("Store is consistent."); Ok(()) }
+    else { Err(format!("{} problems remain{}", problems, if opts.fix { "" } else { "; run /doctor --fix to repair" })) }
+}
+
+// Verify installed binaries against their recorded checksums
+fn verify_installed(store: &Store, package: Option<&str>, opts: &Opts) -> Result<(), String> {
+    let mut db = store.installed.load();
+    if let Some(name) = package && !db.contains_key(name) {
+        return Err(format!("Package {} is not installed", name));
+    }
+    let (mut ok, mut skipped, mut bad, mut repaired) = (0, 0, 0, 0);
+
+    for (name, pkg) in db.iter_mut() {
+        if package.is_some_and(|wanted| wanted != name) { continue; }
+        let installed = pkg.clone();
+        // Only what's broken is touched; the rest of the package is left as it is
+        let repair = |bin: &str, pkg: &mut InstalledPackage| -> bool {
+            if !opts.repair { return false; }
+            if opts.dry_run { println!("  would repair {}", bin); return false; }
+            match repair_binary(store, name, &installed, bin, opts) {
+                Ok(sum) => {
+                    println!("{} {} ({})", paint("REPAIRED", GREEN), bin, name);
+                    pkg.checksums.insert(bin.to_string(), sum);
+                    if let Some(mtime) = mtime_of(Path::new(bin)) { pkg.verified_mtimes.insert(bin.to_string(), mtime); }
+                    true
+                }
+                Err(e) => { println!("  repair failed: {}", e); false }
+            }
+        };
+        for bin in &installed.binaries {
+            let path = Path::new(bin);
+            let Some(mtime) = mtime_of(path) else {
+                println!("{}  {} ({})", paint("MISSING", RED), bin, name);
+                if repair(bin, pkg) { repaired += 1; } else { bad += 1; }
+                continue;
+            };
+            // Untouched since the last good verify: trust the cached result
+            if !opts.deep && pkg.verified_mtimes.get(bin) == Some(&mtime) {
+                skipped += 1;
+                continue;
+            }
+            let actual = sha256_file(path).unwrap_or_default();
+            match pkg.checksums.get(bin) {
+                Some(expected) if *expected != actual => {
+                    println!("{} {} ({})", paint("MODIFIED", RED), bin, name);
+                    pkg.verified_mtimes.remove(bin);
+                    if repair(bin, pkg) { repaired += 1; } else { bad += 1; }
+                    continue;
+                }
+                Some(_) => {}
+                None => { pkg.checksums.insert(bin.clone(), actual); } // installed before checksums were recorded
+            }
+            pkg.verified_mtimes.insert(bin.clone(), mtime);
+            ok += 1;
+        }
+    }
+
+    // A frozen store is still checked, the mtime cache just isn't updated
+    if !store.config.frozen(opts) { store.installed.save(&db); }
+    if opts.repair { println!("{} ok, {} unchanged since last verify, {} repaired, {} failed", ok, skipped, repaired, bad); }
+    else { println!("{} ok, {} unchanged since last verify, {} failed", ok, skipped, bad); }
+    if bad > 0 { return Err(format!("{} binaries failed verification", bad)); }
+    Ok(())
+}
+
+// Puts one installed binary back from where its recorded repo and version say it came from. The
+// source has to match the recorded checksum (a corrupt cached download is fetched again), and
+// place_file only replaces the installed file once the copy checks out, so a failure leaves it as it was.
+fn repair_binary(store: &Store, name: &str, pkg: &InstalledPackage, bin: &str, opts: &Opts) -> Result<String, String> {
+    let expected = pkg.checksums.get(bin);
+    let dest = Path::new(bin);
+    if let Some(parent) = dest.parent() { fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?; }
+    if pkg.repo == "url" {
+        let url = pkg.source_url.as_deref().ok_or_else(|| format!("no source url recorded for {}", name))?;
+        let data = download(url, None, &store.config, opts)?;
+        if expected.is_some_and(|sum| !sum.eq_ignore_ascii_case(&sha256_bytes(&data))) {
+            return Err(format!("{} no longer serves the recorded binary", url));
+        }
+        return place_bytes(&data, dest, store.config.mode.unwrap_or(DEFAULT_BIN_MODE));
+    }
+    if is_pseudo_repo(&pkg.repo) { return Err(format!("{} came from an archive; reinstall it from the archive", name)); }
+    let repo = load_index(&index_path(store, &pkg.repo))?;
+    let step = plan_step(store, None, &repo, &pkg.repo, name, &pkg.version, pkg.explicit)?;
+    let source = step.binaries.iter()
+        .find(|candidate| normalize_path(bin_dest(store, &step, candidate, &pkg.aliases, opts)) == normalize_path(dest))
+        .ok_or_else(|| format!("{}:{} in repo {} has no binary that installs as {}", name, pkg.version, pkg.repo, bin))?;
+    let expected = expected.or(step.checksums.get(source));
+    let matches = |src: &Path| expected.is_none_or(|sum| sha256_file(src).is_some_and(|actual| actual.eq_ignore_ascii_case(sum)));
+    let mut src = source_file(store, &step, source, opts)?;
+    if !matches(&src) && is_remote(&step.path) {
+        let _ = fs::remove_file(&src);
+        src = source_file(store, &step, source, opts)?;
+    }
+    if !matches(&src) { return Err(format!("the source {} doesn't match the recorded checksum either", src.display())); }
+    place_file(&src, dest, step.mode.or(store.config.mode).unwrap_or(DEFAULT_BIN_MODE))
+}
+
+// Files a package installed, with whether each is still there
+#[derive(Serialize)]
+struct FileStatus {
+    path: String,
+    exists: bool,
+    size: Option<u64>,
+}
+
+// Absolute, symlink-resolved form of a path so different spellings compare equal
+fn normalize_path(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    fs::canonicalize(path).or_else(|_| std::path::absolute(path)).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn list_files(store: &Store, package: &str, opts: &Opts) -> Result<(), String> {
+    let pkg = store.installed.get(package).ok_or_else(|| format!("Package {} is not installed", package))?;
+
+    let files: Vec<FileStatus> = pkg.binaries.iter().map(|bin| {
+        let path = normalize_path(bin);
+        let size = fs::metadata(&path).ok().map(|m| m.len());
+        FileStatus { path: path.to_string_lossy().to_string(), exists: size.is_some(), size }
+    }).collect();
+
+    if opts.json { print_json(&files); return Ok(()); }
+    for file in files {
+        match file.size {
+            Some(size) => println!("{} ({} bytes)", file.path, size),
+            None => println!("{} (MISSING)", file.path),
+        }
+    }
+    Ok(())
+}
+
+// Which installed package(s) a file belongs to
+// /which: only paths go to stdout, so `$(bpm /which tool)` never captures an error message
+fn which(store: &Store, package: &str, opts: &Opts) -> Result<(), String> {
+    for path in which_paths(store, package, opts)? { println!("{}", path.display()); }
+    Ok(())
+}
+
+// Absolute paths of the binaries /which prints: the package's main one, or all with --all
+fn which_paths(store: &Store, package: &str, opts: &Opts) -> Result<Vec<PathBuf>, String> {
+    let pkg = store.installed.get(package).ok_or_else(|| format!("Package {} is not installed", package))?;
+    let primary: Vec<&String> = match pkg.binaries.as_slice() {
+        _ if opts.all => pkg.binaries.iter().collect(),
+        [only] => vec![only],
+        many => {
+            let named: Vec<&String> = many.iter()
+                .filter(|bin| Path::new(bin).file_stem().is_some_and(|stem| stem == package))
+                .collect();
+            if named.len() != 1 {
+                return Err(format!("{} has {} binaries and none is clearly its main one; use --all", package, many.len()));
+            }
+            named
+        }
+    };
+    if primary.is_empty() { return Err(format!("{} has no binaries", package)); }
+    Ok(primary.into_iter().map(|bin| std::path::absolute(bin).unwrap_or_else(|_| PathBuf::from(bin))).collect())
+}
+
+fn owns(store: &Store, path: &str) -> Result<(), String> {
+    let target = normalize_path(path);
+    let owners: Vec<String> = store.installed.iter()
+        .filter(|(_, pkg)| pkg.binaries.iter().any(|bin| normalize_path(bin) == target))
+        .map(|(name, pkg)| format!("{} ({})", name, pkg.version))
+        .collect();
+    if owners.is_empty() { return Err(format!("{} is not owned by any package", target.display())); }
+    for owner in owners { println!("{} is owned by {}", target.display(), owner); }
+    Ok(())
+}
+
+// /why: what keeps a package installed, read from the recorded dependencies in reverse
+fn why(store: &Store, package: &str) -> Result<(), String> {
+    let db = store.installed.load();
+    let pkg = db.get(package).ok_or_else(|| format!("Package {} is not installed", package))?;
+    if pkg.explicit { println!("{} {} was installed by name", package, pkg.version); }
+    let chains = dependent_chains(&db, package);
+    for chain in &chains { println!("{}", chain.join(" -> ")); }
+    if !pkg.explicit && chains.is_empty() { println!("Nothing installed depends on {}; /clean would remove it", package); }
+    Ok(())
+}
+
+// Every chain of dependents leading to `package`, each starting at an explicit package or one that
+// nothing depends on. A dependent already on the chain is not followed again, so cycles end.
+fn dependent_chains(db: &InstalledDb, package: &str) -> Vec<Vec<String>> {
+    fn walk<'a>(db: &'a InstalledDb, path: &mut Vec<&'a str>, out: &mut Vec<Vec<String>>) {
+        let name = path[path.len() - 1];
+        let dependents: Vec<&str> = dependents_of(db, name).into_iter().filter(|dependent| !path.contains(dependent)).collect();
+        if path.len() > 1 && (db[name].explicit || dependents.is_empty()) {
+            out.push(path.iter().rev().map(|name| format!("{} {}", name, db[*name].version)).collect());
+            return;
+        }
+        for dependent in dependents {
+            path.push(dependent);
+            walk(db, path, out);
+            path.pop();
+        }
+    }
+    let mut out = Vec::new();
+    if let Some((name, _)) = db.get_key_value(package) { walk(db, &mut vec![name.as_str()], &mut out); }
+    out
+}
+
+// Details of one installed package; -v adds checksums and the index it was resolved from
+fn info(store: &Store, package: &str, opts: &Opts) -> Result<(), String> {
+    let db = store.installed.load();
+    if opts.json {
+        let info = package_info(store, package, db.get(package));
+        if info.installed.is_none() && info.available.is_empty() { return Err(format!("Package {} not found", package)); }
+        print_json(&info);
+        return Ok(());
+    }
+    let pkg = db.get(package).ok_or_else(|| format!("Package {} is not installed", package))?;
+    println!("Package:  {}", package);
+    println!("Version:  {}", pkg.version);
+    println!("Repo:     {}", pkg.repo);
+    println!("Binaries: {}", pkg.binaries.len());
+    if let Some(url) = &pkg.source_url { println!("Source:   {}", url); }
+    for (bin, alias) in &pkg.aliases { println!("Alias:    {} installed as {}", bin, alias); }
+    if !pkg.dependencies.is_empty() { println!("Depends:  {}", pkg.dependencies.join(", ")); }
+    let deprecated = load_repo(store, &pkg.repo).and_then(|mut repo| repo.packages.remove(package))
+        .and_then(|mut versions| versions.remove(&pkg.version)).and_then(|ver| ver.deprecated);
+    if let Some(why) = deprecated { println!("{} {}", paint("Deprecated:", YELLOW), why); }
+    if !opts.verbose { return Ok(()); }
+    for bin in &pkg.binaries {
+        println!("  {}  {}", pkg.checksums.get(bin).map(String::as_str).unwrap_or("-"), bin);
+    }
+    match (&pkg.index_sha256, pkg.index_schema) {
+        (Some(sum), Some(schema)) => println!("Index:    {} (schema v{})", sum, schema),
+        (Some(sum), None) => println!("Index:    {}", sum),
+        _ => println!("Index:    not recorded"),
+    }
+    Ok(())
+}
+
+// /info --deps-only: the direct dependencies of the installed version, or of the latest in the
+// first enabled repo when not installed. Declared strings as the index has them; with --resolved,
+// the name:version each one was (or would be) resolved to.
+fn info_deps(store: &Store, package: &str, opts: &Opts) -> Result<(), String> {
+    let deps = info_dependencies(store, package, opts)?;
+    if opts.json {
+        print_json(&deps);
+    } else {
+        for dep in deps { println!("{}", dep); }
+    }
+    Ok(())
+}
+
+fn info_dependencies(store: &Store, package: &str, opts: &Opts) -> Result<Vec<String>, String> {
+    let installed = store.installed.get(package);
+    let (repo_name, version) = match &installed {
+        Some(pkg) => (pkg.repo.clone(), pkg.version.clone()),
+        None => {
+            let registry = load_registry(store);
+            list_repos(store).into_iter().filter(|name| repo_enabled(&registry, name))
+                .find_map(|name| {
+                    let version = latest_version(load_repo(store, &name)?.packages.get(package)?)?.to_string();
+                    Some((name, version))
+                })
+                .ok_or_else(|| format!("Package {} not found", package))?
+        }
+    };
+    Ok(match (&installed, opts.resolved) {
+        (Some(pkg), true) => pkg.dependencies.clone(),
+        (None, true) => {
+            let mut events = CliEvents::new(opts);
+            let mut resolver = Resolver::new(store, &mut events, opts);
+            resolver.db.clear();
+            resolver.visit(&repo_name, package, Some(&version), true)?;
+            let plan = resolver.finish();
+            if let Some(conflict) = plan.conflicts.first() { return Err(conflict.clone()); }
+            plan.install.into_iter().find(|step| step.package == package).map(|step| step.dependencies).unwrap_or_default()
+        }
+        (_, false) => {
+            let repo = load_repo(store, &repo_name).ok_or_else(|| format!("Repo {} not found", repo_name))?;
+            repo.packages.get(package).and_then(|versions| versions.get(&version))
+                .map(|ver| ver.dependencies.clone())
+                .ok_or_else(|| format!("{}:{} is no longer in repo {}", package, version, repo_name))?
+        }
+    })
+}
+
+// /info --json: installed state and what the repos offer, in one document. Fields are only ever
+// added; a change that breaks readers bumps `schema`.
+const INFO_SCHEMA: u32 = 1;
+
+#[derive(Debug, Serialize)]
+struct PackageInfo<'a> {
+    schema: u32,
+    name: &'a str,
+    status: &'static str, // "not-installed", "installed" or "outdated"
+    installed: Option<&'a InstalledPackage>,
+    latest: Option<String>, // newest in the installed package's repo, or in any repo when not installed
+    available: Vec<AvailableVersion>, // per repo, newest first
+}
+
+#[derive(Debug, Serialize)]
+struct AvailableVersion {
+    repo: String,
+    version: String,
+    description: Option<String>,
+    binaries: Vec<String>,
+    dependencies: Vec<String>,
+    checksums: BTreeMap<String, String>,
+    deprecated: Option<String>,
+}
+
+fn package_info<'a>(store: &Store, package: &'a str, installed: Option<&'a InstalledPackage>) -> PackageInfo<'a> {
+    let registry = load_registry(store);
+    let mut available = Vec::new();
+    for repo_name in list_repos(store) {
+        // The installed package's own repo counts even when disabled
+        if !repo_enabled(&registry, &repo_name) && installed.is_none_or(|pkg| pkg.repo != repo_name) { continue; }
+        let Some(versions) = load_repo(store, &repo_name).and_then(|mut repo| repo.packages.remove(package)) else { continue; };
+        let mut versions: Vec<(String, PackageVersion)> = versions.into_iter().collect();
+        versions.sort_by(|(a, _), (b, _)| compare_versions(b, a));
+        available.extend(versions.into_iter().map(|(version, pkg)| AvailableVersion {
+            repo: repo_name.clone(),
+            version,
+            description: pkg.description,
+            binaries: pkg.binaries,
+            dependencies: pkg.dependencies,
+            checksums: pkg.checksums,
+            deprecated: pkg.deprecated,
+        }));
+    }
+    // Same rule as /u and /outdated: only the repo a package came from can make it outdated
+    let latest = available.iter()
+        .filter(|v| installed.is_none_or(|pkg| pkg.repo == v.repo))
+        .max_by(|a, b| compare_versions(&a.version, &b.version))
+        .map(|v| v.version.clone());
+    let status = match (installed, &latest) {
+        (None, _) => "not-installed",
+        (Some(pkg), Some(latest)) if compare_versions(latest, &pkg.version) == Ordering::Greater => "outdated",
+        (Some(_), _) => "installed",
+    };
+    PackageInfo { schema: INFO_SCHEMA, name: package, status, installed, latest, available }
+}
+
+// --- /search ---
+// Case-insensitive substring match on names and descriptions, or a regex with --regex
+fn search(store: &Store, query: &str, opts: &Opts) -> Result<(), String> {
+    let found = search_matches(store, query, opts)?;
+    for line in &found { println!("{}", line); }
+    if found.is_empty() { println!("No packages match {}", query); }
+    Ok(())
+}
+
+// One "repo:name version - description" line per package whose latest version matches
+fn search_matches(store: &Store, query: &str, opts: &Opts) -> Result<Vec<String>, String> {
+    let pattern = if opts.regex {
+        Some(regex::Regex::new(query).map_err(|e| format!("Invalid regex {}: {}", query, e))?)
+    } else {
+        None
+    };
+    let needle = query.to_lowercase();
+    let matches = |text: &str| match &pattern {
+        Some(re) => re.is_match(text),
+        None => text.to_lowercase().contains(&needle),
+    };
+
+    let registry = load_registry(store);
+    let mut found = Vec::new();
+    for repo_name in list_repos(store) {
+        if !repo_enabled(&registry, &repo_name) { continue; }
+        let Some(repo) = load_repo(store, &repo_name) else { continue; };
+        for (name, versions) in &repo.packages {
+            let Some(latest) = latest_version(versions) else { continue; };
+            let description = versions[latest].description.as_deref().unwrap_or_default();
+            if !matches(name) && !matches(description) { continue; }
+            if description.is_empty() { found.push(format!("{}:{} {}", repo_name, name, latest)); }
+            else { found.push(format!("{}:{} {} - {}", repo_name, name, latest, description)); }
+        }
+    }
+    Ok(found)
+}
+
+// --- /versions ---
+#[derive(Debug, Serialize)]
+struct VersionEntry {
+    repo: String,
+    version: String,
+    installed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deprecated: Option<String>,
+}
+
+// Every version a package has, newest first, across all enabled repos unless one is named
+fn list_versions(store: &Store, arg: &str, opts: &Opts) -> Result<(), String> {
+    let (package, entries) = version_entries(store, arg, opts)?;
+    if opts.json {
+        print_json(&entries);
+        return Ok(());
+    }
+    if entries.is_empty() { println!("No versions of {} newer than {}", package, opts.since.as_deref().unwrap_or_default()); }
+    for entry in &entries {
+        let marker = if entry.installed { " (installed)" } else { "" };
+        match &entry.deprecated {
+            Some(why) => println!("{}:{}:{}{} {} {}", entry.repo, package, entry.version, marker, paint("deprecated:", YELLOW), why),
+            None => println!("{}:{}:{}{}", entry.repo, package, entry.version, marker),
+        }
+    }
+    Ok(())
+}
+
+// The package name and its versions in each repo, newest first, after --since and --limit
+fn version_entries(store: &Store, arg: &str, opts: &Opts) -> Result<(String, Vec<VersionEntry>), String> {
+    let (repo, package, _) = parse_pkg_arg(arg)?;
+    let registry = load_registry(store);
+    let repo_names = match repo {
+        Some(repo) if !list_repos(store).contains(&repo) => return Err(format!("Repo {} not found", repo)),
+        Some(repo) => vec![repo],
+        None => list_repos(store).into_iter().filter(|name| repo_enabled(&registry, name)).collect(),
+    };
+    let installed = store.installed.get(&package);
+    let mut found = false;
+    let mut entries = Vec::new();
+    for repo_name in repo_names {
+        let Some(versions) = load_repo(store, &repo_name).and_then(|mut repo| repo.packages.remove(&package)) else { continue; };
+        if versions.is_empty() { println!("{} {}", paint("⚠", YELLOW), no_versions(&package, &repo_name)); continue; }
+        found = true;
+        let mut versions: Vec<(String, PackageVersion)> = versions.into_iter()
+            .filter(|(ver, _)| opts.since.as_deref().is_none_or(|since| compare_versions(ver, since) == Ordering::Greater))
+            .collect();
+        versions.sort_by(|(a, _), (b, _)| compare_versions(b, a));
+        versions.truncate(opts.limit.unwrap_or(usize::MAX));
+        for (version, pkg) in versions {
+            let is_installed = installed.as_ref().is_some_and(|pkg| pkg.repo == repo_name && pkg.version == version);
+            entries.push(VersionEntry { repo: repo_name.clone(), version, installed: is_installed, deprecated: pkg.deprecated });
+        }
+    }
+    if !found { return Err(format!("Package {} not found", package)); }
+    Ok((package, entries))
+}
+
+// --- /export and /diff ---
+// A manifest is TOML: one `[packages.<name>]` table per package with its repo and version
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    packages: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ManifestEntry {
+    repo: String,
+    version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>, // for `/i --url` packages, which can't be found in a repo
+}
+
+fn export_manifest(store: &Store, path: Option<&str>) -> Result<(), String> {
+    let packages = store.installed.iter()
+        .map(|(name, pkg)| (name, ManifestEntry { repo: pkg.repo, version: pkg.version, url: pkg.source_url }))
+        .collect();
+    let content = toml::to_string(&Manifest { packages }).map_err(|e| e.to_string())?;
+    match path {
+        Some(path) => {
+            fs::write(path, content).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+            println!("Exported installed packages to {}", path);
+        }
+        None => print!("{}", content),
+    }
+    Ok(())
+}
+
+// A bundle is a directory with bundle.toml and one <package>/ directory of binaries per package.
+// Paths in it are relative to the package's install location, so it can be imported into any store.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Bundle {
+    #[serde(default)]
+    packages: BTreeMap<String, BundleEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BundleEntry {
+    repo: String,
+    version: String,
+    #[serde(default)]
+    files: BTreeMap<String, String>, // path under <package>/ -> sha256
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+// Where an installed binary sits relative to its package: the file name in bins/,
+// or its path inside <prefix>/<repo>/<package>/
+fn bundle_path(store: &Store, name: &str, pkg: &InstalledPackage, bin: &str, opts: &Opts) -> PathBuf {
+    let bin = Path::new(bin);
+    let package_dir = store.config.prefix(opts).map(|prefix| Path::new(prefix).join(&pkg.repo).join(name));
+    match package_dir.as_ref().and_then(|dir| bin.strip_prefix(dir).ok()) {
+        Some(relative) => relative.to_path_buf(),
+        None => bin.strip_prefix(store.bins_dir()).map(Path::to_path_buf).unwrap_or_else(|_| PathBuf::from(bin.file_name().unwrap())),
+    }
+}
+
+fn export_bundle(store: &Store, dir: &str, opts: &Opts) -> Result<(), String> {
+    let dir = Path::new(dir);
+    let mut bundle = Bundle::default();
+    for (name, pkg) in store.installed.iter() {
+        let mut files = BTreeMap::new();
+        for bin in &pkg.binaries {
+            let relative = bundle_path(store, &name, &pkg, bin, opts);
+            let dest = dir.join(&name).join(&relative);
+            fs::create_dir_all(dest.parent().unwrap()).map_err(|e| e.to_string())?;
+            fs::copy(bin, &dest).map_err(|e| format!("Failed to copy {}: {}", bin, e))?;
+            let sum = sha256_file(&dest).ok_or_else(|| format!("Failed to read {}", dest.display()))?;
+            files.insert(relative.to_string_lossy().replace('\\', "/"), sum);
+        }
+        bundle.packages.insert(name, BundleEntry { repo: pkg.repo, version: pkg.version, files, url: pkg.source_url });
+    }
+    let content = toml::to_string(&bundle).map_err(|e| e.to_string())?;
+    fs::write(dir.join("bundle.toml"), content).map_err(|e| format!("Failed to write bundle.toml: {}", e))?;
+    println!("Bundled {} packages into {}", bundle.packages.len(), dir.display());
+    Ok(())
+}
+
+// Install everything in a bundle without touching any repo. Every file is checked
+// against its recorded sha256 before anything is copied.
+fn import_bundle(store: &Store, dir: &str, opts: &Opts) -> Result<(), String> {
+    let dir = Path::new(dir);
+    let manifest = dir.join("bundle.toml");
+    let content = fs::read_to_string(&manifest).map_err(|_| format!("Failed to read {}", manifest.display()))?;
+    let bundle: Bundle = toml::from_str(&content).map_err(|e| format!("Failed to parse {}: {}", manifest.display(), e))?;
+    // The manifest's names become paths under the store or --prefix, so none may point outside them
+    for (name, entry) in &bundle.packages {
+        check_name("package", name)?;
+        check_repo_name(&entry.repo)?;
+        check_name("version", &entry.version)?;
+        for file in entry.files.keys() { check_binary_path(file)?; }
+    }
+    for (name, entry) in &bundle.packages {
+        for (file, expected) in &entry.files {
+            if sha256_file(&dir.join(name).join(file)).as_ref() != Some(expected) {
+                return Err(format!("Checksum mismatch for {} in {}, refusing to import", file, name));
+            }
+        }
+    }
+    if opts.dry_run {
+        for (name, entry) in &bundle.packages { println!("Would import {} {} ({})", name, entry.version, entry.repo); }
+        return Ok(());
+    }
+
+    for (name, entry) in &bundle.packages {
+        let (mut binaries, mut checksums, mut verified_mtimes) = (Vec::new(), BTreeMap::new(), BTreeMap::new());
+        for (file, sum) in &entry.files {
+            let dest = match store.config.prefix(opts) {
+                Some(prefix) => Path::new(prefix).join(&entry.repo).join(name).join(file),
+                None => store.bins_dir().join(Path::new(file).file_name().unwrap()),
+            };
+            fs::create_dir_all(dest.parent().unwrap()).map_err(|e| e.to_string())?;
+            place_file(&dir.join(name).join(file), &dest, store.config.mode.unwrap_or(DEFAULT_BIN_MODE))?;
+            let dest_str = dest.to_string_lossy().to_string();
+            checksums.insert(dest_str.clone(), sum.clone());
+            if let Some(mtime) = mtime_of(&dest) { verified_mtimes.insert(dest_str.clone(), mtime); }
+            binaries.push(dest_str);
+        }
+        for old in store.installed.get(name).iter().flat_map(|pkg| &pkg.binaries) {
+            if !binaries.contains(old) { let _ = fs::remove_file(old); }
+        }
+        let size = binaries.iter().filter_map(|bin| fs::metadata(bin).ok()).map(|m| m.len()).sum();
+        let previous = previous_of(store.installed.get(name).as_ref(), &entry.repo, &entry.version);
+        store.installed.insert(name, InstalledPackage {
+            repo: entry.repo.clone(),
+            version: entry.version.clone(),
+            binaries,
+            checksums,
+            verified_mtimes,
+            index_sha256: None,
+            index_schema: None,
+            source_url: entry.url.clone(),
+            installed_at: Some(now_secs()),
+            size: Some(size),
+            aliases: BTreeMap::new(),
+            explicit: true,
+            dependencies: Vec::new(),
+            previous,
+        });
+        println!("Imported {} {}", name, entry.version);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ManifestDiff {
+    install: BTreeMap<String, ManifestEntry>, // in the manifest, not installed
+    remove: BTreeMap<String, ManifestEntry>,  // installed, not in the manifest
+    change: BTreeMap<String, Change>,
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    held: BTreeSet<String>, // would change, but are held, so are left alone
+}
+
+#[derive(Debug, Serialize)]
+struct Change {
+    from: ManifestEntry, // installed
+    to: ManifestEntry,   // wanted
+}
+
+fn diff_manifest(store: &Store, manifest: &Manifest) -> ManifestDiff {
+    let mut diff = ManifestDiff::default();
+    let holds = load_holds(store);
+    let installed: BTreeMap<String, ManifestEntry> = store.installed.iter()
+        .map(|(name, pkg)| (name, ManifestEntry { repo: pkg.repo, version: pkg.version, url: pkg.source_url }))
+        .collect();
+    for (name, wanted) in &manifest.packages {
+        match installed.get(name) {
+            Some(current) if current != wanted && holds.contains(name) => { diff.held.insert(name.clone()); }
+            None => { diff.install.insert(name.clone(), wanted.clone()); }
+            Some(current) if current.repo != wanted.repo || current.version != wanted.version => {
+                diff.change.insert(name.clone(), Change { from: current.clone(), to: wanted.clone() });
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, current) in installed {
+        if manifest.packages.contains_key(&name) { continue; }
+        if holds.contains(&name) { diff.held.insert(name); } else { diff.remove.insert(name, current); }
+    }
+    diff
+}
+
+fn print_diff(diff: &ManifestDiff, opts: &Opts) {
+    if opts.json {
+        print_json(diff);
+        return;
+    }
+    for name in &diff.held { println!("{} {} (held, left as is)", paint("=", YELLOW), name); }
+    if diff.install.is_empty() && diff.remove.is_empty() && diff.change.is_empty() {
+        if diff.held.is_empty() { println!("Installed packages match the manifest."); }
+        return;
+    }
+    for (name, entry) in &diff.install { println!("{} {} {} from {}", paint("+", GREEN), name, entry.version, entry.repo); }
+    for (name, Change { from, to }) in &diff.change {
+        println!("{} {} {} ({}) -> {} ({})", paint("~", YELLOW), name, from.version, from.repo, to.version, to.repo);
+    }
+    for (name, entry) in &diff.remove { println!("{} {} {}", paint("-", RED), name, entry.version); }
+}
+
+fn install_entry(store: &Store, name: &str, entry: &ManifestEntry, opts: &Opts) -> Result<(), String> {
+    match &entry.url {
+        Some(archive) if entry.repo == "archive" => install_archive(store, archive, &Opts { name: Some(name.to_string()), ..opts.clone() }),
+        Some(url) => install_url(store, url, Some(name), opts),
+        None => install_package(store, &entry.repo, name, Some(&entry.version), opts),
+    }
+}
+
+fn wanted_entries(diff: &ManifestDiff) -> impl Iterator<Item = (&String, &ManifestEntry)> {
+    diff.install.iter().chain(diff.change.iter().map(|(name, change)| (name, &change.to)))
+}
+
+fn apply_changes(store: &Store, diff: &ManifestDiff, opts: &Opts) -> Result<(), String> {
+    for name in diff.remove.keys() { remove_package(store, name, None); }
+    for (name, entry) in wanted_entries(diff) {
+        install_entry(store, name, entry, opts).map_err(|e| format!("{}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+// Apply a diff all-or-nothing: every install is resolved before anything changes, and a failure
+// part-way restores the backed-up binaries and the previous installed DB
+fn apply_diff(store: &Store, diff: &ManifestDiff, opts: &Opts) -> Result<(), String> {
+    for (name, entry) in wanted_entries(diff) {
+        if entry.url.is_some() { continue; }
+        let plan = resolve_plan(store, &entry.repo, name, Some(&entry.version), opts)?;
+        if let Some(conflict) = plan.conflicts.first() { return Err(conflict.clone()); }
+    }
+
+    let before = store.installed.load();
+    let backup_dir = store.root.join("cache").join("transaction");
+    let _ = fs::remove_dir_all(&backup_dir);
+    fs::create_dir_all(&backup_dir).map_err(|e| e.to_string())?;
+    let mut backups = Vec::new();
+    for bin in before.values().flat_map(|pkg| &pkg.binaries) {
+        let backup = backup_dir.join(backups.len().to_string());
+        if fs::copy(bin, &backup).is_ok() { backups.push((PathBuf::from(bin), backup)); }
+    }
+
+    if let Err(e) = apply_changes(store, diff, opts) {
+        for (name, pkg) in store.installed.iter() {
+            if before.contains_key(&name) { continue; }
+            for bin in &pkg.binaries { let _ = fs::remove_file(bin); }
+        }
+        for (bin, backup) in &backups {
+            if let Some(parent) = bin.parent() { let _ = fs::create_dir_all(parent); }
+            let _ = fs::copy(backup, bin);
+        }
+        store.installed.save(&before);
+        let _ = fs::remove_dir_all(&backup_dir);
+        return Err(format!("{}; all changes rolled back", e));
+    }
+    let _ = fs::remove_dir_all(&backup_dir);
+    println!("Installed packages now match the manifest.");
+    Ok(())
+}
+
+fn diff_command(store: &Store, path: &str, opts: &Opts) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|_| format!("Failed to read {}", path))?;
+    let manifest: Manifest = toml::from_str(&content).map_err(|e| format!("Failed to parse {}: {}", path, e))?;
+    let diff = diff_manifest(store, &manifest);
+    print_diff(&diff, opts);
+    if opts.apply && !opts.dry_run { apply_diff(store, &diff, opts)?; }
+    Ok(())
+}
+
+// --- /selfupdate ---
+// The release manifest is TOML: a `version` plus one `[binaries.<os>-<arch>]` table per platform
+#[derive(Debug, Deserialize)]
+struct Release {
+    version: String,
+    #[serde(default)]
+    binaries: BTreeMap<String, ReleaseBinary>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseBinary {
+    url: String,
+    sha256: String,
+}
+
+fn self_update(config: &Config, opts: &Opts) -> Result<(), String> {
+    let url = config.release_url.as_deref().ok_or("No release_url set in config.toml")?;
+    let manifest = http_get(url, None, config, opts)?.text().map_err(|e| net_error(url, &e, config, opts))?;
+    let release: Release = toml::from_str(&manifest).map_err(|e| format!("Invalid release manifest: {}", e))?;
+
+    println!("Current version:   {}", BPM_VERSION);
+    println!("Available version: {}", release.version);
+    if compare_versions(&release.version, BPM_VERSION) != Ordering::Greater {
+        println!("bpm is already up to date.");
+        return Ok(());
+    }
+
+    let platform = format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH);
+    let binary = release.binaries.get(&platform).ok_or(format!("No release binary for {}", platform))?;
+    if !confirm(&format!("Update bpm to {}?", release.version), opts) { println!("Cancelled."); return Ok(()); }
+
+    let data = download(&binary.url, None, config, opts)?;
+    if sha256_bytes(&data) != binary.sha256.to_lowercase() {
+        return Err("Checksum mismatch for the downloaded bpm binary, nothing replaced".into());
+    }
+    replace_exe(&data)?;
+    println!("Updated bpm to {}", release.version);
+    Ok(())
+}
+
+// A running executable can be renamed (even on Windows) but not overwritten,
+// so move it aside and put the new one in its place
+fn replace_exe(data: &[u8]) -> Result<(), String> {
+    let exe = std::env::current_exe().map_err(|e| format!("Cannot locate the bpm executable: {}", e))?;
+    let new = exe.with_extension("new");
+    let old = exe.with_extension("old");
+
+    fs::write(&new, data).map_err(|e| format!("Failed to write {}: {}", new.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&new, fs::Permissions::from_mode(0o755)).map_err(|e| e.to_string())?;
+    }
+    let _ = fs::remove_file(&old);
+    fs::rename(&exe, &old).map_err(|e| format!("Failed to move {} aside: {}", exe.display(), e))?;
+    if let Err(e) = fs::rename(&new, &exe) {
+        let _ = fs::rename(&old, &exe);
+        return Err(format!("Failed to replace {}: {}", exe.display(), e));
+    }
+    Ok(())
+}
+
+// The installed packages /l shows: all of them, or only the --explicit or --deps ones
+fn listed_packages(store: &Store, opts: &Opts) -> Result<InstalledDb, String> {
+    if opts.explicit_only && opts.deps_only { return Err("--explicit and --deps exclude each other.".to_string()); }
+    let mut db = store.installed.load();
+    if opts.explicit_only || opts.deps_only { db.retain(|_, pkg| pkg.explicit == opts.explicit_only); }
+    Ok(db)
+}
+
+// List installed packages
+fn list_installed(store: &Store, opts: &Opts) {
+    let db = match listed_packages(store, opts) {
+        Ok(db) => db,
+        Err(e) => { println!("{}", e); return; }
+    };
+    if opts.json {
+        print_json_as_is(&db);
+        return;
+    }
+    if let Some(template) = &opts.format {
+        // Checked up front so a bad template fails even with nothing installed
+        let blank = FORMAT_FIELDS.iter().map(|&key| (key, String::new())).collect();
+        if let Err(e) = render_template(template, &blank) { println!("{} {}", paint("Error:", RED), e); std::process::exit(1); }
+        for (name, pkg) in &db {
+            match render_template(template, &format_fields(name, pkg)) {
+                Ok(line) => println!("{}", line),
+                Err(e) => { println!("{} {}", paint("Error:", RED), e); std::process::exit(1); }
+            }
+        }
+        return;
+    }
+    if opts.tree && !db.is_empty() { print_installed_tree(&db); return; }
+    if db.is_empty() {
+        println!("{}", if opts.explicit_only || opts.deps_only { "No matching packages installed." } else { "No packages installed." });
+    } else {
+        println!("Installed packages:");
+        let now = now_secs();
+        for (name, pkg) in db {
+            // Exact values with -v; entries from before sizes and times were recorded have neither
+            let (size, when) = if opts.verbose {
+                (pkg.size.map(|b| format!("{} bytes", b)), pkg.installed_at.map(|t| format!("installed at {}", t)))
+            } else {
+                (pkg.size.map(human_size), pkg.installed_at.map(|t| format!("installed {}", time_ago(now.saturating_sub(t)))))
+            };
+            let details: Vec<String> = [size, when].into_iter().flatten().collect();
+            if details.is_empty() { println!("{} ({}): {:?}", name, pkg.version, pkg.binaries); }
+            else { println!("{} ({}): {:?}, {}", name, pkg.version, pkg.binaries, details.join(", ")); }
+        }
+    }
+}
+
+// The placeholders /l --format knows; missing values render as empty
+const FORMAT_FIELDS: &[&str] = &["name", "version", "repo", "binaries", "size", "installed_at", "explicit", "source"];
+
+fn format_fields(name: &str, pkg: &InstalledPackage) -> BTreeMap<&'static str, String> {
+    BTreeMap::from([
+        ("name", name.to_string()),
+        ("version", pkg.version.clone()),
+        ("repo", pkg.repo.clone()),
+        ("binaries", pkg.binaries.join(" ")),
+        ("size", pkg.size.map(|b| b.to_string()).unwrap_or_default()),
+        ("installed_at", pkg.installed_at.map(|t| t.to_string()).unwrap_or_default()),
+        ("explicit", pkg.explicit.to_string()),
+        ("source", pkg.source_url.clone().unwrap_or_default()),
+    ])
+}
+
+// `{field}` is replaced by its value and `{{` / `}}` are literal braces
+fn render_template(template: &str, fields: &BTreeMap<&str, String>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => { chars.next(); out.push('{'); }
+            '}' if chars.peek() == Some(&'}') => { chars.next(); out.push('}'); }
+            '{' => {
+                let mut key = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => key.push(c),
+                        None => return Err(format!("Unclosed {{ in --format {:?}", template)),
+                    }
+                }
+                let value = fields.get(key.as_str()).ok_or_else(|| {
+                    format!("Unknown placeholder {{{}}} in --format; known: {}", key, FORMAT_FIELDS.join(", "))
+                })?;
+                out.push_str(value);
+            }
+            _ => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+// /l --all: the user root, then the system root
+fn list_all_roots(opts: &Opts) {
+    let roots = [("user", Store::open(false)), ("system", Store::open(true))];
+    if opts.json {
+        let all: BTreeMap<&str, InstalledDb> = roots.iter().map(|(label, store)| (*label, store.installed.load())).collect();
+        print_json(&all);
+        return;
+    }
+    for (i, (label, store)) in roots.iter().enumerate() {
+        if i > 0 { println!(); }
+        println!("[{}] {}", label, store.root.display());
+        list_installed(store, opts);
+    }
+}
+
+// /l --tree: a forest rooted at the explicit packages, children from the recorded dependencies.
+// A package already shown is marked (*) instead of being expanded again, and a dependency that was
+// assumed installed at install time is a leaf marked (external).
+fn print_installed_tree(db: &InstalledDb) {
+    for line in installed_tree(db) { println!("{}", line); }
+}
+
+fn installed_tree(db: &InstalledDb) -> Vec<String> {
+    fn node(db: &InstalledDb, name: &str, prefix: &str, last: bool, root: bool, seen: &mut BTreeSet<String>, out: &mut Vec<String>) {
+        let pkg = &db[name];
+        let branch = if root { "" } else if last { "└── " } else { "├── " };
+        let shared = !seen.insert(name.to_string());
+        out.push(format!("{}{}{} {}{}", prefix, branch, name, pkg.version, if shared { " (*)" } else { "" }));
+        if shared { return; }
+        let children: Vec<&String> = pkg.dependencies.iter()
+            .filter(|dep| !dep.contains(':') || db.contains_key(dep.split_once(':').map_or(dep.as_str(), |(name, _)| name)))
+            .collect();
+        let prefix = if root { String::new() } else { format!("{}{}", prefix, if last { "    " } else { "│   " }) };
+        for (i, child) in children.iter().enumerate() {
+            let last = i + 1 == children.len();
+            match child.split_once(':') {
+                Some((name, _)) => node(db, name, &prefix, last, false, seen, out),
+                None => out.push(format!("{}{}{} (external)", prefix, if last { "└── " } else { "├── " }, child)),
+            }
+        }
+    }
+    let (mut seen, mut out) = (BTreeSet::new(), Vec::new());
+    for (name, _) in db.iter().filter(|(_, pkg)| pkg.explicit) { node(db, name, "", true, true, &mut seen, &mut out); }
+    // Then whatever no explicit package reaches, tops of those chains first
+    let depended: BTreeSet<&str> = db.values().flat_map(|pkg| &pkg.dependencies)
+        .map(|dep| dep.split_once(':').map_or(dep.as_str(), |(name, _)| name))
+        .collect();
+    for name in db.keys().filter(|name| !depended.contains(name.as_str())) {
+        if !seen.contains(name) { node(db, name, "", true, true, &mut seen, &mut out); }
+    }
+    for name in db.keys() { if !seen.contains(name) { node(db, name, "", true, true, &mut seen, &mut out); } }
+    out
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 { return format!("{} B", bytes); }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // 1023.95 and up would print as 1024.0 of the smaller unit
+    while value >= 1023.95 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn time_ago(secs: u64) -> String {
+    let (n, unit) = match secs {
+        0..60 => return "just now".to_string(),
+        60..3600 => (secs / 60, "minute"),
+        3600..86400 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+// Report a failed command and exit non-zero, so scripts can tell
+fn exit_on_error(result: Result<(), String>) {
+    if let Err(e) = result {
+        print_warnings();
+        emit("error", serde_json::json!({ "message": e }));
+        if json_output() { eprintln!("{} {}", paint("Error:", RED), e); } else { println!("{} {}", paint("Error:", RED), e); }
+        std::process::exit(1);
+    }
+}
+
+// --- /profile ---
+// A profile is profiles/<name>/ in the root, holding its own installed DB, bins, holds and
+// packages.db. Repos and config.toml are the root's. "default" is the root itself.
+const DEFAULT_PROFILE: &str = "default";
+
+fn profile_names(store: &Store) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(store.root.join("profiles")).into_iter().flatten().flatten()
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+fn profile_command(store: &Store, active: Option<&str>, args: &[String], opts: &Opts) -> Result<(), String> {
+    let exists = |name: &str| name == DEFAULT_PROFILE || store.profile_dir(name).is_dir();
+    // Every name ends up joined under profiles/, so `..` and the like must never get that far
+    if let Some(name) = args.get(1) { check_name("profile", name)?; }
+    match (args.first().map(String::as_str), args.get(1)) {
+        (Some("list"), None) => {
+            for name in std::iter::once(DEFAULT_PROFILE.to_string()).chain(profile_names(store)) {
+                let mark = if active.unwrap_or(DEFAULT_PROFILE) == name { "*" } else { " " };
+                println!("{} {}", mark, name);
+            }
+        }
+        (Some("create"), Some(name)) => {
+            if exists(name) { return Err(format!("Profile {} already exists", name)); }
+            fs::create_dir_all(store.profile_dir(name).join("bins")).map_err(|e| format!("Failed to create profile {}: {}", name, e))?;
+            println!("Created profile {}. Switch to it with /profile use {}", name, name);
+        }
+        (Some("remove"), Some(name)) => {
+            if name == DEFAULT_PROFILE { return Err("The default profile can't be removed".to_string()); }
+            if !exists(name) { return Err(format!("Profile {} not found", name)); }
+            if active == Some(name.as_str()) { return Err(format!("Profile {} is in use; switch to another first", name)); }
+            let mut doomed = Store::new(&store.root);
+            doomed.use_profile(name);
+            let count = doomed.installed.load().len();
+            if !confirm(&format!("Remove profile {} and its {} installed packages?", name, count), opts) { return Ok(()); }
+            fs::remove_dir_all(store.profile_dir(name)).map_err(|e| format!("Failed to remove profile {}: {}", name, e))?;
+            println!("Removed profile {}.", name);
+        }
+        (Some("use"), Some(name)) => {
+            if !exists(name) { return Err(format!("Profile {} not found", name)); }
+            write_config_key(store, "profile", Some(toml::Value::String(name.clone())).filter(|_| name != DEFAULT_PROFILE))?;
+            println!("Now using profile {}.", name);
+            if opts.profile.is_some() { println!("{} --profile or BPM_PROFILE is set and still wins for this shell.", paint("⚠", YELLOW)); }
+        }
+        _ => println!("Usage: bpm /profile list | create <name> | remove <name> | use <name>"),
+    }
+    Ok(())
+}
+
+// --- Frozen stores ---
+// The command line, if it asks for a change to the store. Dry runs and listings don't count.
+// `/config set frozen ...` is always allowed so a frozen store can be thawed.
+fn mutating_command(args: &[String], opts: &Opts) -> Option<String> {
+    let cmd = args.get(1)?.as_str();
+    let sub = args.get(2).map(String::as_str);
+    let mutating = match cmd {
+        "/i" => !opts.dry_run && !opts.check,
+        "/r" | "/selfupdate" => true,
+        "/u" | "/rollback" | "/import" | "/upgrade" | "/clean" | "/gc" => !opts.dry_run,
+        "/diff" => opts.apply && !opts.dry_run,
+        "/doctor" => opts.fix,
+        "/verify" => opts.repair && !opts.dry_run,
+        "/hold" => sub.is_some() && !args.iter().any(|arg| arg == "--list"),
+        "/unhold" => sub.is_some(),
+        "/cache" => sub == Some("clear") && !opts.dry_run,
+        "/config" => sub == Some("set") && args.get(3).is_none_or(|key| key != "frozen"),
+        "/profile" => matches!(sub, Some("create" | "remove" | "use")),
+        "/repo" => match sub {
+            Some("refresh") => !opts.dry_run,
+            Some("import" | "scan") => !opts.dry_run,
+            Some("default") => args.get(3).is_some(),
+            Some("add" | "remove" | "enable" | "disable" | "auth" | "set-priority" | "edit" | "clone") => true,
+            _ => false,
+        },
+        _ => false,
+    };
+    mutating.then(|| match sub {
+        Some(sub) if matches!(cmd, "/repo" | "/cache" | "/config" | "/profile") => format!("{} {}", cmd, sub),
+        _ => cmd.to_string(),
+    })
+}
+
+// Commands that write to the store find out up front, not from a panic halfway through
+fn check_writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(".bpm-write-test");
+    fs::create_dir_all(dir).and_then(|_| fs::write(&probe, b"")).and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| format!("Store directory is not writable: {} ({}). Use --store <dir> or BPM_STORE to work on a store elsewhere.", dir.display(), e))
+}
+
+fn check_store_writable(store: &Store, args: &[String], opts: &Opts) -> Result<(), String> {
+    check_writable(&store.state_dir)?;
+    match store.config.prefix(opts) {
+        Some(prefix) if args[1] == "/i" || args[1] == "/u" || args[1] == "/upgrade" => check_writable(Path::new(prefix))?,
+        _ => check_writable(&store.bins_dir())?,
+    }
+    if args[1] == "/repo" { check_writable(&store.repos_root)?; }
+    Ok(())
+}
+
+// Like exit_on_error, but on stderr
+fn exit_on_error_stderr(result: Result<(), String>) {
+    if let Err(e) = result {
+        print_warnings();
+        emit("error", serde_json::json!({ "message": e }));
+        eprintln!("{} {}", paint("Error:", RED), e);
+        std::process::exit(1);
+    }
+}
+
+// CLI entry
+fn main() {
+    let (args, opts) = match parse_opts(std::env::args().collect()) {
+        Ok(parsed) => parsed,
+        Err(e) => { exit_on_error(Err(e)); return; }
+    };
+    init_color(&opts);
+    init_output(&opts);
+    exit_on_error(init_events(&opts));
+    let mut store = Store::open_for(&opts);
+    if store.config.frozen(&opts) && let Some(cmd) = mutating_command(&args, &opts) {
+        exit_on_error(Err(format!("Store is frozen: {} would change it", cmd)));
+    }
+    if args.get(1).is_some_and(|arg| arg == "/config") {
+        exit_on_error(config_command(&store, &args[2..], &opts));
+        return;
+    }
+    let profile = opts.profile.clone().or(store.config.profile.clone()).filter(|name| name != DEFAULT_PROFILE);
+    if let Some(name) = &profile { exit_on_error(check_name("profile", name).map(|_| ())); }
+    if args.get(1).is_some_and(|arg| arg == "/profile") {
+        exit_on_error(profile_command(&store, profile.as_deref(), &args[2..], &opts));
+        return;
+    }
+    if let Some(name) = &profile {
+        if !store.profile_dir(name).is_dir() {
+            exit_on_error(Err(format!("Profile {} does not exist; create it with /profile create {}", name, name)));
+        }
+        store.use_profile(name);
+    }
+    if mutating_command(&args, &opts).is_some() { exit_on_error(check_store_writable(&store, &args, &opts)); }
+
+    // /doctor reports these itself, with the fixes
+    if args.get(1).is_none_or(|cmd| cmd != "/doctor") {
+        let partial = partial_installs(&store.installed.load());
+        if !partial.is_empty() {
+            let names: Vec<&str> = partial.iter().map(|(name, _)| name.as_str()).collect();
+            eprintln!("{} Partially installed: {}. Run bpm /doctor --fix to complete or roll them back.", paint("⚠", YELLOW), names.join(", "));
+        }
+    }
+
+    if args.len() < 2 {
+        println!("Usage: bpm /i|/r|/u|/upgrade|/outdated|/verify|/repo|/l <repo:package[:version]> | /v, /h = help");
+        return;
+    }
+
+    run(&store, &args, &opts);
+    finish_output();
+}
+
+fn run(store: &Store, args: &[String], opts: &Opts) {
+    match args[1].as_str() {
+        "/i" => {
+            if let Some(url) = &opts.url { exit_on_error(install_url(store, url, opts.name.as_deref(), opts)); return; }
+            if let Some(archive) = &opts.archive { exit_on_error(install_archive(store, archive, opts)); return; }
+            if let Some(lock) = &opts.locked { exit_on_error(install_locked(store, lock, opts)); return; }
+            if args.len() < 3 { println!("Usage: bpm /i <[repo:]package[:version]>... | --url <url> [--name <name>] [--sha256 <hash>]"); return; }
+            install_all(store, &args[2..], opts);
+        }
+        "/r" => {
+            if args.len() < 3 { println!("Usage: bpm /r <package[:version]>"); return; }
+            let (package, version) = match args[2].split_once(':') {
+                Some((package, version)) => (package, Some(version)),
+                None => (args[2].as_str(), None),
+            };
+            remove_matching(store, package, version, opts);
+        }
+        "/u" => {
+            if args.len() < 3 { println!("Usage: bpm /u <[repo:]package> [--switch-repo <repo>] [--refresh]"); return; }
+            let (repo, package, _) = match parse_pkg_arg(&args[2]) {
+                Ok(parsed) => parsed,
+                Err(e) => { exit_on_error(Err(e)); return; }
+            };
+            if opts.refresh {
+                let named = opts.switch_repo.clone().or(repo.clone())
+                    .or_else(|| store.installed.get(&package).map(|installed| installed.repo));
+                exit_on_error(refresh_before_resolve(store, named.map(|repo| vec![repo]), opts));
+            }
+            let repo = match update_repo(store, &package, repo, opts) {
+                Ok(repo) => repo,
+                Err(e) => { exit_on_error(Err(e)); return; }
+            };
+            if repo.as_ref().is_some_and(|repo| !check_repo_enabled(store, repo, opts)) { return; }
+            let Some(repo) = repo.or_else(|| select_repo(store, &package, opts)) else { return; };
+            exit_on_error(update_package(store, &repo, &package, opts));
+        }
+        "/deps" => {
+            if args.len() < 3 { println!("Usage: bpm /deps <[repo:]package[:version]> [--json]"); return; }
+            let (repo, package, version) = match parse_pkg_arg(&args[2]) {
+                Ok(parsed) => parsed,
+                Err(e) => { exit_on_error(Err(e)); return; }
+            };
+            let Some(repo) = repo.or_else(|| select_repo(store, &package, opts)) else { return; };
+            exit_on_error(list_deps(store, &repo, &package, version.as_deref(), opts));
+        }
+        "/freeze" => {
+            if args.len() < 3 { println!("Usage: bpm /freeze <[repo:]package[:version]> [--out <lockfile>]"); return; }
+            let (repo, package, version) = match parse_pkg_arg(&args[2]) {
+                Ok(parsed) => parsed,
+                Err(e) => { exit_on_error(Err(e)); return; }
+            };
+            let Some(repo) = repo.or_else(|| select_repo(store, &package, opts)) else { return; };
+            exit_on_error(freeze(store, &repo, &package, version.as_deref(), opts));
+        }
+        "/upgrade" => upgrade_all(store, opts),
+        "/clean" => clean_unneeded(store, opts),
+        "/rollback" => {
+            if args.len() < 3 { println!("Usage: bpm /rollback <package> [--force]"); return; }
+            exit_on_error(rollback_package(store, &args[2], opts));
+        }
+        "/outdated" => list_outdated(store),
+        "/search" => {
+            if args.len() < 3 { println!("Usage: bpm /search <query> [--regex]"); return; }
+            exit_on_error(search(store, &args[2], opts));
+        }
+        "/versions" => {
+            if args.len() < 3 { println!("Usage: bpm /versions <[repo:]package> [--since <version>] [--limit <n>] [--json]"); return; }
+            exit_on_error(list_versions(store, &args[2], opts));
+        }
+        "/export" => match &opts.bundle {
+            Some(dir) => exit_on_error(export_bundle(store, dir, opts)),
+            None => exit_on_error(export_manifest(store, args.get(2).map(String::as_str))),
+        },
+        "/import" => {
+            if args.len() < 3 { println!("Usage: bpm /import <bundle dir>"); return; }
+            exit_on_error(import_bundle(store, &args[2], opts));
+        }
+        "/diff" => {
+            if args.len() < 3 { println!("Usage: bpm /diff <manifest> [--json] [--apply]"); return; }
+            exit_on_error(diff_command(store, &args[2], opts));
+        }
+        "/files" => {
+            if args.len() < 3 { println!("Usage: bpm /files <package> [--json]"); return; }
+            exit_on_error(list_files(store, &args[2], opts));
+        }
+        "/owns" => {
+            if args.len() < 3 { println!("Usage: bpm /owns <path>"); return; }
+            exit_on_error(owns(store, &args[2]));
+        }
+        "/why" => {
+            if args.len() < 3 { println!("Usage: bpm /why <package>"); return; }
+            exit_on_error(why(store, &args[2]));
+        }
+        "/which" => {
+            if args.len() < 3 { eprintln!("Usage: bpm /which <package> [--all]"); std::process::exit(1); }
+            exit_on_error_stderr(which(store, &args[2], opts));
+        }
+        "/info" => {
+            if args.len() < 3 { println!("Usage: bpm /info <package> [-v] [--json] [--deps-only [--resolved]]"); return; }
+            if opts.info_deps { exit_on_error(info_deps(store, &args[2], opts)); return; }
+            exit_on_error(info(store, &args[2], opts));
+        }
+        "/doctor" => exit_on_error(doctor(store, opts)),
+        "/gc" => exit_on_error(gc(store, opts)),
+        "/cache" => exit_on_error(cache_command(store, &args[2..], opts)),
+        "/hold" => hold_command(store, &args[2..], true),
+        "/unhold" => hold_command(store, &args[2..], false),
+        "/verify" => exit_on_error(verify_installed(store, args.get(2).map(String::as_str), opts)),
+        "/repo" => repo_command(store, &args[2..], opts),
+        "/l" if opts.all => list_all_roots(opts),
+        "/l" => list_installed(store, opts),
+        "/selfupdate" => exit_on_error(self_update(&store.config, opts)),
+        "/v" => println!("bpm ver: {}", BPM_VERSION),
+        "/h" => {
+            println!("Blur Package Manager | Help Menu");
+            println!("/i          = install");
+            println!("/r          = remove");
+            println!("/u          = update");
+            println!("/upgrade    = upgrade all installed packages (--exclude <package> to skip one, --repo <name> for one repo only)");
+            println!("/clean      = remove dependencies nothing installed needs any more (--exclude <package> to keep one)");
+            println!("/rollback   = reinstall the version a package had before its current one");
+            println!("/deps       = list every dependency a package would pull in");
+            println!("/freeze     = write a lockfile pinning a package's whole closure (/i --locked installs it)");
+            println!("/outdated   = list packages with newer versions and their release notes");
+            println!("/search     = find packages by name or description (--regex for a pattern)");
+            println!("/versions   = list the versions a repo has of a package (--since <version>, --limit <n>)");
+            println!("/export     = write the installed packages to a manifest (--bundle <dir> to copy the binaries too)");
+            println!("/import     = install the packages of a bundle made by /export --bundle, offline");
+            println!("/diff       = compare a manifest with what is installed (--apply to make them match)");
+            println!("/files      = list the files a package installed");
+            println!("/owns       = show which package installed a file");
+            println!("/why        = show which installed packages depend on a package, and through what");
+            println!("/which      = print the path of a package's main binary (--all for every one)");
+            println!("/info       = show an installed package (-v for checksums and the source index)");
+            println!("/doctor     = check the whole store for consistency (--fix to repair)");
+            println!("/cache      = show the size of the download cache, or clear it (--indexes/--binaries)");
+            println!("/gc         = drop binaries of removed packages from packages.db (--dry-run to preview)");
+            println!("/hold       = keep packages at their installed version (--list to show held ones)");
+            println!("/unhold     = let held packages update again");
+            println!("/verify     = check installed binaries against their checksums (--deep to rehash all)");
+            println!("/repo       = list, add, remove, refresh, enable or disable repos");
+            println!("/l          = list installed packages");
+            println!("/config     = list, get or set config.toml values");
+            println!("/selfupdate = update bpm itself from the configured release_url");
+            println!("/v          = shows version");
+            println!("/h          = shows this menu");
+        }
+        _ => println!("Unknown command. Use /i, /r, /u, /upgrade, /clean, /outdated, /deps, /files, /owns, /why, /hold, /unhold, /verify, /repo, /l, /selfupdate"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A store rooted in a fresh temporary directory, removed when the guard drops
+    fn temp_store() -> (tempfile::TempDir, Store) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::new(dir.path());
+        (dir, store)
+    }
+
+    // A local repo <name>/ whose index is `index` with {src} replaced by a directory holding `files`
+    fn write_repo(store: &Store, name: &str, index: &str, files: &[(&str, &str)]) {
+        let src = store.root.join(name).join("src");
+        fs::create_dir_all(&src).unwrap();
+        for (file, content) in files { fs::write(src.join(file), content).unwrap(); }
+        let index = index.replace("{src}", &src.to_string_lossy().replace('\\', "/"));
+        fs::write(store.root.join(name).join("packages.mri"), index).unwrap();
+    }
+
+    // Indexes held in memory, no store behind them
+    struct MemRepos(BTreeMap<String, Repo>);
+
+    impl MemRepos {
+        fn new(repos: &[(&str, &str)]) -> Self {
+            MemRepos(repos.iter().map(|(name, index)| (name.to_string(), toml::from_str(index).unwrap())).collect())
+        }
+    }
+
+    impl RepoProvider for MemRepos {
+        fn repo(&self, name: &str) -> Result<Repo, String> {
+            self.0.get(name).cloned().ok_or_else(|| format!("Repo {} not found", name))
+        }
+        fn index_sha256(&self, _name: &str) -> Option<String> { None }
+        // Nothing on disk: the path and binaries are taken as written
+        fn package_files(&self, _key: &str, pkg: &PackageVersion) -> Result<PackageFiles, String> {
+            Ok(PackageFiles { path: pkg.path.clone(), binaries: pkg.binaries.clone(), size: 0 })
+        }
+    }
+
+    // Keeps everything the resolver reports
+    #[derive(Default)]
+    struct Recorded {
+        planned: Vec<String>,
+        warnings: Vec<(&'static str, String)>,
+        trace: Vec<String>,
+    }
+
+    impl ResolveEvents for Recorded {
+        fn planned(&mut self, step: &PlanStep, count: usize) {
+            self.planned.push(format!("{}:{}", step.package, step.version));
+            assert_eq!(self.planned.len(), count);
+        }
+        fn warning(&mut self, kind: &'static str, message: String) { self.warnings.push((kind, message)); }
+        fn trace(&mut self, line: String) { self.trace.push(line); }
+    }
+
+    // packages.mri text for (package, version, dependencies) entries, each with one binary named after it
+    fn index(entries: &[(&str, &str, &[&str])]) -> String {
+        entries.iter().map(|(name, version, deps)| format!(
+            "[{}.\"{}\"]\npath = \"src\"\nbinaries = [\"{}\"]\ndependencies = {:?}\n", name, version, name, deps)).collect()
+    }
+
+    // A local repo of index() entries. All versions share one src directory, so each binary holds
+    // "<name> <version>" of the last version listed.
+    fn write_packages(store: &Store, repo: &str, entries: &[(&str, &str, &[&str])]) {
+        let files: Vec<(&str, String)> = entries.iter().map(|(name, version, _)| (*name, format!("{} {}", name, version))).collect();
+        let files: Vec<(&str, &str)> = files.iter().map(|(name, content)| (*name, content.as_str())).collect();
+        write_repo(store, repo, &index(entries).replace("path = \"src\"", "path = \"{src}\""), &files);
+    }
+
+    // An HTTP server on a free local port, answering every request with handler(request head)
+    // as (status, body). Returns its base URL; it lives until the test process exits.
+    fn mock_server(handler: impl Fn(&str) -> (u16, Vec<u8>) + Send + Sync + 'static) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handler = std::sync::Arc::new(handler);
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    let mut head = Vec::new();
+                    let mut byte = [0u8; 1];
+                    while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).unwrap_or(0) == 1 { head.push(byte[0]); }
+                    let (status, body) = handler(&String::from_utf8_lossy(&head));
+                    let _ = write!(stream, "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len());
+                    let _ = stream.write_all(&body);
+                });
+            }
+        });
+        url
+    }
+
+    // A remote repo `web` served by a mock server, answering with each of `indexes` in turn (the last one repeats)
+    fn remote_repo(store: &Store, indexes: Vec<String>) -> String {
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        let url = mock_server(move |_| {
+            let i = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst).min(indexes.len() - 1);
+            (200, indexes[i].clone().into_bytes())
+        });
+        let mut registry = load_registry(store);
+        registry.insert("web".into(), RepoEntry { url: Some(format!("{}/packages.mri", url)), ..RepoEntry::default() });
+        save_registry(store, &registry);
+        url
+    }
+
+    fn installed(repo: &str, version: &str) -> InstalledPackage {
+        InstalledPackage { repo: repo.into(), version: version.into(), explicit: true, ..InstalledPackage::default() }
+    }
+
+    fn planned(plan: &Plan) -> Vec<String> {
+        plan.install.iter().map(|step| format!("{}:{}:{}", step.repo, step.package, step.version)).collect()
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn bare_names_pick_the_highest_priority_repo_without_asking() {
+        let (_dir, store) = temp_store();
+        for repo in ["alpha", "beta"] { write_repo(&store, repo, &index(&[("tool", "1.0", &[])]), &[]); }
+        write_repo(&store, "gamma", &index(&[("other", "1.0", &[])]), &[]);
+        let opts = Opts { yes: true, ..Opts::default() };
+        // Equal priorities fall back to name order
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("alpha"));
+        let mut registry = load_registry(&store);
+        registry.insert("beta".into(), RepoEntry { priority: Some(5), ..RepoEntry::default() });
+        save_registry(&store, &registry);
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("beta"));
+        assert_eq!(select_repo(&store, "other", &opts).as_deref(), Some("gamma"));
+        assert_eq!(select_repo(&store, "missing", &opts), None);
+    }
+
+    #[test]
+    fn upgrade_leaves_excluded_packages_alone() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("b", "1.0", &[])]);
+        for name in ["a", "b"] { install_package(&store, "main", name, None, &Opts::default()).unwrap(); }
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("a", "2.0", &[]), ("b", "1.0", &[]), ("b", "2.0", &[])]);
+        upgrade_all(&store, &Opts { exclude: vec!["b".into()], ..Opts::default() });
+        let db = store.installed.load();
+        assert_eq!(db["a"].version, "2.0");
+        assert_eq!(db["b"].version, "1.0");
+    }
+
+    #[test]
+    fn piped_output_gets_no_bars_or_escape_codes() {
+        // Test output is captured, so stdout is not a terminal here
+        assert!(!io::stdout().is_terminal());
+        let config = Config::default();
+        for opts in [Opts::default(), Opts { quiet: true, ..Opts::default() }, Opts { json: true, ..Opts::default() }] {
+            assert!(progress_hidden(&opts));
+            assert!(progress_bar(10, "Copying", &config, &opts).is_hidden());
+            assert!(spinner("Resolving", &opts).is_hidden());
+        }
+        assert!(!color_enabled(None, None, None, false));
+        assert_eq!(paint("Error:", RED), "Error:");
+    }
+
+    #[test]
+    fn quiet_db_entries_match_calcbits() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..40).collect();
+        let theirs = dir.path().join("theirs.db");
+        save_to_db(&theirs.to_string_lossy(), "tool", &data, false).unwrap();
+        let mut ours = Vec::new();
+        write_db_entry(&mut ours, "tool", &data).unwrap();
+        assert_eq!(String::from_utf8(ours).unwrap(), fs::read_to_string(theirs).unwrap());
+    }
+
+    #[test]
+    fn plan_json_lists_steps_dependencies_first() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib"]), ("lib", "1.0", &[])]);
+        let plan = resolve_plan(&store, "main", "app", None, &Opts::default()).unwrap();
+        assert_eq!(serde_json::to_value(&plan).unwrap(), serde_json::json!({
+            "install": [
+                { "package": "lib", "version": "1.0", "repo": "main", "explicit": false, "size": 7 },
+                { "package": "app", "version": "1.0", "repo": "main", "explicit": true, "size": 7 },
+            ],
+            "external": [],
+            "conflicts": [],
+            "total_size": 14,
+        }));
+    }
+
+    #[test]
+    fn removal_by_version_only_matches_the_installed_version() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[])]);
+        install_package(&store, "main", "tool", None, &Opts { quiet: true, ..Opts::default() }).unwrap();
+        let bin = store.bins_dir().join("tool");
+        let installed = || store.installed.load();
+        remove_package(&store, "tool", Some("2.0"));
+        assert!(installed().contains_key("tool") && bin.exists());
+        remove_package(&store, "tool", Some("1.0"));
+        assert!(!installed().contains_key("tool"));
+        assert!(!bin.exists());
+    }
+
+    #[test]
+    fn verify_rehashes_only_touched_binaries() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("b", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["a", "b"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        verify_installed(&store, None, &opts).unwrap();
+        let (a, b) = (store.bins_dir().join("a"), store.bins_dir().join("b"));
+        let cached = |name: &str, bin: &Path| store.installed.get(name).unwrap().verified_mtimes[&bin.to_string_lossy().to_string()];
+        // b is changed behind the cache's back, keeping its mtime, so it is trusted without a rehash
+        let b_mtime = fs::metadata(&b).unwrap().modified().unwrap();
+        fs::write(&b, "tampered").unwrap();
+        fs::File::options().write(true).open(&b).unwrap().set_modified(b_mtime).unwrap();
+        // a is touched without changing its content, so it is rehashed and found intact
+        let later = fs::metadata(&a).unwrap().modified().unwrap() + Duration::from_secs(5);
+        fs::File::options().write(true).open(&a).unwrap().set_modified(later).unwrap();
+        let a_before = cached("a", &a);
+        verify_installed(&store, None, &opts).unwrap();
+        assert_ne!(cached("a", &a), a_before);
+        assert_eq!(cached("b", &b), mtime_of(&b).unwrap());
+        // --deep ignores the cache
+        assert!(verify_installed(&store, None, &Opts { deep: true, ..opts }).is_err());
+    }
+
+    #[test]
+    fn disabled_repos_are_skipped_for_bare_names() {
+        let (_dir, store) = temp_store();
+        for repo in ["alpha", "beta"] { write_repo(&store, repo, &index(&[("tool", "1.0", &[])]), &[]); }
+        let mut registry = load_registry(&store);
+        registry.insert("alpha".into(), RepoEntry { enabled: false, ..RepoEntry::default() });
+        save_registry(&store, &registry);
+        let opts = Opts { yes: true, ..Opts::default() };
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("beta"));
+        assert!(!check_repo_enabled(&store, "alpha", &opts));
+        assert!(check_repo_enabled(&store, "alpha", &Opts { force: true, ..opts }));
+    }
+
+    #[test]
+    fn installed_dependency_must_satisfy_version_and_repo() {
+        let repos = MemRepos::new(&[
+            ("main", &index(&[("tool", "1.0", &["lib:>=2.0"]), ("pinned", "1.0", &["other/lib"]), ("lib", "1.0", &[]), ("lib", "2.0", &[])])),
+            ("other", &index(&[("lib", "1.5", &[])])),
+        ]);
+        let opts = Opts::default();
+        let db = InstalledDb::from([("lib".to_string(), installed("main", "1.0"))]);
+        let plan = resolve(&repos, db.clone(), &BTreeMap::new(), "main", "tool", None, &Config::default(), &opts, &mut ()).unwrap();
+        assert_eq!(planned(&plan), ["main:lib:2.0", "main:tool:1.0"]);
+        let plan = resolve(&repos, db.clone(), &BTreeMap::new(), "main", "pinned", None, &Config::default(), &opts, &mut ()).unwrap();
+        assert_eq!(planned(&plan), ["other:lib:1.5", "main:pinned:1.0"]);
+        // An installed version that does satisfy the dependent is kept
+        let db = InstalledDb::from([("lib".to_string(), installed("main", "2.0"))]);
+        let plan = resolve(&repos, db, &BTreeMap::new(), "main", "tool", None, &Config::default(), &opts, &mut ()).unwrap();
+        assert_eq!(planned(&plan), ["main:tool:1.0"]);
+        assert_eq!(plan.install[0].dependencies, ["lib:2.0"]);
+    }
+
+    #[test]
+    fn resolution_plans_dependencies_first() {
+        let repos = MemRepos::new(&[
+            ("main", &index(&[("app", "1.0", &["cli", "lib"]), ("cli", "1.0", &["lib"]), ("lib", "1.0", &[]), ("lib", "2.0", &[])])),
+        ]);
+        let mut events = Recorded::default();
+        let plan = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "app", None, &Config::default(), &Opts::default(), &mut events).unwrap();
+        assert_eq!(planned(&plan), ["main:lib:2.0", "main:cli:1.0", "main:app:1.0"]);
+        assert_eq!(events.planned, ["lib:2.0", "cli:1.0", "app:1.0"]);
+        assert!(plan.conflicts.is_empty() && events.warnings.is_empty());
+        let err = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "nothing", None, &Config::default(), &Opts::default(), &mut events).unwrap_err();
+        assert_eq!(err, "Package nothing not found in repo main");
+        // A missing dependency fails the whole plan and names its dependent
+        let repos = MemRepos::new(&[("main", &index(&[("app", "1.0", &["gone"])]))]);
+        let err = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "app", None, &Config::default(), &Opts::default(), &mut ()).unwrap_err();
+        assert_eq!(err, "Package gone not found in repo main, required by app:1.0");
+        // Paths and globs are the provider's business; nothing is read from disk or the environment
+        let repos = MemRepos::new(&[("main", "[g.\"1.0\"]\npath = \"${BPM_TEST_UNSET}/x\"\nbinaries = [\"bin/*\"]\n")]);
+        let plan = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "g", None, &Config::default(), &Opts::default(), &mut ()).unwrap();
+        assert_eq!((plan.install[0].path.as_str(), plan.install[0].binaries.as_slice()), ("${BPM_TEST_UNSET}/x", &["bin/*".to_string()][..]));
+    }
+
+    #[test]
+    fn resolution_reports_version_conflicts() {
+        let repos = MemRepos::new(&[
+            ("main", &index(&[("app", "1.0", &["a", "b"]), ("a", "1.0", &["c:1.0"]), ("b", "1.0", &["c:2.0"]), ("c", "1.0", &[]), ("c", "2.0", &[])])),
+        ]);
+        let opts = Opts::default();
+        let plan = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "app", None, &Config::default(), &opts, &mut ()).unwrap();
+        assert_eq!(plan.conflicts.len(), 1, "{:?}", plan.conflicts);
+        let contest = &plan.contested[0];
+        assert_eq!((contest.package.as_str(), contest.planned.1.as_str(), contest.wanted.1.as_str()), ("c", "1.0", "2.0"));
+        // Forcing one side, as --interactive does, plans that version for every dependent
+        let overrides = BTreeMap::from([("c".to_string(), ("main".to_string(), "2.0".to_string()))]);
+        let plan = resolve(&repos, InstalledDb::new(), &overrides, "main", "app", None, &Config::default(), &opts, &mut ()).unwrap();
+        assert!(planned(&plan).contains(&"main:c:2.0".to_string()), "{:?}", planned(&plan));
+        assert!(!planned(&plan).contains(&"main:c:1.0".to_string()));
+    }
+
+    #[test]
+    fn dependency_specs_parse_every_combination() {
+        fn parsed(dep: &str) -> Result<(Option<&str>, &str, Option<&str>), String> {
+            parse_dep(dep).map(|spec| (spec.repo, spec.name, spec.version))
+        }
+        assert_eq!(parsed("lib"), Ok((None, "lib", None)));
+        assert_eq!(parsed("lib:1.2"), Ok((None, "lib", Some("1.2"))));
+        assert_eq!(parsed("lib:>=1.0"), Ok((None, "lib", Some(">=1.0"))));
+        assert_eq!(parsed("other/lib"), Ok((Some("other"), "lib", None)));
+        assert_eq!(parsed("other/lib:<2"), Ok((Some("other"), "lib", Some("<2"))));
+        for bad in ["", "lib:", "other/", "/lib", "a/b/c/lib", "lib:1:2"] {
+            let err = parse_dep(bad).map(|_| ()).unwrap_err();
+            assert!(err.starts_with("Ambiguous dependency"), "{:?}: {}", bad, err);
+        }
+    }
+
+    #[test]
+    fn dependencies_pinned_to_a_repo_come_from_it() {
+        let repos = MemRepos::new(&[
+            ("main", &index(&[("app", "1.0", &["other/lib", "lib:1.0"]), ("lib", "1.0", &[]), ("lib", "3.0", &[])])),
+            ("other", &index(&[("lib", "2.0", &[])])),
+            ("pinned", &index(&[("app", "1.0", &["other/lib:>=2.0"])])),
+        ]);
+        let plan = |repo| resolve(&repos, InstalledDb::new(), &BTreeMap::new(), repo, "app", None, &Config::default(), &Opts::default(), &mut ());
+        // other/lib:2.0 is planned first; lib:1.0 from main then conflicts with it
+        let first = plan("main").unwrap();
+        assert_eq!(planned(&first)[0], "other:lib:2.0");
+        assert_eq!(first.conflicts.len(), 1);
+        assert_eq!(planned(&plan("pinned").unwrap()), ["other:lib:2.0", "pinned:app:1.0"]);
+    }
+
+    #[test]
+    fn changelog_covers_only_the_versions_jumped_over() {
+        let repo: Repo = toml::from_str(concat!(
+            "[tool.\"1.0\"]\npath = \"src\"\nbinaries = [\"tool\"]\nchangelog = \"First\"\n",
+            "[tool.\"1.2\"]\npath = \"src\"\nbinaries = [\"tool\"]\nchangelog = \"Second\"\n",
+            "[tool.\"1.9\"]\npath = \"src\"\nbinaries = [\"tool\"]\n",
+            "[tool.\"1.10\"]\npath = \"src\"\nbinaries = [\"tool\"]\nrelease_notes = \"  Third \"\n",
+            "[tool.\"2.0\"]\npath = \"src\"\nbinaries = [\"tool\"]\nchangelog = \"Fourth\"\n",
+        )).unwrap();
+        let versions = &repo.packages["tool"];
+        assert_eq!(changelog_span(versions, "1.0", "1.10"), [("1.2", "Second"), ("1.10", "Third")]);
+        assert_eq!(changelog_span(versions, "1.0", "2.0").len(), 3);
+        assert!(changelog_span(versions, "2.0", "2.0").is_empty());
+    }
+
+    #[test]
+    fn assumed_installed_dependencies_are_not_planned() {
+        let repos = MemRepos::new(&[("main", &index(&[("app", "1.0", &["lib", "missing"]), ("lib", "1.0", &[])]))]);
+        let opts = Opts { assume_installed: vec!["missing".into()], ..Opts::default() };
+        let plan = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "app", None, &Config::default(), &opts, &mut ()).unwrap();
+        assert_eq!(planned(&plan), ["main:lib:1.0", "main:app:1.0"]);
+        assert_eq!(plan.external, ["missing"]);
+        // The config's list counts the same as the flag
+        let config = Config { assume_installed: vec!["lib".into(), "missing".into()], ..Config::default() };
+        let plan = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "app", None, &config, &Opts::default(), &mut ()).unwrap();
+        assert_eq!(planned(&plan), ["main:app:1.0"]);
+        assert_eq!(plan.external, ["lib", "missing"]);
+    }
+
+    #[test]
+    fn selfupdate_without_release_url_is_an_error() {
+        let err = self_update(&Config::default(), &Opts::default()).unwrap_err();
+        assert!(err.contains("release_url"), "{}", err);
+    }
+
+    #[test]
+    fn gzipped_indexes_load_like_plain_ones() {
+        let (_dir, store) = temp_store();
+        let text = index(&[("tool", "1.0", &["lib"]), ("lib", "2.0", &[])]);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        fs::create_dir_all(store.root.join("main")).unwrap();
+        fs::write(store.root.join("main").join("packages.mri.gz"), encoder.finish().unwrap()).unwrap();
+        // Only the compressed index exists, so that is the one used
+        assert!(store.repo_index_path("main").ends_with("packages.mri.gz"));
+        let repo = load_repo(&store, "main").unwrap();
+        assert_eq!(repo.packages["tool"]["1.0"].dependencies, ["lib"]);
+        assert!(repo.packages["lib"].contains_key("2.0"));
+        let broken = store.root.join("broken.gz");
+        fs::write(&broken, [0x1f, 0x8b, 0, 1]).unwrap();
+        assert!(load_index(&broken).unwrap_err().starts_with("Failed to decompress"));
+    }
+
+    #[test]
+    fn no_progress_comes_from_the_flag_or_the_environment() {
+        let (_, opts) = parse_opts(args(&["bpm", "/i", "tool", "--no-progress"])).unwrap();
+        assert!(opts.no_progress && progress_hidden(&opts));
+        assert!(progress_bar(1, "Copying", &Config::default(), &opts).is_hidden());
+        // Only this test sets the variable, and nothing else it could affect depends on it
+        unsafe { std::env::set_var("BPM_NO_PROGRESS", "1"); }
+        let (rest, opts) = parse_opts(args(&["bpm", "/i", "tool"])).unwrap();
+        unsafe { std::env::remove_var("BPM_NO_PROGRESS"); }
+        assert!(opts.no_progress);
+        assert_eq!(rest, ["bpm", "/i", "tool"]);
+    }
+
+    #[test]
+    fn store_comes_from_the_flag_then_the_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let elsewhere = dir.path().join("store").to_string_lossy().into_owned();
+        let (rest, opts) = parse_opts(args(&["bpm", "/l", "--store", &elsewhere, "--system"])).unwrap();
+        assert_eq!(rest, ["bpm", "/l"]);
+        let store = Store::open_for(&opts);
+        assert_eq!((store.root.to_string_lossy().into_owned(), store.bins_dir()), (elsewhere.clone(), dir.path().join("store").join("bins")));
+        // /doctor --store is still the bare check of the whole store
+        let (rest, opts) = parse_opts(args(&["bpm", "/doctor", "--store", "--fix"])).unwrap();
+        assert_eq!(rest, ["bpm", "/doctor", "--store"]);
+        assert!(opts.fix && opts.store.is_none());
+        // Only this test sets the variable, and nothing else it could affect depends on it
+        unsafe { std::env::set_var("BPM_STORE", &elsewhere); }
+        let from_env = parse_opts(args(&["bpm", "/l"])).map(|(_, opts)| opts.store);
+        let flag_wins = parse_opts(args(&["bpm", "/l", "--store", "other"])).map(|(_, opts)| opts.store);
+        unsafe { std::env::remove_var("BPM_STORE"); }
+        assert_eq!(from_env.unwrap(), Some(elsewhere));
+        assert_eq!(flag_wins.unwrap().as_deref(), Some("other"));
+    }
+
+    #[test]
+    fn index_schema_versions_load_or_degrade() {
+        let (_dir, store) = temp_store();
+        let path = store.root.join("packages.mri");
+        let load = |text: String| { fs::write(&path, text).unwrap(); load_index(&path) };
+        let v1 = load(index(&[("tool", "1.0", &[])])).unwrap();
+        assert_eq!(v1.schema_version, 1);
+        let explicit = load(format!("schema_version = 1\n{}", index(&[("tool", "1.0", &[])]))).unwrap();
+        assert_eq!((explicit.schema_version, explicit.packages.len()), (1, 1));
+        // A newer schema keeps the packages it can read and skips the rest
+        let v2 = load(format!("schema_version = 2\n{}[odd.\"1.0\"]\nlayout = \"v2-only\"\n", index(&[("tool", "1.0", &[])]))).unwrap();
+        assert_eq!(v2.schema_version, 2);
+        assert_eq!(v2.packages.keys().collect::<Vec<_>>(), ["tool"]);
+        for bad in ["schema_version = 0\n", "schema_version = \"2\"\n"] {
+            assert!(load(bad.to_string()).unwrap_err().starts_with("Invalid schema_version"), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn reinstall_leaves_byte_identical_binaries_alone() {
+        let (dir, store) = temp_store();
+        let src = |version: &str, x: &str, y: &str| {
+            let path = dir.path().join(version);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("x"), x).unwrap();
+            fs::write(path.join("y"), y).unwrap();
+            path.to_string_lossy().replace('\\', "/")
+        };
+        let (v1, v2) = (src("v1", "same", "old"), src("v2", "same", "new"));
+        write_repo(&store, "main", &format!(
+            "[tool.\"1.0\"]\npath = \"{}\"\nbinaries = [\"x\", \"y\"]\n[tool.\"2.0\"]\npath = \"{}\"\nbinaries = [\"x\", \"y\"]\n", v1, v2), &[]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "tool", Some("1.0"), &opts).unwrap();
+        let (x, y) = (store.bins_dir().join("x"), store.bins_dir().join("y"));
+        let long_ago = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        for bin in [&x, &y] { fs::File::options().write(true).open(bin).unwrap().set_modified(long_ago).unwrap(); }
+        install_package(&store, "main", "tool", Some("2.0"), &opts).unwrap();
+        assert_eq!(fs::metadata(&x).unwrap().modified().unwrap(), long_ago);
+        assert_ne!(fs::metadata(&y).unwrap().modified().unwrap(), long_ago);
+        assert_eq!(fs::read_to_string(&y).unwrap(), "new");
+        let saved = fs::read_to_string(store.packages_db_path()).unwrap();
+        assert_eq!(saved.matches("NAME:x\n").count(), 1);
+        assert_eq!(saved.matches("NAME:y\n").count(), 2);
+    }
+
+    #[test]
+    fn queries_about_missing_packages_are_errors() {
+        let (_dir, store) = temp_store();
+        assert!(list_files(&store, "nope", &Opts::default()).is_err());
+        assert!(info(&store, "nope", &Opts::default()).is_err());
+        assert!(info(&store, "nope", &Opts { json: true, ..Opts::default() }).is_err());
+        let tool = InstalledPackage { binaries: vec![store.bins_dir().join("tool").to_string_lossy().to_string()], ..installed("main", "1.0") };
+        store.installed.insert("tool", tool);
+        list_files(&store, "tool", &Opts::default()).unwrap();
+        assert!(owns(&store, &store.bins_dir().join("nope").to_string_lossy()).is_err());
+        owns(&store, &store.bins_dir().join("tool").to_string_lossy()).unwrap();
+    }
+
+    #[test]
+    fn owns_matches_paths_however_they_are_spelled() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[])]);
+        install_package(&store, "main", "tool", None, &Opts { yes: true, quiet: true, ..Opts::default() }).unwrap();
+        let roundabout = store.bins_dir().join("..").join("bins").join("tool");
+        owns(&store, &roundabout.to_string_lossy()).unwrap();
+        // A file in bins/ that no package installed is unowned
+        fs::write(store.bins_dir().join("stray"), "x").unwrap();
+        assert!(owns(&store, &store.bins_dir().join("stray").to_string_lossy()).unwrap_err().contains("not owned by any package"));
+    }
+
+    #[test]
+    fn flag_values_must_be_present_and_valid() {
+        for bad in [&["bpm", "/i", "x", "--timeout", "abc"][..], &["bpm", "/i", "x", "--timeout"], &["bpm", "--timeout", "2.5"],
+                    &["bpm", "--timeout", "-1"], &["bpm", "/upgrade", "--exclude"], &["bpm", "/i", "x", "--max-depth", "abc"],
+                    &["bpm", "--parallel", "2.5"], &["bpm", "--limit", "-1"], &["bpm", "--priority", "high"], &["bpm", "--color", "sometimes"],
+                    &["bpm", "/i", "--url"]] {
+            assert!(parse_opts(args(bad)).is_err(), "{:?}", bad);
+        }
+        let (rest, opts) = parse_opts(args(&["bpm", "/i", "x", "--timeout", "3", "--assume-installed", "lib", "--max-depth", "3", "--priority", "-2", "--color", "never"])).unwrap();
+        assert_eq!(rest, ["bpm", "/i", "x"]);
+        assert_eq!(opts.timeout, Some(3));
+        assert_eq!(opts.assume_installed, ["lib"]);
+        assert_eq!(opts.max_depth, Some(3));
+        assert_eq!(opts.priority, Some(-2));
+        assert_eq!(opts.color, Some(ColorChoice::Never));
+    }
+
+    #[test]
+    fn slow_servers_hit_the_timeout() {
+        let url = mock_server(|_| { std::thread::sleep(Duration::from_secs(3)); (200, b"late".to_vec()) });
+        let opts = Opts { timeout: Some(1), ..Opts::default() };
+        let started = std::time::Instant::now();
+        let err = download(&format!("{}/slow", url), None, &Config::default(), &opts).unwrap_err();
+        assert!(err.starts_with("Timed out after 1s fetching"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(3));
+        // The config's timeout applies when no flag is given
+        let config = Config { network_timeout: Some(1), ..Config::default() };
+        assert!(download(&format!("{}/slow", url), None, &config, &Opts::default()).unwrap_err().starts_with("Timed out after 1s"));
+    }
+
+    #[test]
+    fn prefix_installs_mirror_the_repo_layout() {
+        let (dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[])]);
+        let prefix = dir.path().join("opt");
+        let opts = Opts { yes: true, quiet: true, prefix: Some(prefix.to_string_lossy().to_string()), ..Opts::default() };
+        install_package(&store, "main", "tool", None, &opts).unwrap();
+        let bin = prefix.join("main").join("tool").join("tool");
+        assert_eq!(fs::read_to_string(&bin).unwrap(), "tool 1.0");
+        assert!(!store.bins_dir().join("tool").exists());
+        remove_package(&store, "tool", None);
+        assert!(!prefix.join("main").exists());
+        assert!(prefix.is_dir());
+    }
+
+    #[test]
+    fn refresh_replaces_the_cached_index() {
+        let (_dir, store) = temp_store();
+        remote_repo(&store, vec![index(&[("tool", "1.0", &[])]), index(&[("tool", "1.0", &[]), ("tool", "1.1", &[])])]);
+        let opts = Opts { quiet: true, ..Opts::default() };
+        // Nothing is cached yet, so the repo can't be read
+        assert!(load_repo(&store, "web").is_none());
+        refresh_repo(&store, "web", &opts).unwrap();
+        assert_eq!(latest_in_repo(&store, "web", "tool").as_deref(), Some("1.0"));
+        assert!(load_registry(&store)["web"].fetched_at.is_some());
+        refresh_repo(&store, "web", &opts).unwrap();
+        assert_eq!(latest_in_repo(&store, "web", "tool").as_deref(), Some("1.1"));
+        assert!(store.previous_index_path("web").is_file());
+    }
+
+    #[test]
+    fn index_paths_expand_environment_variables() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(expand_env("${PATH}/tools").unwrap(), format!("{}/tools", path));
+        assert_eq!(expand_env("/plain/path").unwrap(), "/plain/path");
+        assert_eq!(expand_env("cost $$5 and $HOME").unwrap(), "cost $5 and $HOME");
+        assert_eq!(expand_env("$${PATH}").unwrap(), "${PATH}");
+        assert_eq!(expand_env("${BPM_TEST_SURELY_UNSET}/x").unwrap_err(), "Environment variable ${BPM_TEST_SURELY_UNSET} is not set");
+        assert!(expand_env("${PATH").unwrap_err().starts_with("Unterminated ${"));
+    }
+
+    #[test]
+    fn resolution_stops_at_the_maximum_depth() {
+        let (_dir, store) = temp_store();
+        // p0 -> p1 -> ... -> p9
+        let names: Vec<String> = (0..10).map(|i| format!("p{}", i)).collect();
+        let deps: Vec<Vec<&str>> = (0..10).map(|i| names.get(i + 1).map(String::as_str).into_iter().collect()).collect();
+        let entries: Vec<(&str, &str, &[&str])> = (0..10).map(|i| (names[i].as_str(), "1.0", deps[i].as_slice())).collect();
+        write_packages(&store, "main", &entries);
+        let plan = |max_depth| resolve_plan(&store, "main", "p0", None, &Opts { max_depth: Some(max_depth), ..Opts::default() });
+        let err = plan(5).unwrap_err();
+        assert!(err.starts_with("Dependency chain deeper than 5: p0:1.0 -> p1:1.0"), "{}", err);
+        assert_eq!(plan(10).unwrap().install.len(), 10);
+        assert!(plan(9).is_err());
+    }
+
+    #[test]
+    fn installs_record_the_hash_of_the_index_used() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[])]);
+        install_package(&store, "main", "tool", None, &Opts { yes: true, quiet: true, ..Opts::default() }).unwrap();
+        let tool = store.installed.get("tool").unwrap();
+        assert_eq!(tool.index_sha256.unwrap(), sha256_bytes(&fs::read(store.repo_index_path("main")).unwrap()));
+        assert_eq!(tool.index_schema, Some(1));
+    }
+
+    #[test]
+    fn repo_stats_summarize_a_small_repo() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "other", &[("dep", "1.0", &[])]);
+        let text = format!("{}[signed.\"0.9\"]\npath = \"src\"\nbinaries = [\"signed\"]\nchecksums = {{ signed = \"00\" }}\n",
+            index(&[("tool", "1.0", &["lib:>=2"]), ("tool", "1.10", &["lib", "other/dep", "other/gone"]), ("lib", "2.0", &[])]));
+        let repo: Repo = toml::from_str(&text).unwrap();
+        let stats = repo_stats(&store, "main", &repo);
+        assert_eq!((stats.packages, stats.versions), (3, 4));
+        assert_eq!(stats.without_checksums, ["lib", "tool"]);
+        assert_eq!(stats.unresolved_dependencies, ["tool:1.10 -> other/gone"]);
+        assert_eq!((stats.newest_version.as_deref(), stats.oldest_version.as_deref()), (Some("2.0"), Some("0.9")));
+    }
+
+    #[test]
+    fn resolution_rejects_cycles_unless_allowed() {
+        let repos = MemRepos::new(&[("main", &index(&[("a", "1.0", &["b"]), ("b", "1.0", &["a"])]))]);
+        let err = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "a", None, &Config::default(), &Opts::default(), &mut ()).unwrap_err();
+        assert!(err.contains("a:1.0 -> b:1.0 -> a:1.0"), "{}", err);
+        let mut events = Recorded::default();
+        let opts = Opts { allow_cycles: true, ..Opts::default() };
+        let plan = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "a", None, &Config::default(), &opts, &mut events).unwrap();
+        assert_eq!(planned(&plan), ["main:b:1.0", "main:a:1.0"]);
+        assert_eq!(events.warnings, [("cycle", "Circular dependency: a:1.0 -> b:1.0 -> a:1.0".to_string())]);
+    }
+
+    #[test]
+    fn keep_going_installs_the_good_packages_past_the_bad() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("b", "1.0", &[]), ("c", "1.0", &[])]);
+        let specs = args(&["main:a", "main:b:9.0", "main:missing", "main:c"]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        assert_eq!(install_each(&store, &specs, &opts), (vec!["main:a"], vec!["main:b:9.0"]));
+        assert!(store.installed.get("c").is_none());
+        let opts = Opts { keep_going: true, ..opts };
+        assert_eq!(install_each(&store, &specs, &opts), (vec!["main:a", "main:c"], vec!["main:b:9.0", "main:missing"]));
+        assert!(store.installed.get("b").is_none());
+        assert!(store.installed.get("c").is_some());
+    }
+
+    #[test]
+    fn requires_env_warns_or_refuses_when_unmet() {
+        let unmet = unmet_requirements(&args(&["PATH", "tool:sh", "BPM_TEST_SURELY_UNSET", "tool:bpm-test-no-such-tool"]));
+        assert_eq!(unmet, ["BPM_TEST_SURELY_UNSET", "tool:bpm-test-no-such-tool"]);
+        let (_dir, mut store) = temp_store();
+        write_repo(&store, "main", concat!(
+            "[ok.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"ok\"]\nrequires_env = [\"PATH\"]\n",
+            "[needy.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"needy\"]\nrequires_env = [\"BPM_TEST_SURELY_UNSET\"]\n",
+        ), &[("ok", "x"), ("needy", "x")]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        // Unmet is only a warning by default
+        install_package(&store, "main", "needy", None, &opts).unwrap();
+        remove_package(&store, "needy", None);
+        store.config.strict_env = true;
+        let err = install_package(&store, "main", "needy", None, &opts).unwrap_err();
+        assert_eq!(err, "needy:1.0 requires BPM_TEST_SURELY_UNSET, nothing installed.");
+        assert!(store.installed.get("needy").is_none());
+        install_package(&store, "main", "ok", None, &opts).unwrap();
+    }
+
+    #[test]
+    fn config_set_round_trips_and_rejects_unknown_keys() {
+        let (_dir, store) = temp_store();
+        let opts = Opts::default();
+        config_command(&store, &args(&["set", "network_timeout", "30"]), &opts).unwrap();
+        config_command(&store, &args(&["set", "prefix", "/opt/tools"]), &opts).unwrap();
+        config_command(&store, &args(&["set", "assume_installed", "[\"libc\"]"]), &opts).unwrap();
+        let config = load_config(&store);
+        assert_eq!(config.network_timeout, Some(30));
+        assert_eq!(config.prefix.as_deref(), Some("/opt/tools"));
+        assert_eq!(config.assume_installed, ["libc"]);
+        config_command(&store, &args(&["get", "network_timeout"]), &opts).unwrap();
+        let before = fs::read_to_string(store.config_path()).unwrap();
+        assert_eq!(config_command(&store, &args(&["set", "no_such_key", "1"]), &opts).unwrap_err(), "Unknown config key no_such_key");
+        assert_eq!(config_command(&store, &args(&["get", "no_such_key"]), &opts).unwrap_err(), "Unknown config key no_such_key");
+        assert!(config_command(&store, &args(&["set", "network_timeout", "soon"]), &opts).unwrap_err().starts_with("Invalid value for network_timeout"));
+        assert_eq!(fs::read_to_string(store.config_path()).unwrap(), before);
+    }
+
+    #[test]
+    fn url_installs_download_and_check_the_binary() {
+        let (_dir, store) = temp_store();
+        let url = mock_server(|head| if head.starts_with("GET /dl/tool ") { (200, b"payload".to_vec()) } else { (404, Vec::new()) });
+        let sum = sha256_bytes(b"payload");
+        let opts = Opts { yes: true, quiet: true, sha256: Some("00".repeat(32)), ..Opts::default() };
+        let err = install_url(&store, &format!("{}/dl/tool", url), None, &opts).unwrap_err();
+        assert!(err.starts_with("Checksum mismatch"), "{}", err);
+        assert!(!store.bins_dir().join("tool").exists());
+        let opts = Opts { sha256: Some(sum.to_uppercase()), ..opts };
+        install_url(&store, &format!("{}/dl/tool", url), None, &opts).unwrap();
+        assert_eq!(fs::read(store.bins_dir().join("tool")).unwrap(), b"payload");
+        let pkg = store.installed.get("tool").unwrap();
+        assert_eq!((pkg.repo.as_str(), pkg.version.as_str()), ("url", &sum[..12]));
+        assert_eq!(pkg.source_url, Some(format!("{}/dl/tool", url)));
+        assert!(install_url(&store, &format!("{}/dl/missing", url), Some("other"), &Opts { sha256: None, ..opts }).is_err());
+        assert!(store.installed.get("other").is_none());
+        assert!(install_url(&store, &format!("{}/dl/", url), Some(".."), &Opts::default()).unwrap_err().contains("pass --name"));
+    }
+
+    #[test]
+    fn check_reports_missing_binaries_of_a_partial_closure() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib"]), ("lib", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        check_closure(&store, "main", "app", None, &opts).unwrap();
+        fs::remove_file(store.root.join("main").join("src").join("lib")).unwrap();
+        let err = check_closure(&store, "main", "app", None, &opts).unwrap_err();
+        assert_eq!(err, "1 binaries needed by app are not available locally");
+        assert!(store.installed.get("app").is_none());
+        assert!(!store.bins_dir().join("app").exists());
+    }
+
+    // The same cases for every InstalledStore backend
+    fn exercise_installed_store(db: &dyn InstalledStore) {
+        assert!(db.get("a").is_none());
+        assert!(db.remove("a").is_none());
+        db.insert("a", installed("main", "1.0"));
+        db.insert("b", installed("main", "2.0"));
+        db.insert("a", installed("extra", "1.1"));
+        assert_eq!(db.get("a").map(|pkg| (pkg.repo, pkg.version)), Some(("extra".to_string(), "1.1".to_string())));
+        assert_eq!(db.iter().map(|(name, pkg)| format!("{}:{}", name, pkg.version)).collect::<Vec<_>>(), ["a:1.1", "b:2.0"]);
+        assert_eq!(db.remove("b").map(|pkg| pkg.version), Some("2.0".to_string()));
+        assert!(db.get("b").is_none());
+        let mut all = db.load();
+        all.insert("c".into(), installed("main", "3.0"));
+        db.save(&all);
+        assert_eq!(db.load().keys().collect::<Vec<_>>(), ["a", "c"]);
+        db.check().unwrap();
+    }
+
+    #[test]
+    fn json_store_behaves_like_an_installed_store() {
+        let dir = tempfile::tempdir().unwrap();
+        exercise_installed_store(&JsonStore { path: dir.path().join("installed.json") });
+        fs::write(dir.path().join("installed.json"), "{ not json").unwrap();
+        let damaged = JsonStore { path: dir.path().join("installed.json") };
+        assert!(damaged.load().is_empty());
+        assert!(damaged.check().is_err());
+        let store = Store { installed: Box::new(damaged), ..Store::new(dir.path()) };
+        assert!(doctor(&store, &Opts::default()).unwrap_err().starts_with("Installed DB is damaged"));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_behaves_like_an_installed_store() {
+        let dir = tempfile::tempdir().unwrap();
+        exercise_installed_store(&SqliteStore { path: dir.path().join("installed.sqlite"), legacy_json: dir.path().join("installed.json") });
+        // A first open imports the JSON DB it replaces
+        let legacy = JsonStore { path: dir.path().join("legacy.json") };
+        legacy.insert("old", installed("main", "0.9"));
+        let db = SqliteStore { path: dir.path().join("migrated.sqlite"), legacy_json: legacy.path.clone() };
+        assert_eq!(db.get("old").map(|pkg| pkg.version), Some("0.9".to_string()));
+    }
+
+    #[test]
+    fn plan_summary_counts_requested_packages_dependencies_and_bytes() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib", "util"]), ("lib", "1.0", &["util"]), ("util", "1.0", &[])]);
+        let plan = resolve_plan(&store, "main", "app", None, &Opts::default()).unwrap();
+        // Each binary holds "<name> 1.0"
+        assert_eq!(plan_summary(&plan), "Will install 3 packages (1 requested, 2 dependencies), 22 bytes");
+        store.installed.insert("util", installed("main", "1.0"));
+        let plan = resolve_plan(&store, "main", "app", None, &Opts::default()).unwrap();
+        assert_eq!(plan_summary(&plan), "Will install 2 packages (1 requested, 1 dependencies), 14 bytes");
+        assert_eq!(plan_summary(&Plan::default()), "Will install 0 packages (0 requested, 0 dependencies), 0 bytes");
+    }
+
+    #[test]
+    fn diff_finds_installs_removals_and_changes_and_applies_them() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("a", "2.0", &[]), ("b", "1.0", &[]), ("c", "1.0", &[]), ("d", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for (name, version) in [("a", "1.0"), ("c", "1.0"), ("d", "1.0")] { install_package(&store, "main", name, Some(version), &opts).unwrap(); }
+        let entry = |version: &str| ManifestEntry { repo: "main".into(), version: version.into(), url: None };
+        let manifest = Manifest { packages: BTreeMap::from([("a".to_string(), entry("2.0")), ("b".to_string(), entry("1.0")), ("d".to_string(), entry("1.0"))]) };
+        let diff = diff_manifest(&store, &manifest);
+        assert_eq!(diff.install.keys().collect::<Vec<_>>(), ["b"]);
+        assert_eq!(diff.remove.keys().collect::<Vec<_>>(), ["c"]);
+        assert_eq!(diff.change.iter().map(|(name, change)| format!("{} {}->{}", name, change.from.version, change.to.version)).collect::<Vec<_>>(), ["a 1.0->2.0"]);
+        apply_diff(&store, &diff, &opts).unwrap();
+        let now: Vec<String> = store.installed.iter().map(|(name, pkg)| format!("{}:{}", name, pkg.version)).collect();
+        assert_eq!(now, ["a:2.0", "b:1.0", "d:1.0"]);
+        assert!(!store.bins_dir().join("c").exists());
+        let diff = diff_manifest(&store, &manifest);
+        assert!(diff.install.is_empty() && diff.remove.is_empty() && diff.change.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn installed_binaries_get_an_executable_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let (_dir, mut store) = temp_store();
+        write_repo(&store, "main", "[plain.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"plain\"]\n[own.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"own\"]\nmode = 0o700\n",
+            &[("plain", "x"), ("own", "x")]);
+        for file in ["plain", "own"] {
+            fs::set_permissions(store.root.join("main").join("src").join(file), fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        let bins = store.bins_dir();
+        let mode = |name: &str| fs::metadata(bins.join(name)).unwrap().permissions().mode() & 0o777;
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "plain", None, &opts).unwrap();
+        install_package(&store, "main", "own", None, &opts).unwrap();
+        assert_eq!((mode("plain"), mode("own")), (0o755, 0o700));
+        remove_package(&store, "plain", None);
+        store.config.mode = Some(0o750);
+        install_package(&store, "main", "plain", None, &opts).unwrap();
+        assert_eq!(mode("plain"), 0o750);
+    }
+
+    #[test]
+    fn regex_search_matches_names_and_descriptions() {
+        let (_dir, store) = temp_store();
+        write_repo(&store, "main", concat!(
+            "[ripgrep.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"rg\"]\ndescription = \"Search files fast\"\n",
+            "[fd.\"2.0\"]\npath = \"{src}\"\nbinaries = [\"fd\"]\n",
+        ), &[]);
+        let regex = Opts { regex: true, ..Opts::default() };
+        assert_eq!(search_matches(&store, "^rip", &regex).unwrap(), ["main:ripgrep 1.0 - Search files fast"]);
+        assert_eq!(search_matches(&store, "^(fd|files)$", &regex).unwrap(), ["main:fd 2.0"]);
+        assert!(search_matches(&store, "^grep", &regex).unwrap().is_empty());
+        assert!(search_matches(&store, "rip(", &regex).unwrap_err().starts_with("Invalid regex rip("));
+        // Without --regex the query is a plain, case-insensitive substring
+        assert_eq!(search_matches(&store, "SEARCH", &Opts::default()).unwrap(), ["main:ripgrep 1.0 - Search files fast"]);
+        assert!(search_matches(&store, "rip(", &Opts::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn updates_stay_in_the_repo_a_package_came_from() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "a", &[("tool", "1.0", &[])]);
+        write_packages(&store, "b", &[("tool", "2.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "a", "tool", None, &opts).unwrap();
+        assert_eq!(update_repo(&store, "tool", None, &opts).unwrap().as_deref(), Some("a"));
+        upgrade_all(&store, &opts);
+        assert_eq!(store.installed.get("tool").map(|pkg| (pkg.repo, pkg.version)), Some(("a".to_string(), "1.0".to_string())));
+        let err = update_repo(&store, "tool", Some("b".into()), &opts).unwrap_err();
+        assert_eq!(err, "tool was installed from a; pass --switch-repo b to update it from there");
+        let switch = Opts { switch_repo: Some("b".into()), ..opts.clone() };
+        assert_eq!(update_repo(&store, "tool", None, &switch).unwrap().as_deref(), Some("b"));
+        write_packages(&store, "a", &[("other", "1.0", &[])]);
+        let err = update_repo(&store, "tool", None, &opts).unwrap_err();
+        assert!(err.starts_with("Repo a does not provide tool"), "{}", err);
+        // Not installed yet: the usual repo selection applies
+        assert_eq!(update_repo(&store, "other", None, &opts).unwrap(), None);
+    }
+
+    #[test]
+    fn sizes_and_ages_switch_units_at_their_boundaries() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1024), "1.0 KB");
+        assert_eq!(human_size(1536), "1.5 KB");
+        assert_eq!(human_size(1024 * 1024 - 1), "1.0 MB");
+        assert_eq!(human_size(1024 * 1024), "1.0 MB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0 GB");
+        assert_eq!(human_size(2048 * 1024 * 1024 * 1024 * 1024), "2048.0 TB");
+        assert_eq!(time_ago(0), "just now");
+        assert_eq!(time_ago(59), "just now");
+        assert_eq!(time_ago(60), "1 minute ago");
+        assert_eq!(time_ago(3599), "59 minutes ago");
+        assert_eq!(time_ago(3600), "1 hour ago");
+        assert_eq!(time_ago(86399), "23 hours ago");
+        assert_eq!(time_ago(86400), "1 day ago");
+        assert_eq!(time_ago(10 * 86400), "10 days ago");
+    }
+
+    #[test]
+    fn prefetch_downloads_at_most_parallel_binaries_at_once() {
+        use std::sync::atomic::AtomicUsize;
+        let (_dir, store) = temp_store();
+        let active = std::sync::Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let seen = active.clone();
+        let url = mock_server(move |head| {
+            let now = seen.0.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+            seen.1.fetch_max(now, AtomicOrdering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            seen.0.fetch_sub(1, AtomicOrdering::SeqCst);
+            if head.contains("/bad ") { (404, Vec::new()) } else { (200, b"bin".to_vec()) }
+        });
+        let step = |name: &str| PlanStep {
+            repo: "par".into(), package: name.into(), version: "1.0".into(),
+            path: format!("{}/bins", url), binaries: vec![name.into()], ..PlanStep::default()
+        };
+        let plan = Plan { install: (0..6).map(|i| step(&format!("p{}", i))).collect(), ..Plan::default() };
+        let opts = Opts { quiet: true, parallel: Some(2), ..Opts::default() };
+        prefetch(&store, &plan, &opts).unwrap();
+        assert_eq!(active.1.load(AtomicOrdering::SeqCst), 2);
+        for step in &plan.install { assert_eq!(fs::read(local_source(&store, step, &step.binaries[0])).unwrap(), b"bin"); }
+
+        // A failed download removes what this run fetched and leaves earlier downloads alone
+        let plan = Plan { install: vec![step("q0"), step("bad"), step("q1"), step("p0")], ..Plan::default() };
+        let err = prefetch(&store, &plan, &Opts { parallel: Some(1), ..opts }).unwrap_err();
+        assert!(err.ends_with("nothing installed."), "{}", err);
+        assert!(!local_source(&store, &plan.install[0], "q0").exists());
+        assert!(local_source(&store, &plan.install[3], "p0").exists());
+    }
+
+    #[test]
+    fn binary_globs_install_every_match_and_fail_on_none() {
+        let (_dir, store) = temp_store();
+        write_repo(&store, "main", concat!(
+            "[many.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"tools/*\", \"readme\"]\n",
+            "[none.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"*.exe\"]\n",
+        ), &[("readme", "r")]);
+        let tools = store.root.join("main").join("src").join("tools");
+        fs::create_dir_all(tools.join("nested")).unwrap();
+        for name in ["b", "a"] { fs::write(tools.join(name), name).unwrap(); }
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "many", None, &opts).unwrap();
+        let binaries = store.installed.get("many").unwrap().binaries;
+        let names: Vec<String> = binaries.iter().map(|bin| Path::new(bin).file_name().unwrap().to_string_lossy().to_string()).collect();
+        assert_eq!(names, ["a", "b", "readme"]);
+        assert!(binaries.iter().all(|bin| Path::new(bin).is_file()));
+        let err = install_package(&store, "main", "none", None, &opts).unwrap_err();
+        assert!(err.contains("Binary glob *.exe matches nothing"), "{}", err);
+        assert!(store.installed.get("none").is_none());
+    }
+
+    #[test]
+    fn index_diffs_list_added_removed_and_bumped_packages() {
+        let old: Repo = toml::from_str(&index(&[("kept", "1.0", &[]), ("bumped", "1.0", &[]), ("gone", "0.3", &[]), ("older", "1.0", &[])])).unwrap();
+        let new: Repo = toml::from_str(&index(&[("kept", "1.0", &[]), ("bumped", "1.0", &[]), ("bumped", "1.2", &[]), ("older", "0.9", &[]), ("fresh", "0.1", &[])])).unwrap();
+        let diff = diff_indexes(&old, &new);
+        assert_eq!(diff.added, BTreeMap::from([("fresh".to_string(), "0.1".to_string())]));
+        assert_eq!(diff.removed, BTreeMap::from([("gone".to_string(), "0.3".to_string())]));
+        let bumped: Vec<String> = diff.bumped.iter().map(|(name, bump)| format!("{} {}->{}", name, bump.from, bump.to)).collect();
+        assert_eq!(bumped, ["bumped 1.0->1.2", "older 1.0->0.9"]);
+        let same = diff_indexes(&new, &new);
+        assert!(same.added.is_empty() && same.removed.is_empty() && same.bumped.is_empty());
+    }
+
+    #[test]
+    fn as_name_must_be_a_plain_file_name() {
+        let step = PlanStep { package: "tool".into(), explicit: true, binaries: vec!["tool".into()], ..PlanStep::default() };
+        for bad in ["..", ".", "a/b", "a\\b", ""] {
+            let opts = Opts { as_name: Some(bad.to_string()), ..Opts::default() };
+            assert!(step_aliases(&step, None, &opts).is_err(), "{:?}", bad);
+        }
+        let opts = Opts { as_name: Some("t".into()), ..Opts::default() };
+        assert_eq!(step_aliases(&step, None, &opts).unwrap(), BTreeMap::from([("tool".to_string(), "t".to_string())]));
+    }
+
+    #[test]
+    fn as_installs_under_the_alias_and_removes_it() {
+        let (dir, store) = temp_store();
+        let a = dir.path().join("a");
+        fs::create_dir_all(&a).unwrap();
+        fs::write(a.join("tool"), "a").unwrap();
+        write_repo(&store, "main", &format!(concat!(
+            "[a.\"1.0\"]\npath = {:?}\nbinaries = [\"tool\"]\n",
+            "[b.\"1.0\"]\npath = \"{{src}}\"\nbinaries = [\"tool\"]\n",
+            "[pair.\"1.0\"]\npath = \"{{src}}\"\nbinaries = [\"tool\", \"helper\"]\n",
+        ), a.to_string_lossy().replace('\\', "/")), &[("tool", "b"), ("helper", "h")]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "a", None, &opts).unwrap();
+        let alias = Opts { as_name: Some("btool".into()), ..opts.clone() };
+        install_package(&store, "main", "b", None, &alias).unwrap();
+        assert_eq!(fs::read_to_string(store.bins_dir().join("tool")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(store.bins_dir().join("btool")).unwrap(), "b");
+        assert_eq!(store.installed.get("b").unwrap().aliases, BTreeMap::from([("tool".to_string(), "btool".to_string())]));
+        verify_installed(&store, Some("b"), &Opts { deep: true, ..opts.clone() }).unwrap();
+        // A reinstall without --as keeps the alias
+        install_package(&store, "main", "b", None, &opts).unwrap();
+        assert!(store.bins_dir().join("btool").is_file());
+        let err = install_package(&store, "main", "pair", None, &Opts { as_name: Some("p".into()), ..opts.clone() }).unwrap_err();
+        assert_eq!(err, "pair has 2 binaries; use --as <binary>=<name>");
+        remove_package(&store, "b", None);
+        assert!(!store.bins_dir().join("btool").exists());
+        assert_eq!(fs::read_to_string(store.bins_dir().join("tool")).unwrap(), "a");
+    }
+
+    #[test]
+    fn doctor_finds_and_fixes_each_kind_of_inconsistency() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("b", "1.0", &[]), ("c", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["a", "b", "c"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        doctor(&store, &opts).unwrap();
+
+        fs::remove_file(store.bins_dir().join("a")).unwrap();
+        fs::write(store.bins_dir().join("b"), "tampered").unwrap();
+        store.installed.insert("ghost", installed("gone", "1.0"));
+        fs::write(store.bins_dir().join("stray"), "x").unwrap();
+        assert_eq!(doctor(&store, &opts).unwrap_err(), "4 problems remain; run /doctor --fix to repair");
+        // Only the missing repo has no repair
+        assert_eq!(doctor(&store, &Opts { fix: true, ..opts.clone() }).unwrap_err(), "1 problems remain");
+        assert_eq!(fs::read_to_string(store.bins_dir().join("a")).unwrap(), "a 1.0");
+        assert_eq!(fs::read_to_string(store.bins_dir().join("b")).unwrap(), "b 1.0");
+        assert!(!store.bins_dir().join("stray").exists());
+
+        remove_package(&store, "ghost", None);
+        fs::remove_file(store.packages_db_path()).unwrap();
+        assert_eq!(doctor(&store, &opts).unwrap_err(), "3 problems remain; run /doctor --fix to repair");
+        doctor(&store, &Opts { fix: true, ..opts.clone() }).unwrap();
+        doctor(&store, &opts).unwrap();
+    }
+
+    #[test]
+    fn private_repos_send_their_credentials() {
+        let (dir, store) = temp_store();
+        let url = mock_server(|head| {
+            let head = head.to_lowercase();
+            let ok = head.contains("authorization: bearer s3cret\r\n") || head.contains("authorization: basic bwu6cznjcmv0\r\n");
+            if ok { (200, index(&[("tool", "1.0", &[])]).into_bytes()) } else { (401, Vec::new()) }
+        });
+        let token = dir.path().join("token");
+        fs::write(&token, "s3cret\n").unwrap();
+        let entry = |username: Option<&str>, token_file: Option<&Path>, token_env: Option<&str>| RepoEntry {
+            url: Some(format!("{}/packages.mri", url)),
+            username: username.map(str::to_string),
+            token_file: token_file.map(|path| path.to_string_lossy().to_string()),
+            token_env: token_env.map(str::to_string),
+            ..RepoEntry::default()
+        };
+        save_registry(&store, &BTreeMap::from([
+            ("open".to_string(), entry(None, None, None)),
+            ("bearer".to_string(), entry(None, Some(&token), None)),
+            ("basic".to_string(), entry(Some("me"), Some(&token), None)),
+            ("unset".to_string(), entry(None, None, Some("BPM_TEST_TOKEN_NOBODY_SETS"))),
+            ("by-name".to_string(), entry(None, None, None)),
+        ]));
+        let opts = Opts { quiet: true, ..Opts::default() };
+        let err = refresh_repo(&store, "open", &opts).unwrap_err();
+        assert!(err.contains("HTTP 401"), "{}", err);
+        refresh_repo(&store, "bearer", &opts).unwrap();
+        refresh_repo(&store, "basic", &opts).unwrap();
+        let err = refresh_repo(&store, "unset", &opts).unwrap_err();
+        assert_eq!(err, "Repo unset reads its token from $BPM_TEST_TOKEN_NOBODY_SETS, which is not set");
+        // Only this test sets the variable, for a repo no other test has
+        unsafe { std::env::set_var("BPM_REPO_TOKEN_BY_NAME", "s3cret"); }
+        let result = refresh_repo(&store, "by-name", &opts);
+        unsafe { std::env::remove_var("BPM_REPO_TOKEN_BY_NAME"); }
+        result.unwrap();
+        assert_eq!(latest_in_repo(&store, "by-name", "tool").as_deref(), Some("1.0"));
+    }
+
+    #[test]
+    fn held_packages_are_skipped_by_update_upgrade_and_diff_apply() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[]), ("other", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "tool", None, &opts).unwrap();
+        install_package(&store, "main", "other", None, &opts).unwrap();
+        hold_command(&store, &args(&["tool"]), true);
+        write_packages(&store, "main", &[("tool", "1.0", &[]), ("tool", "2.0", &[]), ("other", "1.0", &[])]);
+        let version = || store.installed.get("tool").unwrap().version;
+
+        assert_eq!(update_package(&store, "main", "tool", &opts).unwrap_err(), "tool is held; /unhold it or pass --force to update it anyway");
+        upgrade_all(&store, &opts);
+        assert_eq!(version(), "1.0");
+        let entry = ManifestEntry { repo: "main".into(), version: "2.0".into(), url: None };
+        let diff = diff_manifest(&store, &Manifest { packages: BTreeMap::from([("tool".to_string(), entry)]) });
+        assert_eq!(diff.held, BTreeSet::from(["tool".to_string()]));
+        assert!(diff.change.is_empty());
+        assert_eq!(diff.remove.keys().collect::<Vec<_>>(), ["other"]);
+        apply_diff(&store, &diff, &opts).unwrap();
+        assert_eq!(version(), "1.0");
+
+        // The hold lives outside the installed DB
+        store.installed.save(&InstalledDb::new());
+        assert!(load_holds(&store).contains("tool"));
+        hold_command(&store, &args(&["tool"]), false);
+        install_package(&store, "main", "tool", Some("1.0"), &opts).unwrap();
+        update_package(&store, "main", "tool", &opts).unwrap();
+        assert_eq!(version(), "2.0");
+    }
+
+    #[test]
+    fn bundles_round_trip_into_a_fresh_store() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib"]), ("lib", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "app", None, &opts).unwrap();
+        let bundle = tempfile::tempdir().unwrap();
+        export_bundle(&store, &bundle.path().to_string_lossy(), &opts).unwrap();
+
+        // No repos in the new store: everything comes from the bundle
+        let (_other, fresh) = temp_store();
+        import_bundle(&fresh, &bundle.path().to_string_lossy(), &opts).unwrap();
+        let versions: Vec<String> = fresh.installed.iter().map(|(name, pkg)| format!("{}:{}:{}", pkg.repo, name, pkg.version)).collect();
+        assert_eq!(versions, ["main:app:1.0", "main:lib:1.0"]);
+        assert_eq!(fs::read_to_string(fresh.bins_dir().join("lib")).unwrap(), "lib 1.0");
+        verify_installed(&fresh, None, &Opts { deep: true, ..opts.clone() }).unwrap();
+
+        let (_other, fresh) = temp_store();
+        fs::write(bundle.path().join("lib").join("lib"), "tampered").unwrap();
+        assert!(import_bundle(&fresh, &bundle.path().to_string_lossy(), &opts).is_err());
+        assert!(fresh.installed.iter().next().is_none());
+    }
+
+    #[test]
+    fn locked_installs_refuse_drifted_versions_and_binaries() {
+        let (dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib"]), ("lib", "1.0", &[])]);
+        let lock = dir.path().join("bpm.lock").to_string_lossy().to_string();
+        let opts = Opts { yes: true, quiet: true, out: Some(lock.clone()), ..Opts::default() };
+        freeze(&store, "main", "app", None, &opts).unwrap();
+        install_locked(&store, &lock, &opts).unwrap();
+        assert_eq!(store.installed.get("lib").unwrap().version, "1.0");
+        for name in ["app", "lib"] { remove_package(&store, name, None); }
+
+        fs::write(store.root.join("main").join("src").join("lib"), "lib 1.0 rebuilt").unwrap();
+        assert!(install_locked(&store, &lock, &opts).is_err());
+        assert!(store.installed.get("app").is_none() && store.installed.get("lib").is_none());
+
+        write_packages(&store, "main", &[("app", "1.0", &["lib"]), ("lib", "1.1", &[])]);
+        let err = install_locked(&store, &lock, &opts).unwrap_err();
+        assert!(err.contains("lib") && err.ends_with(&format!("refusing to install from {}", lock)), "{}", err);
+        assert!(store.installed.get("app").is_none() && store.installed.get("lib").is_none());
+    }
+
+    #[test]
+    fn odd_and_malicious_names_are_rejected() {
+        for bad in ["..", ".", "", "../x", "a/b", "a\\b", "a..b", "a b", "tab\t", "nl\n", "nul\0", "esc\u{1b}[31m"] {
+            assert_eq!(check_name("package", bad).unwrap_err(), format!("Invalid package name {:?}", bad));
+            assert!(parse_pkg_arg(bad).is_err(), "{:?}", bad);
+            assert!(parse_pkg_arg(&format!("main:{}", bad)).is_err(), "{:?}", bad);
+            assert!(parse_pkg_arg(&format!("main:tool:{}", bad)).is_err(), "{:?}", bad);
+        }
+        for good in ["tool", "tool-2", "lib_x.y", "1.0.0-rc1"] { assert_eq!(check_name("package", good).unwrap(), good); }
+        assert_eq!(parse_pkg_arg("org/main:tool:1.0").unwrap(), (Some("org/main".into()), "tool".into(), Some("1.0".into())));
+        assert!(parse_pkg_arg("org/sub/main:tool").is_err());
+        assert!(parse_pkg_arg("main:tool:1.0:extra").is_err());
+
+        // An index can't smuggle one in either
+        let (_dir, store) = temp_store();
+        write_repo(&store, "main", "[tool.\"../../1.0\"]\npath = \"{src}\"\nbinaries = [\"tool\"]\n[\"a b\".\"1.0\"]\npath = \"{src}\"\nbinaries = [\"tool\"]\n",
+            &[("tool", "x")]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        assert!(install_package(&store, "main", "tool", None, &opts).is_err());
+        assert!(install_package(&store, "main", "a b", None, &opts).is_err());
+        assert!(store.installed.iter().next().is_none());
+    }
+
+    #[test]
+    fn bundle_import_refuses_paths_outside_the_prefix() {
+        let (dir, store) = temp_store();
+        let bundle = dir.path().join("bundle");
+        let prefix = dir.path().join("prefix");
+        fs::create_dir_all(bundle.join("tool")).unwrap();
+        fs::write(bundle.join("tool").join("payload"), "x").unwrap();
+        fs::write(bundle.join("payload"), "x").unwrap();
+        let sum = sha256_bytes(b"x");
+        let escaped = dir.path().join("escaped");
+        let opts = Opts { prefix: Some(prefix.to_string_lossy().to_string()), ..Opts::default() };
+        for (repo, file) in [(escaped.to_string_lossy().to_string(), "payload"), ("main".to_string(), "../payload")] {
+            fs::write(bundle.join("bundle.toml"), format!(
+                "[packages.tool]\nrepo = {:?}\nversion = \"1.0\"\nfiles = {{ {:?} = {:?} }}\n", repo, file, sum)).unwrap();
+            assert!(import_bundle(&store, &bundle.to_string_lossy(), &opts).is_err(), "{} {}", repo, file);
+        }
+        assert!(!escaped.exists());
+        assert!(!prefix.join("main").join("payload").exists());
+        assert!(store.installed.get("tool").is_none());
+    }
+
+    #[test]
+    fn repo_verify_passes_consistent_checksums_and_fails_broken_ones() {
+        let (_dir, store) = temp_store();
+        let good = sha256_bytes(b"x");
+        let entry = |name: &str, checksums: &str| format!("[{}.\"1.0\"]\npath = \"{{src}}\"\nbinaries = [\"tool\"]\nchecksums = {{ {} }}\n", name, checksums);
+        write_repo(&store, "good", &entry("tool", &format!("tool = {:?}", good)), &[("tool", "x")]);
+        repo_verify(&store, "good").unwrap();
+        for checksums in [
+            format!("tool = {:?}", sha256_bytes(b"y")),       // doesn't match the binary
+            "tool = \"abc\"".to_string(),                    // not a sha256
+            format!("tool = {:?}, ghost = {:?}", good, good), // names a binary the package lacks
+        ] {
+            write_repo(&store, "broken", &entry("tool", &checksums), &[("tool", "x")]);
+            assert_eq!(repo_verify(&store, "broken").unwrap_err(), "Repo broken failed verification", "{}", checksums);
+        }
+        write_repo(&store, "broken", &(entry("tool", "") + &entry("Tool", "")), &[("tool", "x")]);
+        assert!(repo_verify(&store, "broken").is_err());
+
+        // A configured key can't be checked against yet, so it fails rather than passing unverified
+        let mut registry = load_registry(&store);
+        registry.insert("good".into(), RepoEntry { key: Some("/keys/good.pub".into()), ..RepoEntry::default() });
+        save_registry(&store, &registry);
+        assert_eq!(repo_verify(&store, "good").unwrap_err(), "Repo good failed verification");
+    }
+
+    #[test]
+    fn prefix_removal_prunes_empty_dirs_but_keeps_shared_ones() {
+        let (dir, store) = temp_store();
+        write_repo(&store, "main", concat!(
+            "[a.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"bin/a\"]\n",
+            "[b.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"b\"]\n",
+            "[c.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"c\"]\n",
+        ), &[("b", "b"), ("c", "c")]);
+        fs::create_dir_all(store.root.join("main").join("src").join("bin")).unwrap();
+        fs::write(store.root.join("main").join("src").join("bin").join("a"), "a").unwrap();
+        let prefix = dir.path().join("opt");
+        let opts = Opts { yes: true, quiet: true, prefix: Some(prefix.to_string_lossy().to_string()), ..Opts::default() };
+        for name in ["a", "b", "c"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        let repo_dir = prefix.join("main");
+        assert!(repo_dir.join("a").join("bin").join("a").is_file());
+        // A file bpm didn't install keeps its directory
+        fs::write(repo_dir.join("c").join("notes.txt"), "mine").unwrap();
+
+        remove_package(&store, "a", None);
+        assert!(!repo_dir.join("a").exists());
+        assert!(repo_dir.join("b").join("b").is_file());
+        remove_package(&store, "c", None);
+        assert!(repo_dir.join("c").join("notes.txt").is_file());
+        fs::remove_dir_all(repo_dir.join("c")).unwrap();
+        remove_package(&store, "b", None);
+        assert!(!repo_dir.exists());
+        assert!(prefix.is_dir());
+    }
+
+    // Every file under `dir` with its contents
+    fn snapshot(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+        glob::glob(&format!("{}/**/*", glob::Pattern::escape(&dir.to_string_lossy()))).unwrap().flatten()
+            .filter(|path| path.is_file())
+            .map(|path| { let data = fs::read(&path).unwrap(); (path, data) })
+            .collect()
+    }
+
+    #[test]
+    fn refresh_dry_run_fetches_and_writes_nothing() {
+        use std::sync::atomic::AtomicUsize;
+        let (_dir, store) = temp_store();
+        let calls = std::sync::Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let url = mock_server(move |_| { counted.fetch_add(1, AtomicOrdering::SeqCst); (200, index(&[("tool", "1.0", &[])]).into_bytes()) });
+        save_registry(&store, &BTreeMap::from([("web".to_string(), RepoEntry { url: Some(format!("{}/packages.mri", url)), ..RepoEntry::default() })]));
+        let dry = Opts { quiet: true, dry_run: true, ..Opts::default() };
+        let before = snapshot(&store.root);
+        refresh_repo(&store, "web", &dry).unwrap();
+        assert_eq!(snapshot(&store.root), before);
+        refresh_repo(&store, "web", &Opts { dry_run: false, ..dry.clone() }).unwrap();
+        assert_eq!(calls.load(AtomicOrdering::SeqCst), 1);
+        let before = snapshot(&store.root);
+        refresh_repo(&store, "web", &dry).unwrap();
+        assert_eq!(snapshot(&store.root), before);
+        assert_eq!(calls.load(AtomicOrdering::SeqCst), 1);
+    }
+
+    #[test]
+    fn explicit_and_deps_filters_partition_the_installed_db() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib"]), ("lib", "1.0", &["base"]), ("base", "1.0", &[]), ("tool", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "app", None, &opts).unwrap();
+        install_package(&store, "main", "tool", None, &opts).unwrap();
+        let names = |opts: &Opts| listed_packages(&store, opts).unwrap().into_keys().collect::<Vec<_>>();
+        assert_eq!(names(&Opts { explicit_only: true, ..Opts::default() }), ["app", "tool"]);
+        assert_eq!(names(&Opts { deps_only: true, ..Opts::default() }), ["base", "lib"]);
+        assert_eq!(names(&Opts::default()), ["app", "base", "lib", "tool"]);
+        let both = Opts { explicit_only: true, deps_only: true, ..Opts::default() };
+        assert_eq!(listed_packages(&store, &both).unwrap_err(), "--explicit and --deps exclude each other.");
+        // Asking for a dependency by name makes it explicit
+        install_package(&store, "main", "lib", None, &opts).unwrap();
+        assert_eq!(names(&Opts { deps_only: true, ..Opts::default() }), ["base"]);
+    }
+
+    #[test]
+    fn color_follows_flag_then_no_color_then_force_color_then_terminal() {
+        use ColorChoice::*;
+        for terminal in [false, true] {
+            for (no_color, force_color) in [(None, None), (Some("1"), None), (None, Some("1")), (Some("1"), Some("1"))] {
+                assert!(color_enabled(Some(Always), no_color, force_color, terminal));
+                assert!(!color_enabled(Some(Never), no_color, force_color, terminal));
+            }
+            for choice in [None, Some(Auto)] {
+                assert_eq!(color_enabled(choice, None, None, terminal), terminal);
+                assert!(!color_enabled(choice, Some("1"), None, terminal));
+                assert!(!color_enabled(choice, Some("1"), Some("1"), terminal));
+                assert!(color_enabled(choice, None, Some("1"), terminal));
+                assert!(!color_enabled(choice, None, Some("0"), terminal));
+                // Empty values count as unset
+                assert_eq!(color_enabled(choice, Some(""), None, terminal), terminal);
+                assert_eq!(color_enabled(choice, None, Some(""), terminal), terminal);
+            }
+        }
+        let (_, opts) = parse_opts(args(&["bpm", "/l", "--no-color"])).unwrap();
+        assert_eq!(opts.color, Some(Never));
+        let (_, opts) = parse_opts(args(&["bpm", "/l", "--color", "always"])).unwrap();
+        assert_eq!(opts.color, Some(Always));
+    }
+
+    #[test]
+    fn scoped_repos_parse_and_live_in_nested_directories() {
+        assert_eq!(parse_pkg_arg("myorg/tools:pkg:1.0").unwrap(), (Some("myorg/tools".into()), "pkg".into(), Some("1.0".into())));
+        assert_eq!(parse_pkg_arg("myorg/tools:pkg").unwrap(), (Some("myorg/tools".into()), "pkg".into(), None));
+        for bad in ["a/b/c", "/tools", "myorg/", "../tools", "my org/tools"] { assert!(check_repo_name(bad).is_err(), "{:?}", bad); }
+        // Slashes only belong to the repo part
+        assert!(parse_pkg_arg("myorg/tools/pkg").is_err());
+        assert!(parse_pkg_arg("tools:my/pkg").is_err());
+
+        let (_dir, store) = temp_store();
+        assert_eq!(store.repo_index_path("myorg/tools"), store.root.join("myorg").join("tools").join("packages.mri"));
+        assert_eq!(store.cached_index_path("myorg/tools"), store.root.join("cache").join("myorg").join("tools").join("packages.mri"));
+        write_packages(&store, "myorg/tools", &[("pkg", "1.0", &["dep"]), ("dep", "1.0", &[])]);
+        write_packages(&store, "tools", &[("pkg", "2.0", &[])]);
+        assert!(list_repos(&store).contains(&"myorg/tools".to_string()));
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "myorg/tools", "pkg", None, &opts).unwrap();
+        for name in ["pkg", "dep"] { assert_eq!(store.installed.get(name).unwrap().repo, "myorg/tools"); }
+        assert_eq!(store.installed.get("pkg").unwrap().version, "1.0");
+    }
+
+    #[test]
+    fn gc_prunes_removed_and_superseded_blobs() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("b", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "a", None, &opts).unwrap();
+        install_package(&store, "main", "b", None, &opts).unwrap();
+        write_packages(&store, "main", &[("a", "1.1", &[]), ("b", "1.0", &[])]);
+        install_package(&store, "main", "a", None, &opts).unwrap();
+        remove_package(&store, "b", None);
+        assert_eq!(packages_db_names(&store), ["a", "b", "a"]);
+
+        let before = fs::read_to_string(store.packages_db_path()).unwrap();
+        gc(&store, &Opts { dry_run: true, ..opts.clone() }).unwrap();
+        assert_eq!(fs::read_to_string(store.packages_db_path()).unwrap(), before);
+        gc(&store, &opts).unwrap();
+        let after = fs::read_to_string(store.packages_db_path()).unwrap();
+        assert_eq!(packages_db_names(&store), ["a"]);
+        assert_eq!(read_packages_db(&after)[0].text, read_packages_db(&before)[2].text);
+        gc(&store, &opts).unwrap();
+        assert_eq!(fs::read_to_string(store.packages_db_path()).unwrap(), after);
+    }
+
+    #[test]
+    fn installs_record_the_resolved_dependencies() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib:1.0", "util"]), ("lib", "1.0", &[]), ("lib", "2.0", &[]), ("util", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "app", None, &opts).unwrap();
+        assert_eq!(store.installed.get("app").unwrap().dependencies, ["lib:1.0", "util:1.0"]);
+        assert!(store.installed.get("util").unwrap().dependencies.is_empty());
+        // The record is what was installed, whatever the repo says later
+        write_packages(&store, "main", &[("app", "1.0", &["other"]), ("other", "1.0", &[])]);
+        assert_eq!(store.installed.get("app").unwrap().dependencies, ["lib:1.0", "util:1.0"]);
+        // Entries from before dependencies were recorded load with none
+        let old: InstalledPackage = serde_json::from_str(r#"{"repo": "main", "version": "1.0", "binaries": []}"#).unwrap();
+        assert!(old.dependencies.is_empty());
+    }
+
+    #[test]
+    fn trace_explains_each_version_choice() {
+        let repos = MemRepos::new(&[("main", &index(&[
+            ("app", "1.0", &["lib:<2.0", "util:1.0", "tool"]),
+            ("lib", "1.0", &[]), ("lib", "1.5", &[]), ("lib", "2.0", &[]),
+            ("util", "1.0", &[]), ("util", "1.1", &[]),
+            ("tool", "0.9", &["lib:>=1.0"]), ("tool", "1.0", &["lib:>=1.0"]),
+        ]))]);
+        let opts = Opts { trace: true, ..Opts::default() };
+        let mut events = Recorded::default();
+        resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "app", None, &Config::default(), &opts, &mut events).unwrap();
+        assert_eq!(events.trace, [
+            "app from main wanted by the command line as any version: candidates 1.0; chose 1.0 (latest)",
+            "lib from main wanted by app:1.0 as <2.0: candidates 1.0, 1.5, 2.0; chose 1.5 (highest matching <2.0; newer 2.0 excluded)",
+            "util from main wanted by app:1.0 as 1.0: candidates 1.0, 1.1; chose 1.0 (pinned)",
+            "tool from main wanted by app:1.0 as any version: candidates 0.9, 1.0; chose 1.0 (latest)",
+            "lib from main wanted by tool:1.0 as >=1.0: candidates 1.0, 1.5, 2.0; chose 2.0 (highest matching >=1.0)",
+            "lib is already planned at 1.5, that plan stands",
+        ]);
+        // Off by default
+        let mut events = Recorded::default();
+        resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "app", None, &Config::default(), &Opts::default(), &mut events).unwrap();
+        assert!(events.trace.is_empty());
+    }
+
+    // A .tar.gz holding `files` as (path, content)
+    fn tar_gz(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default()));
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, content.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn archives_install_from_their_embedded_manifest() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("lib", "1.0", &[])]);
+        let manifest = |sum: &str| format!(
+            "[tool.\"1.0\"]\npath = \"bin\"\nbinaries = [\"tool\"]\ndependencies = [\"main/lib\"]\nchecksums = {{ tool = {:?} }}\n", sum);
+        let good = tar_gz(&[("package.mri", &manifest(&sha256_bytes(b"tool"))), ("bin/tool", "tool")]);
+        let bad = tar_gz(&[("package.mri", &manifest(&sha256_bytes(b"other"))), ("bin/tool", "tool")]);
+        let url = mock_server(move |head| match head.split(' ').nth(1) {
+            Some("/good.tar.gz") => (200, good.clone()),
+            Some("/bad.tar.gz") => (200, bad.clone()),
+            _ => (404, Vec::new()),
+        });
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        assert!(install_archive(&store, &format!("{}/bad.tar.gz", url), &opts).is_err());
+        assert!(store.installed.get("tool").is_none());
+        install_archive(&store, &format!("{}/good.tar.gz", url), &opts).unwrap();
+        let tool = store.installed.get("tool").unwrap();
+        assert_eq!((tool.repo.as_str(), tool.version.as_str()), ("archive", "1.0"));
+        assert_eq!(tool.source_url, Some(format!("{}/good.tar.gz", url)));
+        assert_eq!(fs::read_to_string(store.bins_dir().join("tool")).unwrap(), "tool");
+        assert_eq!(store.installed.get("lib").unwrap().repo, "main");
+    }
+
+    #[test]
+    fn installed_tree_nests_dependencies_and_marks_shared_ones() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[
+            ("app", "1.0", &["lib", "util"]), ("lib", "1.0", &["base"]), ("util", "2.0", &["base"]), ("base", "0.1", &[]),
+            ("tool", "3.0", &["util"]), ("lone", "1.0", &[]),
+        ]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["app", "tool", "lone"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        assert_eq!(installed_tree(&store.installed.load()), [
+            "app 1.0",
+            "├── lib 1.0",
+            "│   └── base 0.1",
+            "└── util 2.0",
+            "    └── base 0.1 (*)",
+            "lone 1.0",
+            "tool 3.0",
+            "└── util 2.0 (*)",
+        ]);
+        // A dependency left behind by its removed dependent becomes a root of its own
+        remove_package(&store, "app", None);
+        remove_package(&store, "tool", None);
+        assert_eq!(installed_tree(&store.installed.load()), ["lone 1.0", "lib 1.0", "└── base 0.1", "util 2.0", "└── base 0.1 (*)"]);
+    }
+
+    #[test]
+    fn installed_tree_marks_assumed_installed_dependencies_external() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["libc", "lib"]), ("lib", "1.0", &["libc"])]);
+        let opts = Opts { yes: true, quiet: true, assume_installed: vec!["libc".into()], ..Opts::default() };
+        install_package(&store, "main", "app", None, &opts).unwrap();
+        assert_eq!(store.installed.get("app").unwrap().dependencies, ["libc", "lib:1.0"]);
+        assert_eq!(installed_tree(&store.installed.load()), [
+            "app 1.0",
+            "├── libc (external)",
+            "└── lib 1.0",
+            "    └── libc (external)",
+        ]);
+    }
+
+    #[test]
+    fn why_follows_recorded_dependencies_back_to_each_root() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[
+            ("app", "1.0", &["lib", "util"]), ("lib", "1.0", &["base"]), ("util", "2.0", &["base"]), ("base", "0.1", &[]),
+            ("tool", "3.0", &["util"]),
+        ]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["app", "tool"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        let chains = |name: &str| dependent_chains(&store.installed.load(), name);
+        assert_eq!(chains("base"), [
+            ["app 1.0", "lib 1.0", "base 0.1"],
+            ["app 1.0", "util 2.0", "base 0.1"],
+            ["tool 3.0", "util 2.0", "base 0.1"],
+        ]);
+        assert!(chains("app").is_empty());
+        // A chain stops at an explicit package even when something depends on it in turn
+        install_package(&store, "main", "util", None, &opts).unwrap();
+        assert_eq!(chains("base"), [vec!["app 1.0", "lib 1.0", "base 0.1"], vec!["util 2.0", "base 0.1"]]);
+        assert!(why(&store, "base").is_ok());
+        assert_eq!(why(&store, "nope").unwrap_err(), "Package nope is not installed");
+    }
+
+    #[test]
+    fn cache_clear_is_selective_and_ttl_decides_staleness() {
+        let (_dir, store) = temp_store();
+        let cache = store.root.join("cache");
+        let files = [
+            cache.join("web").join("packages.mri"),
+            cache.join("web").join("packages.prev.mri"),
+            cache.join("web").join("bins").join("tool").join("1.0").join("tool"),
+            cache.join("archive").join("0123456789ab").join("package.mri"),
+        ];
+        for file in &files {
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "x").unwrap();
+        }
+        let exists = || files.iter().map(|file| file.exists()).collect::<Vec<_>>();
+        cache_command(&store, &args(&["clear"]), &Opts { binaries: true, dry_run: true, ..Opts::default() }).unwrap();
+        assert_eq!(exists(), [true, true, true, true]);
+        cache_command(&store, &args(&["clear"]), &Opts { binaries: true, ..Opts::default() }).unwrap();
+        // An extracted archive's package.mri is not a repo index
+        assert_eq!(exists(), [true, true, false, false]);
+        assert!(!cache.join("web").join("bins").exists() && !cache.join("archive").exists());
+        cache_command(&store, &args(&["clear"]), &Opts { indexes: true, ..Opts::default() }).unwrap();
+        assert_eq!(exists(), [false, false, false, false]);
+
+        assert!(index_stale(None, Some(60)));
+        assert!(index_stale(Some(10), None));
+        assert!(!index_stale(Some(59), Some(60)));
+        assert!(index_stale(Some(60), Some(60)));
+        assert!(index_stale(Some(0), Some(0)));
+    }
+
+    #[test]
+    fn interactive_choices_settle_a_conflicting_diamond() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[
+            ("app", "1.0", &["left", "right"]), ("left", "1.0", &["lib:1.0"]), ("right", "1.0", &["lib:2.0"]),
+            ("lib", "1.0", &[]), ("lib", "2.0", &[]),
+        ]);
+        let interactive = Opts { interactive: true, quiet: true, ..Opts::default() };
+        let mut asked = Vec::new();
+        let plan = resolve_choosing(&store, &store, "main", "app", None, &interactive, &mut |header, items| {
+            asked.push((header.to_string(), items.to_vec()));
+            Some(1)
+        }).unwrap();
+        assert_eq!(asked, [("Conflict: lib is needed at both 1.0 and 2.0. Which should every dependent get?".to_string(),
+            vec!["main:lib:1.0 (planned first)".to_string(), "main:lib:2.0".to_string()])]);
+        assert!(plan.conflicts.is_empty());
+        assert_eq!(planned(&plan), ["main:lib:2.0", "main:left:1.0", "main:right:1.0", "main:app:1.0"]);
+
+        // Declining keeps the conflict, and without --interactive nothing is asked
+        let plan = resolve_choosing(&store, &store, "main", "app", None, &interactive, &mut |_, _| None).unwrap();
+        assert_eq!(plan.conflicts, ["lib is needed at 2.0 but 1.0 is already planned"]);
+        let plan = resolve_choosing(&store, &store, "main", "app", None, &Opts::default(), &mut |_, _| panic!("asked")).unwrap();
+        assert_eq!(plan.conflicts.len(), 1);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        assert!(install_package(&store, "main", "app", None, &opts).is_err());
+        install_package(&store, "main", "app", None, &Opts { interactive: true, ..opts }).unwrap();
+        assert_eq!(store.installed.get("lib").unwrap().version, "1.0");
+    }
+
+    #[test]
+    fn ping_reports_reachable_and_unreachable_repos() {
+        let (_dir, store) = temp_store();
+        let url = mock_server(|head| match head.split(' ').nth(1) {
+            Some("/good.mri") => (200, index(&[("tool", "1.0", &[]), ("tool", "1.1", &[])]).into_bytes()),
+            Some("/garbled.mri") => (200, b"[[[".to_vec()),
+            _ => (500, Vec::new()),
+        });
+        // A port nothing listens on any more
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let remote = |path: String| RepoEntry { url: Some(path), ..RepoEntry::default() };
+        save_registry(&store, &BTreeMap::from([
+            ("good".to_string(), remote(format!("{}/good.mri", url))),
+            ("garbled".to_string(), remote(format!("{}/garbled.mri", url))),
+            ("failing".to_string(), remote(format!("{}/failing.mri", url))),
+            ("down".to_string(), remote(format!("http://{}/packages.mri", closed))),
+        ]));
+        write_packages(&store, "local", &[("tool", "1.0", &[])]);
+        let opts = Opts { quiet: true, timeout: Some(5), ..Opts::default() };
+        let ping = |name| repo_ping(&store, name, &opts);
+
+        let good = ping("good");
+        assert!(good.reachable && good.error.is_none());
+        assert_eq!((good.status, good.packages, good.versions), (Some(200), Some(1), Some(2)));
+        assert!(good.latency_ms.is_some());
+        let garbled = ping("garbled");
+        assert!(garbled.reachable && garbled.error.is_some() && garbled.packages.is_none());
+        let failing = ping("failing");
+        assert_eq!((failing.reachable, failing.status), (true, Some(500)));
+        assert!(failing.error.unwrap().ends_with("HTTP 500 Internal Server Error"));
+        let down = ping("down");
+        assert!(!down.reachable && down.status.is_none() && down.error.is_some());
+        let local = ping("local");
+        assert!(local.reachable && local.status.is_none());
+        assert_eq!(local.packages, Some(1));
+        assert!(!ping("missing").reachable);
+        // Pinging caches nothing
+        assert!(!store.root.join("cache").exists());
+    }
+
+    #[test]
+    fn interrupted_copies_never_leave_a_partial_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dest) = (dir.path().join("src"), dir.path().join("tool"));
+        fs::write(&src, "new build").unwrap();
+        fs::write(&dest, "old build").unwrap();
+
+        // A copy that fails part-way is discarded, and the installed binary is untouched
+        let (tmp, mut file) = claim_tmp(&dest).unwrap();
+        file.write_all(b"new bu").unwrap();
+        drop(file);
+        assert!(commit_tmp(&tmp, &dest, Err("interrupted".into()), DEFAULT_BIN_MODE).is_err());
+        assert!(!tmp.exists());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "old build");
+        assert!(place_file(&dir.path().join("missing"), &dest, DEFAULT_BIN_MODE).is_err());
+        assert!(!tmp.exists());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "old build");
+
+        // A fresh .tmp belongs to a run still writing it; an old one was left by a crash
+        fs::write(&tmp, "new").unwrap();
+        let err = place_file(&src, &dest, DEFAULT_BIN_MODE).unwrap_err();
+        assert!(err.ends_with("is being written by another bpm run; try again once it finishes"), "{}", err);
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "old build");
+        let crashed = std::time::SystemTime::now() - Duration::from_secs(STALE_TMP_SECS + 1);
+        fs::File::options().write(true).open(&tmp).unwrap().set_modified(crashed).unwrap();
+        assert_eq!(place_file(&src, &dest, DEFAULT_BIN_MODE).unwrap(), sha256_bytes(b"new build"));
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new build");
+        assert!(!tmp.exists());
+    }
+
+    #[test]
+    fn downloaded_sources_only_appear_in_the_cache_once_complete() {
+        let (_dir, store) = temp_store();
+        let url = mock_server(|_| (200, b"whole build".to_vec()));
+        let step = PlanStep { package: "tool".into(), version: "1.0".into(), repo: "web".into(), path: url, ..PlanStep::default() };
+        let cached = local_source(&store, &step, "tool");
+        // What a download cut short leaves behind is not taken for the cached binary
+        fs::create_dir_all(cached.parent().unwrap()).unwrap();
+        fs::write(cached.with_extension("part"), "whole bu").unwrap();
+        assert!(!cached.exists());
+        assert_eq!(source_file(&store, &step, "tool", &Opts { quiet: true, ..Opts::default() }).unwrap(), cached);
+        assert_eq!(fs::read_to_string(&cached).unwrap(), "whole build");
+        assert!(!cached.with_extension("part").exists());
+    }
+
+    #[test]
+    fn versions_since_and_limit_trim_the_list() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "0.9", &[]), ("tool", "0.10", &[]), ("tool", "1.0", &[]), ("tool", "1.2", &[])]);
+        write_packages(&store, "extra", &[("tool", "2.0", &[])]);
+        store.installed.insert("tool", installed("main", "0.10"));
+        let versions = |arg: &str, since: Option<&str>, limit: Option<usize>| {
+            let opts = Opts { since: since.map(str::to_string), limit, ..Opts::default() };
+            version_entries(&store, arg, &opts).map(|(_, entries)| entries.iter()
+                .map(|e| format!("{}:{}{}", e.repo, e.version, if e.installed { "*" } else { "" })).collect::<Vec<_>>())
+        };
+        assert_eq!(versions("main:tool", None, None).unwrap(), ["main:1.2", "main:1.0", "main:0.10*", "main:0.9"]);
+        assert_eq!(versions("main:tool", Some("0.10"), None).unwrap(), ["main:1.2", "main:1.0"]);
+        assert_eq!(versions("main:tool", None, Some(2)).unwrap(), ["main:1.2", "main:1.0"]);
+        assert_eq!(versions("main:tool", Some("0.9"), Some(1)).unwrap(), ["main:1.2"]);
+        assert!(versions("main:tool", Some("1.2"), None).unwrap().is_empty());
+        assert_eq!(versions("tool", Some("1.0"), None).unwrap(), ["extra:2.0", "main:1.2"]);
+        assert_eq!(versions("main:nothing", None, None).unwrap_err(), "Package nothing not found");
+        assert_eq!(versions("ghost:tool", None, None).unwrap_err(), "Repo ghost not found");
+    }
+
+    #[test]
+    fn user_and_system_roots_install_independently() {
+        let dir = tempfile::tempdir().unwrap();
+        let (user_root, system_root) = (dir.path().join("user"), dir.path().join("system"));
+        fs::create_dir_all(&system_root).unwrap();
+        fs::write(system_root.join("config.toml"), "repos_from = \"user\"\n").unwrap();
+        let user = Store::open_in(false, &user_root, &system_root);
+        write_packages(&user, "main", &[("tool", "1.0", &[]), ("other", "1.0", &[])]);
+        let system = Store::open_in(true, &user_root, &system_root);
+        assert_eq!(system.repos_root, user_root);
+        assert_eq!(system.bins_dir(), system_root.join("bins"));
+
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&user, "main", "tool", None, &opts).unwrap();
+        install_package(&system, "main", "other", None, &opts).unwrap();
+        assert_eq!(user.installed.load().into_keys().collect::<Vec<_>>(), ["tool"]);
+        assert_eq!(system.installed.load().into_keys().collect::<Vec<_>>(), ["other"]);
+        assert!(user_root.join("bins").join("tool").is_file() && !user_root.join("bins").join("other").exists());
+        assert!(system_root.join("bins").join("other").is_file() && !system_root.join("bins").join("tool").exists());
+        remove_package(&system, "other", None);
+        assert!(user.installed.get("tool").is_some());
+        // Without repos_from each root has its own repos
+        fs::remove_file(system_root.join("config.toml")).unwrap();
+        assert!(!list_repos(&Store::open_in(true, &user_root, &system_root)).contains(&"main".to_string()));
+    }
+
+    #[test]
+    fn set_priority_reorders_selection_for_an_ambiguous_name() {
+        let (_dir, store) = temp_store();
+        for repo in ["alpha", "beta", "gamma"] { write_repo(&store, repo, &index(&[("tool", "1.0", &[])]), &[]); }
+        let opts = Opts { yes: true, ..Opts::default() };
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("alpha"));
+        repo_command(&store, &args(&["set-priority", "gamma", "10"]), &opts);
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("gamma"));
+        repo_command(&store, &args(&["set-priority", "beta", "20"]), &opts);
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("beta"));
+        repo_command(&store, &args(&["set-priority", "beta", "-1"]), &opts);
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("gamma"));
+        // Bad numbers and unknown repos change nothing
+        let before = fs::read_to_string(store.registry_path()).unwrap();
+        repo_command(&store, &args(&["set-priority", "alpha", "high"]), &opts);
+        repo_command(&store, &args(&["set-priority", "alpha", "1.5"]), &opts);
+        repo_command(&store, &args(&["set-priority", "delta", "99"]), &opts);
+        assert_eq!(fs::read_to_string(store.registry_path()).unwrap(), before);
+        // Back to 0 drops the setting
+        repo_command(&store, &args(&["set-priority", "gamma", "0"]), &opts);
+        assert_eq!(load_registry(&store)["gamma"].priority, None);
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("alpha"));
+    }
+
+    #[test]
+    fn half_installed_packages_are_detected_and_completed() {
+        let (_dir, store) = temp_store();
+        write_repo(&store, "main", "[pair.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"one\", \"two\"]\n[solo.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"solo\"]\n",
+            &[("one", "1"), ("two", "2"), ("solo", "s")]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "pair", None, &opts).unwrap();
+        install_package(&store, "main", "solo", None, &opts).unwrap();
+        assert!(partial_installs(&store.installed.load()).is_empty());
+        // What a crash between the two copies of an old, non-atomic install left behind
+        let two = store.bins_dir().join("two");
+        fs::remove_file(&two).unwrap();
+        assert_eq!(partial_installs(&store.installed.load()), [("pair".to_string(), vec![two.to_string_lossy().to_string()])]);
+
+        let pkg = store.installed.get("pair").unwrap();
+        let missing = [two.to_string_lossy().to_string()];
+        assert!(repair_partial(&store, "pair", &pkg, &missing, &opts));
+        assert_eq!(fs::read_to_string(&two).unwrap(), "2");
+        assert!(partial_installs(&store.installed.load()).is_empty());
+
+        // An archive package can't be fetched again by version
+        let mut pkg = store.installed.get("solo").unwrap();
+        pkg.repo = "archive".into();
+        store.installed.insert("solo", pkg.clone());
+        fs::remove_file(store.bins_dir().join("solo")).unwrap();
+        assert!(!repair_partial(&store, "solo", &pkg, &pkg.binaries, &opts));
+        assert_eq!(partial_installs(&store.installed.load()).len(), 1);
+    }
+
+    #[test]
+    fn glob_removal_takes_every_match_but_literal_names_come_first() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("py3", "1.0", &[]), ("pyenv", "1.0", &[]), ("ruby", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["py3", "pyenv", "ruby"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        store.installed.insert("py*", installed("main", "1.0"));
+        let names = || store.installed.load().into_keys().collect::<Vec<_>>();
+        // An installed package literally named like the pattern is removed alone
+        remove_matching(&store, "py*", None, &opts);
+        assert_eq!(names(), ["py3", "pyenv", "ruby"]);
+        remove_matching(&store, "py*", None, &opts);
+        assert_eq!(names(), ["ruby"]);
+        assert!(!store.bins_dir().join("py3").exists() && !store.bins_dir().join("pyenv").exists());
+        remove_matching(&store, "x?", None, &opts);
+        remove_matching(&store, "[", None, &opts);
+        assert_eq!(names(), ["ruby"]);
+    }
+
+    #[test]
+    fn removal_refuses_packages_still_needed_unless_forced() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["libfoo"]), ("libfoo", "1.0", &["libbar"]), ("libbar", "1.0", &[]),
+            ("libbaz", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["app", "libbaz"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        let names = || store.installed.load().into_keys().collect::<Vec<_>>();
+        // app still needs libfoo, so none of the matches go, not even libbaz
+        remove_matching(&store, "lib*", None, &opts);
+        assert_eq!(names(), ["app", "libbar", "libbaz", "libfoo"]);
+        remove_matching(&store, "libbar", None, &opts);
+        assert_eq!(names(), ["app", "libbar", "libbaz", "libfoo"]);
+        // Dependents that match as well go with it
+        remove_matching(&store, "*", None, &opts);
+        assert!(names().is_empty());
+
+        for name in ["app", "libbaz"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        remove_matching(&store, "lib*", None, &Opts { force: true, ..opts.clone() });
+        assert_eq!(names(), ["app"]);
+    }
+
+    #[test]
+    fn clean_removes_dependencies_nothing_needs_down_the_chain() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib"]), ("lib", "1.0", &["base"]), ("base", "1.0", &[]),
+            ("tool", "1.0", &["util"]), ("util", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["app", "tool"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        let names = || store.installed.load().into_keys().collect::<Vec<_>>();
+        clean_unneeded(&store, &opts);
+        assert_eq!(names(), ["app", "base", "lib", "tool", "util"]);
+
+        remove_package(&store, "app", None);
+        remove_package(&store, "tool", None);
+        assert_eq!(unneeded_packages(&store.installed.load(), &[]), ["lib", "util", "base"]);
+        // An excluded package stays, and keeps what it depends on
+        clean_unneeded(&store, &Opts { dry_run: true, ..opts.clone() });
+        assert_eq!(names(), ["base", "lib", "util"]);
+        clean_unneeded(&store, &Opts { exclude: vec!["lib".into()], ..opts.clone() });
+        assert_eq!(names(), ["base", "lib"]);
+        assert!(!store.bins_dir().join("util").exists());
+        clean_unneeded(&store, &opts);
+        assert!(names().is_empty());
+    }
+
+    #[test]
+    fn info_json_merges_installed_and_available_state() {
+        let (_dir, store) = temp_store();
+        write_repo(&store, "main", concat!(
+            "[tool.\"1.0\"]\npath = \"src\"\nbinaries = [\"tool\"]\n",
+            "[tool.\"1.1\"]\npath = \"src\"\nbinaries = [\"tool\"]\ndescription = \"Faster\"\ndependencies = [\"lib\"]\nchecksums = { tool = \"ab\" }\n",
+        ), &[]);
+        write_repo(&store, "other", "[tool.\"3.0\"]\npath = \"src\"\nbinaries = [\"tool\"]\ndeprecated = \"use main\"\n", &[]);
+        let pkg = installed("main", "1.0");
+        let info = serde_json::to_value(package_info(&store, "tool", Some(&pkg))).unwrap();
+        assert_eq!(info["schema"], INFO_SCHEMA);
+        assert_eq!(info["name"], "tool");
+        assert_eq!(info["status"], "outdated");
+        // Only the installed package's own repo decides what is newer
+        assert_eq!(info["latest"], "1.1");
+        assert_eq!(info["installed"]["version"], "1.0");
+        assert_eq!(info["available"], serde_json::json!([
+            { "repo": "main", "version": "1.1", "description": "Faster", "binaries": ["tool"], "dependencies": ["lib"],
+              "checksums": { "tool": "ab" }, "deprecated": null },
+            { "repo": "main", "version": "1.0", "description": null, "binaries": ["tool"], "dependencies": [], "checksums": {}, "deprecated": null },
+            { "repo": "other", "version": "3.0", "description": null, "binaries": ["tool"], "dependencies": [], "checksums": {}, "deprecated": "use main" },
+        ]));
+        let current = installed("main", "1.1");
+        assert_eq!(package_info(&store, "tool", Some(&current)).status, "installed");
+        let info = package_info(&store, "tool", None);
+        assert_eq!((info.status, info.latest.as_deref()), ("not-installed", Some("3.0")));
+        assert!(package_info(&store, "nothing", None).available.is_empty());
+    }
+
+    #[test]
+    fn installing_a_successor_removes_the_package_it_replaces() {
+        let (dir, store) = temp_store();
+        let old_src = dir.path().join("old");
+        fs::create_dir_all(&old_src).unwrap();
+        for bin in ["tool", "oldhelper"] { fs::write(old_src.join(bin), "old").unwrap(); }
+        write_repo(&store, "main", &format!(concat!(
+            "[oldtool.\"1.0\"]\npath = {:?}\nbinaries = [\"tool\", \"oldhelper\"]\n",
+            "[newtool.\"2.0\"]\npath = \"{{src}}\"\nbinaries = [\"tool\"]\nreplaces = [\"oldtool\", \"newtool\", \"never-installed\"]\n",
+            "[app.\"1.0\"]\npath = \"{{src}}\"\nbinaries = [\"app\"]\ndependencies = [\"newtool\"]\n",
+        ), old_src.to_string_lossy().replace('\\', "/")), &[("tool", "new"), ("app", "app")]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "oldtool", None, &opts).unwrap();
+        let plan = resolve_plan(&store, "main", "app", None, &opts).unwrap();
+        // Only installed packages other than itself are replaced
+        assert_eq!(plan.install.iter().map(|step| step.replaces.clone()).collect::<Vec<_>>(), [vec!["oldtool".to_string()], vec![]]);
+        install_package(&store, "main", "app", None, &opts).unwrap();
+        assert!(store.installed.get("oldtool").is_none());
+        assert_eq!(fs::read_to_string(store.bins_dir().join("tool")).unwrap(), "new");
+        assert!(!store.bins_dir().join("oldhelper").exists());
+        // oldtool was asked for by name, so its successor now is too
+        assert!(store.installed.get("newtool").unwrap().explicit);
+    }
+
+    #[test]
+    fn templates_fill_placeholders_escape_braces_and_reject_unknowns() {
+        let pkg = InstalledPackage { binaries: vec!["/b/tool".into(), "/b/t2".into()], size: Some(2048), ..installed("main", "1.2") };
+        let fields = format_fields("tool", &pkg);
+        assert_eq!(render_template("{name} {version} ({repo})", &fields).unwrap(), "tool 1.2 (main)");
+        assert_eq!(render_template("{{name}}={name}", &fields).unwrap(), "{name}=tool");
+        assert_eq!(render_template("plain text", &fields).unwrap(), "plain text");
+        assert_eq!(render_template("", &fields).unwrap(), "");
+        assert_eq!(render_template("{name}{name}", &fields).unwrap(), "tooltool");
+        assert_eq!(render_template("{size}\t{explicit}\t{binaries}", &fields).unwrap(), "2048\ttrue\t/b/tool /b/t2");
+        // Fields without a value render empty
+        assert_eq!(render_template("[{source}]", &fields).unwrap(), "[]");
+        let err = render_template("{nmae}", &fields).unwrap_err();
+        assert!(err.starts_with("Unknown placeholder {nmae} in --format; known: name, version"), "{}", err);
+        assert_eq!(render_template("{name", &fields).unwrap_err(), "Unclosed { in --format \"{name\"");
+        assert!(render_template("{}", &fields).is_err());
+        for key in FORMAT_FIELDS { assert!(fields.contains_key(key), "{}", key); }
+    }
+
+    #[test]
+    fn a_package_depending_on_itself_gets_its_own_error() {
+        let repos = MemRepos::new(&[
+            ("main", &index(&[("tool", "1.0", &["lib", "tool"]), ("lib", "1.0", &[]), ("pinned", "1.0", &["main/pinned:1.0"]),
+                ("folded", "1.0", &["Folded"]), ("twin", "1.0", &["Twin"]), ("Twin", "1.0", &[])])),
+        ]);
+        let resolve_root = |package: &str, opts: &Opts| resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", package, None, &Config::default(), opts, &mut ());
+        assert_eq!(resolve_root("tool", &Opts::default()).unwrap_err(), "tool:1.0 depends on itself (\"tool\" in its dependencies)");
+        assert_eq!(resolve_root("pinned", &Opts::default()).unwrap_err(), "pinned:1.0 depends on itself (\"main/pinned:1.0\" in its dependencies)");
+        // Names match case-insensitively, so a differently cased name is still itself
+        assert_eq!(resolve_root("folded", &Opts::default()).unwrap_err(), "folded:1.0 depends on itself (\"Folded\" in its dependencies)");
+        // Not a real cycle, so --allow-cycles doesn't let it through
+        assert!(resolve_root("tool", &Opts { allow_cycles: true, ..Opts::default() }).is_err());
+        // An exact match is another package, whatever the case
+        assert_eq!(planned(&resolve_root("twin", &Opts::default()).unwrap()), ["main:Twin:1.0", "main:twin:1.0"]);
+    }
+
+    #[test]
+    fn which_prints_the_main_binary_or_all_of_them() {
+        let (_dir, store) = temp_store();
+        let bins = store.root.join("bin");
+        let bin = |name: &str| bins.join(name).to_string_lossy().into_owned();
+        store.installed.insert("solo", InstalledPackage { binaries: vec![bin("solo-cli")], ..installed("main", "1.0") });
+        store.installed.insert("tool", InstalledPackage { binaries: vec![bin("tool-helper"), bin("tool")], ..installed("main", "1.0") });
+        store.installed.insert("kit", InstalledPackage { binaries: vec![bin("kit-a"), bin("kit-b")], ..installed("main", "1.0") });
+        let paths = |package: &str, all: bool| which_paths(&store, package, &Opts { all, ..Opts::default() });
+
+        assert_eq!(paths("solo", false).unwrap(), [bins.join("solo-cli")]);
+        // With several binaries the one named after the package wins
+        assert_eq!(paths("tool", false).unwrap(), [bins.join("tool")]);
+        assert_eq!(paths("tool", true).unwrap(), [bins.join("tool-helper"), bins.join("tool")]);
+        let err = paths("kit", false).unwrap_err();
+        assert!(err.contains("use --all"), "{}", err);
+        assert_eq!(paths("kit", true).unwrap().len(), 2);
+        assert!(paths("missing", false).unwrap_err().contains("not installed"));
+    }
+
+    #[test]
+    fn profile_names_cannot_leave_profiles_dir() {
+        let (_dir, store) = temp_store();
+        fs::write(store.root.join("installed.json"), "{}").unwrap();
+        let opts = Opts { yes: true, ..Opts::default() };
+        profile_command(&store, None, &args(&["create", "p1"]), &opts).unwrap();
+        for sub in ["remove", "use", "create"] {
+            for name in ["..", "../p1", "a/b", ""] {
+                assert!(profile_command(&store, None, &args(&[sub, name]), &opts).is_err(), "{} {:?}", sub, name);
+            }
+        }
+        assert!(store.root.join("installed.json").exists());
+        assert!(store.profile_dir("p1").is_dir());
+    }
+
+    #[test]
+    fn profiles_install_the_same_package_independently() {
+        let (dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[]), ("tool", "2.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["work", "play"] { profile_command(&store, None, &args(&["create", name]), &opts).unwrap(); }
+        assert_eq!(profile_names(&store), ["play", "work"]);
+        let profile = |name: &str| {
+            let mut store = Store::new(dir.path());
+            store.use_profile(name);
+            store
+        };
+        let (work, play) = (profile("work"), profile("play"));
+        install_package(&work, "main", "tool", Some("1.0"), &opts).unwrap();
+        install_package(&play, "main", "tool", Some("2.0"), &opts).unwrap();
+
+        assert_eq!(work.installed.get("tool").unwrap().version, "1.0");
+        assert_eq!(play.installed.get("tool").unwrap().version, "2.0");
+        assert!(work.bins_dir().join("tool").exists() && play.bins_dir().join("tool").exists());
+        assert!(work.bins_dir().starts_with(store.profile_dir("work")));
+        // The default profile saw none of it
+        assert!(store.installed.get("tool").is_none());
+
+        remove_package(&work, "tool", None);
+        assert!(work.installed.get("tool").is_none());
+        assert_eq!(play.installed.get("tool").unwrap().version, "2.0");
+        assert!(play.bins_dir().join("tool").exists());
+    }
+
+    #[test]
+    fn security_level_decides_what_an_unchecksummed_package_gets() {
+        let (_dir, mut store) = temp_store();
+        let sealed_index = format!("[sealed.\"1.0\"]\npath = \"{{src}}\"\nbinaries = [\"sealed\"]\nchecksums = {{ sealed = \"{}\" }}\n",
+            sha256_bytes(b"sealed 1.0"));
+        write_repo(&store, "main", &(index(&[("bare", "1.0", &[])]).replace("\"src\"", "\"{src}\"") + &sealed_index),
+            &[("bare", "bare 1.0"), ("sealed", "sealed 1.0")]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        let mut install_as = |security: Security, package: &str| {
+            store.config.security = security;
+            store.installed.save(&InstalledDb::new());
+            install_package(&store, "main", package, None, &opts).map(|_| store.installed.get(package).is_some())
+        };
+
+        assert_eq!(install_as(Security::Permissive, "bare"), Ok(true));
+        assert_eq!(install_as(Security::Warn, "bare"), Ok(true));
+        assert!(take_warnings().iter().any(|w| w.kind == "security" && w.message == "bare:1.0 has no checksum for bare"));
+        let err = install_as(Security::Strict, "bare").unwrap_err();
+        assert!(err.starts_with("security = strict"), "{}", err);
+        assert_eq!(install_as(Security::Strict, "sealed"), Ok(true));
+        assert!(store.installed.get("bare").is_none());
+
+        // A remote repo fails strict even with every checksum published, and the error says why
+        remote_repo(&store, vec![index(&[("tool", "1.0", &[])])]);
+        let step = PlanStep { package: "tool".into(), version: "1.0".into(), repo: "web".into(), ..PlanStep::default() };
+        let plan = Plan { install: vec![step], ..Plan::default() };
+        let err = check_security(&store, &plan).unwrap_err();
+        assert_eq!(err, "security = strict: nothing installed (bpm can't check index signatures yet, so strict refuses every remote repo)");
+        store.config.security = Security::Warn;
+        assert!(check_security(&store, &plan).is_ok());
+        assert!(take_warnings().iter().any(|w| w.message == "tool:1.0 comes from remote repo web, whose index is not signed"));
+    }
+
+    #[test]
+    fn progress_style_takes_a_preset_or_a_template() {
+        for spec in [None, Some("default"), Some("minimal"), Some("spinner"), Some("{msg} {bar:10} {eta}")] {
+            assert!(progress_style(spec).is_ok(), "{:?}", spec);
+        }
+        let err = progress_style(Some("fancy")).err().unwrap();
+        assert!(err.starts_with("Unknown progress_style \"fancy\""), "{}", err);
+        let err = progress_style(Some("{bar:x}")).err().unwrap();
+        assert!(err.starts_with("Invalid progress_style template"), "{}", err);
+        // The message shrinks to fit instead of wrapping on narrow terminals
+        assert!(DEFAULT_PROGRESS_TEMPLATE.contains("{wide_msg}"));
+        let config: Config = toml::from_str("progress_style = \"spinner\"").unwrap();
+        assert_eq!(config.progress_style.as_deref(), Some("spinner"));
+        assert!(progress_bar(10, "Copying", &config, &Opts { no_progress: true, ..Opts::default() }).is_hidden());
+    }
+
+    #[test]
+    fn rollback_restores_the_version_before_an_upgrade() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "tool", None, &opts).unwrap();
+        let err = rollback_package(&store, "tool", &opts).unwrap_err();
+        assert!(err.starts_with("No earlier version of tool"), "{}", err);
+
+        write_packages(&store, "main", &[("tool", "1.0", &[]), ("tool", "2.0", &[])]);
+        upgrade_all(&store, &opts);
+        assert_eq!(store.installed.get("tool").unwrap().version, "2.0");
+        rollback_package(&store, "tool", &opts).unwrap();
+        let pkg = store.installed.get("tool").unwrap();
+        assert_eq!((pkg.repo.as_str(), pkg.version.as_str()), ("main", "1.0"));
+        assert_eq!(pkg.previous.map(|prev| prev.version).as_deref(), Some("2.0"));
+        assert!(store.bins_dir().join("tool").exists());
+    }
+
+    #[test]
+    fn frozen_store_blocks_mutating_commands_only() {
+        let blocked = |line: &[&str]| {
+            let (args, opts) = parse_opts(args(line)).unwrap();
+            mutating_command(&args, &opts)
+        };
+        for line in [&["bpm", "/i", "tool"][..], &["bpm", "/r", "tool"], &["bpm", "/u", "tool"], &["bpm", "/upgrade"],
+            &["bpm", "/cache", "clear"], &["bpm", "/repo", "add", "x", "/tmp/x"], &["bpm", "/config", "set", "color", "never"]] {
+            assert!(blocked(line).is_some(), "{:?}", line);
+        }
+        assert_eq!(blocked(&["bpm", "/repo", "remove", "x"]).as_deref(), Some("/repo remove"));
+        for line in [&["bpm", "/l"][..], &["bpm", "/info", "tool"], &["bpm", "/search", "tool"], &["bpm", "/verify"],
+            &["bpm", "/i", "tool", "--dry-run"], &["bpm", "/upgrade", "--dry-run"], &["bpm", "/repo", "list"],
+            &["bpm", "/config", "set", "frozen", "false"]] {
+            assert_eq!(blocked(line), None, "{:?}", line);
+        }
+
+        let (_, opts) = parse_opts(args(&["bpm", "/l", "--frozen"])).unwrap();
+        assert!(Config::default().frozen(&opts));
+        let config: Config = toml::from_str("frozen = true").unwrap();
+        assert!(config.frozen(&Opts::default()) && !Config::default().frozen(&Opts::default()));
+    }
+
+    #[test]
+    fn repo_export_then_import_recreates_the_registry() {
+        let (dir, store) = temp_store();
+        let url = remote_repo(&store, vec![index(&[("tool", "1.0", &[])])]);
+        let mut registry = load_registry(&store);
+        registry.insert("web".into(), RepoEntry {
+            priority: Some(5), fetched_at: Some(1), key: Some("/keys/web.pub".into()), ..registry["web"].clone()
+        });
+        registry.insert("off".into(), RepoEntry {
+            enabled: false, url: Some(format!("{}/off.mri", url)), token_env: Some("BPM_TEST_OFF_TOKEN".into()), ..RepoEntry::default()
+        });
+        save_registry(&store, &registry);
+        let list = dir.path().join("repos.toml");
+        repo_export(&store, Some(list.to_str().unwrap())).unwrap();
+        assert!(!fs::read_to_string(&list).unwrap().contains("fetched_at"));
+
+        let (_fresh_dir, fresh) = temp_store();
+        let opts = Opts { quiet: true, ..Opts::default() };
+        repo_import(&fresh, list.to_str().unwrap(), &opts).unwrap();
+        let strip = |registry: Registry| serde_json::to_value(registry.into_iter()
+            .map(|(name, entry)| (name, RepoEntry { fetched_at: None, ..entry })).collect::<Registry>()).unwrap();
+        assert_eq!(strip(load_registry(&fresh)), strip(registry));
+        // The imported remote repo was fetched and resolves straight away
+        assert!(load_registry(&fresh)["web"].fetched_at.is_some());
+        assert!(resolve_plan(&fresh, "web", "tool", None, &Opts::default()).is_ok());
+
+        // A second import leaves what's there unless --update
+        let mut changed = load_registry(&fresh);
+        changed.get_mut("web").unwrap().priority = Some(9);
+        save_registry(&fresh, &changed);
+        repo_import(&fresh, list.to_str().unwrap(), &opts).unwrap();
+        assert_eq!(load_registry(&fresh)["web"].priority, Some(9));
+        repo_import(&fresh, list.to_str().unwrap(), &Opts { update: true, ..opts }).unwrap();
+        assert_eq!(load_registry(&fresh)["web"].priority, Some(5));
+    }
+
+    #[test]
+    fn install_refuses_a_binary_owned_by_another_installed_package() {
+        let (_dir, store) = temp_store();
+        write_repo(&store, "main", "[a.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"tool\"]\n[b.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"tool\"]\n",
+            &[("tool", "x")]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "a", None, &opts).unwrap();
+        let err = install_package(&store, "main", "b", None, &opts).unwrap_err();
+        assert!(err.contains("belongs to installed package a"), "{}", err);
+        assert!(store.installed.get("b").is_none());
+        // Reinstalling the owner itself is fine
+        install_package(&store, "main", "a", None, &Opts { force: true, ..opts.clone() }).unwrap();
+    }
+
+    #[test]
+    fn two_packages_sharing_a_binary_name_install_nothing() {
+        let (_dir, store) = temp_store();
+        for sub in ["a", "b"] { fs::create_dir_all(store.root.join("main/src").join(sub)).unwrap(); }
+        write_repo(&store, "main", "[both.\"1.0\"]\npath = \"{src}\"\nbinaries = []\ndependencies = [\"a\", \"b\"]\n\
+            [a.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"a/shared\"]\n[b.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"b/shared\"]\n",
+            &[("a/shared", "from a"), ("b/shared", "from b")]);
+        let err = install_package(&store, "main", "both", None, &Opts { yes: true, quiet: true, ..Opts::default() }).unwrap_err();
+        assert!(err.starts_with("a and b both install ") && err.ends_with("shared, nothing installed."), "{}", err);
+        assert!(store.installed.load().is_empty());
+        assert!(!store.bins_dir().join("shared").exists());
+    }
+
+    #[test]
+    fn a_binary_being_written_by_another_run_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dest) = (dir.path().join("src"), dir.path().join("tool"));
+        fs::write(&src, "new").unwrap();
+        // Another run is midway through writing tool.tmp
+        let tmp = fs::File::create(tmp_sibling(&dest)).unwrap();
+        let err = place_file(&src, &dest, DEFAULT_BIN_MODE).unwrap_err();
+        assert!(err.ends_with("is being written by another bpm run; try again once it finishes"), "{}", err);
+        assert!(!dest.exists() && tmp_sibling(&dest).exists());
+        // One idle for longer than that is a crashed run's and is taken over
+        tmp.set_modified(std::time::SystemTime::now() - Duration::from_secs(STALE_TMP_SECS + 1)).unwrap();
+        place_file(&src, &dest, DEFAULT_BIN_MODE).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+        assert!(!tmp_sibling(&dest).exists());
+    }
+
+    #[test]
+    fn upgrade_with_repo_only_touches_that_repo() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("b", "1.0", &[])]);
+        write_packages(&store, "extra", &[("c", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["a", "b"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        install_package(&store, "extra", "c", None, &opts).unwrap();
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("a", "2.0", &[]), ("b", "1.0", &[]), ("b", "2.0", &[])]);
+        write_packages(&store, "extra", &[("c", "1.0", &[]), ("c", "2.0", &[])]);
+
+        upgrade_all(&store, &Opts { repo: Some("main".into()), exclude: vec!["b".into()], ..opts.clone() });
+        let versions = || ["a", "b", "c"].map(|name| store.installed.get(name).unwrap().version);
+        assert_eq!(versions(), ["2.0", "1.0", "1.0"]);
+        upgrade_all(&store, &Opts { repo: Some("extra".into()), ..opts });
+        assert_eq!(versions(), ["2.0", "1.0", "2.0"]);
+    }
+
+    #[test]
+    fn deprecated_versions_warn_and_strict_refuses_them() {
+        let (_dir, mut store) = temp_store();
+        write_packages(&store, "main", &[("relic", "1.0", &[])]);
+        let text = fs::read_to_string(store.repo_index_path("main")).unwrap();
+        let extra = format!("deprecated = \"use relic 2\"\nchecksums = {{ relic = \"{}\" }}\n", sha256_bytes(b"relic 1.0"));
+        fs::write(store.repo_index_path("main"), text + &extra).unwrap();
+        let (_, versions) = version_entries(&store, "relic", &Opts::default()).unwrap();
+        assert_eq!(versions[0].deprecated.as_deref(), Some("use relic 2"));
+
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "relic", None, &opts).unwrap();
+        assert!(take_warnings().iter().any(|w| w.kind == "deprecated" && w.message == "relic:1.0 is deprecated: use relic 2"));
+
+        store.installed.save(&InstalledDb::new());
+        store.config.security = Security::Strict;
+        let err = install_package(&store, "main", "relic", None, &opts).unwrap_err();
+        assert!(err.contains("--allow-deprecated"), "{}", err);
+        assert!(store.installed.get("relic").is_none());
+        install_package(&store, "main", "relic", None, &Opts { allow_deprecated: true, ..opts }).unwrap();
+        assert_eq!(store.installed.get("relic").unwrap().version, "1.0");
+    }
+
+    #[test]
+    fn repo_scan_registers_dropped_in_repo_dirs() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "known", &[("a", "1.0", &[])]);
+        write_packages(&store, "dropped", &[("b", "1.0", &[])]);
+        write_repo(&store, "broken", "not [toml", &[]);
+        let mut registry = load_registry(&store);
+        registry.insert("known".into(), RepoEntry::default());
+        save_registry(&store, &registry);
+
+        repo_scan(&store, &Opts { dry_run: true, ..Opts::default() });
+        assert!(!load_registry(&store).contains_key("dropped"));
+        repo_scan(&store, &Opts { yes: true, ..Opts::default() });
+        let registry = load_registry(&store);
+        assert_eq!(registry.keys().collect::<Vec<_>>(), ["dropped", "known"]);
+        assert!(registry["dropped"].enabled && registry["dropped"].url.is_none());
+    }
+
+    #[test]
+    fn events_file_gets_one_line_per_step() {
+        let (dir, store) = temp_store();
+        let path = dir.path().join("events.ndjson");
+        // EVENTS is set once per process, so other tests running alongside append to this file too
+        init_events(&Opts { events_file: Some(path.to_string_lossy().into_owned()), ..Opts::default() }).unwrap();
+        write_packages(&store, "main", &[("evapp", "1.0", &["evlib"]), ("evlib", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "evapp", None, &opts).unwrap();
+        remove_package(&store, "evapp", None);
+
+        let events: Vec<serde_json::Value> = fs::read_to_string(&path).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .filter(|event: &serde_json::Value| event.to_string().contains("evapp") || event.to_string().contains("evlib"))
+            .collect();
+        let sequence: Vec<String> = events.iter()
+            .map(|event| format!("{} {}", event["event"].as_str().unwrap(), event["package"].as_str().unwrap_or("-")))
+            .collect();
+        assert_eq!(sequence, ["resolved -", "copied evlib", "installed evlib", "copied evapp", "installed evapp", "removed evapp"]);
+        assert_eq!(events[0]["packages"], serde_json::json!(["evlib:1.0", "evapp:1.0"]));
+        assert!(events.iter().all(|event| event["ts"].as_u64().is_some()));
+    }
+
+    #[test]
+    fn a_package_with_zero_versions_is_an_error_not_a_panic() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[])]);
+        let text = fs::read_to_string(store.repo_index_path("main")).unwrap();
+        fs::write(store.repo_index_path("main"), text + "[ghost]\n").unwrap();
+        let expected = "Package ghost has no versions defined in repo main";
+
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        assert_eq!(install_package(&store, "main", "ghost", None, &opts).unwrap_err(), expected);
+        assert_eq!(resolve_plan(&store, "main", "ghost", Some("1.0"), &opts).unwrap_err(), expected);
+        assert_eq!(version_entries(&store, "ghost", &opts).unwrap_err(), "Package ghost not found");
+        assert_eq!(repo_verify(&store, "main").unwrap_err(), "Repo main failed verification");
+        install_package(&store, "main", "tool", None, &opts).unwrap();
+    }
+
+    #[test]
+    fn info_deps_lists_declared_or_resolved_dependencies() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib:>=1.0", "main/util"]), ("lib", "1.0", &[]), ("lib", "1.2", &[]), ("util", "3.0", &[])]);
+        let declared = info_dependencies(&store, "app", &Opts::default()).unwrap();
+        let resolved = Opts { resolved: true, ..Opts::default() };
+        assert_eq!(declared, ["lib:>=1.0", "main/util"]);
+        // Not installed: what it would resolve to right now
+        assert_eq!(info_dependencies(&store, "app", &resolved).unwrap(), ["lib:1.2", "util:3.0"]);
+
+        // Installed: what it was resolved to then, even once newer versions appear
+        install_package(&store, "main", "app", None, &Opts { yes: true, quiet: true, ..Opts::default() }).unwrap();
+        write_packages(&store, "main", &[("app", "1.0", &["lib:>=1.0", "main/util"]), ("lib", "1.0", &[]), ("lib", "1.2", &[]),
+            ("lib", "1.3", &[]), ("util", "3.0", &[])]);
+        assert_eq!(info_dependencies(&store, "app", &resolved).unwrap(), ["lib:1.2", "util:3.0"]);
+        assert_eq!(info_dependencies(&store, "app", &Opts::default()).unwrap(), declared);
+        assert!(info_dependencies(&store, "util", &Opts::default()).unwrap().is_empty());
+        assert_eq!(info_dependencies(&store, "nope", &resolved).unwrap_err(), "Package nope not found");
+    }
+
+    #[test]
+    fn no_save_db_leaves_packages_db_alone() {
+        let (_dir, mut store) = temp_store();
+        write_packages(&store, "main", &[("a", "1.0", &[]), ("b", "1.0", &[]), ("c", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        let db_len = |store: &Store| fs::metadata(store.packages_db_path()).map(|m| m.len()).unwrap_or(0);
+        install_package(&store, "main", "a", None, &opts).unwrap();
+        let after_a = db_len(&store);
+        assert!(after_a > 0);
+
+        install_package(&store, "main", "b", None, &Opts { no_save_db: true, ..opts.clone() }).unwrap();
+        assert_eq!(db_len(&store), after_a);
+        assert!(store.bins_dir().join("b").exists());
+        store.config.save_db = Some(false);
+        install_package(&store, "main", "c", None, &opts).unwrap();
+        assert_eq!(db_len(&store), after_a);
+    }
+
+    #[test]
+    fn repo_edit_url_marks_the_cached_index_stale() {
+        let (_dir, store) = temp_store();
+        remote_repo(&store, vec![index(&[("tool", "1.0", &[])])]);
+        let moved = mock_server(|_| (200, index(&[("tool", "2.0", &[])]).into_bytes()));
+        let broken = mock_server(|_| (404, Vec::new()));
+        let opts = Opts { quiet: true, ..Opts::default() };
+        refresh_repo(&store, "web", &opts).unwrap();
+        let mut registry = load_registry(&store);
+        registry.get_mut("web").unwrap().priority = Some(3);
+        save_registry(&store, &registry);
+
+        // A url that doesn't serve an index changes nothing
+        let edit = |url: &str, opts: &Opts| repo_edit(&store, "web", &Opts { url: Some(format!("{}/packages.mri", url)), ..opts.clone() });
+        assert!(edit(&broken, &Opts { yes: true, ..opts.clone() }).unwrap_err().ends_with("repo web left unchanged"));
+        assert!(load_registry(&store)["web"].fetched_at.is_some());
+
+        // The cached index came from the old url; --yes takes up the offer to refresh it
+        let original = load_registry(&store)["web"].url.clone().unwrap();
+        edit(&moved, &Opts { yes: true, ..opts.clone() }).unwrap();
+        let entry = &load_registry(&store)["web"];
+        assert_eq!(entry.url, Some(format!("{}/packages.mri", moved)));
+        assert_eq!(entry.priority, Some(3));
+        assert!(fs::read_to_string(store.cached_index_path("web")).unwrap().contains("2.0"));
+
+        // With no cached index nothing is refreshed, and the entry is left stale
+        fs::remove_file(store.cached_index_path("web")).unwrap();
+        edit(original.trim_end_matches("/packages.mri"), &Opts { yes: true, ..opts }).unwrap();
+        assert_eq!(load_registry(&store)["web"].fetched_at, None);
+        assert!(index_stale(index_age(&store, "web"), Some(3600)));
+    }
+
+    #[test]
+    fn repo_edit_key_sets_and_drops_the_signing_key() {
+        let (dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[])]);
+        let key = dir.path().join("main.pub");
+        fs::write(&key, "public key").unwrap();
+        let edit = |key: &str| repo_edit(&store, "main", &Opts { key: Some(key.into()), yes: true, quiet: true, ..Opts::default() });
+        assert_eq!(edit("missing.pub").unwrap_err(), "Key file missing.pub not found");
+        assert_eq!(load_registry(&store).get("main").and_then(|entry| entry.key.clone()), None);
+        edit(key.to_str().unwrap()).unwrap();
+        assert_eq!(load_registry(&store)["main"].key.as_deref(), key.to_str());
+        // Setting it again changes nothing, and none drops it
+        edit(key.to_str().unwrap()).unwrap();
+        edit("none").unwrap();
+        assert_eq!(load_registry(&store)["main"].key, None);
+    }
+
+    #[test]
+    fn a_dependency_pinned_to_a_missing_version_aborts_the_install() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["ok", "lib:1.2.0"]), ("ok", "1.0", &[]), ("lib", "1.0", &[])]);
+        let err = install_package(&store, "main", "app", None, &Opts { yes: true, quiet: true, ..Opts::default() }).unwrap_err();
+        assert_eq!(err, "Version 1.2.0 not found for package lib, required by app:1.0");
+        // Nothing was copied, not even the dependency that did resolve
+        assert!(store.installed.load().is_empty());
+        assert!(!store.bins_dir().join("ok").exists());
+    }
+
+    #[test]
+    fn summary_is_a_field_of_the_json_document() {
+        let report = OperationReport { installed: 2, unchanged: 0, removed: 1, binaries: 3, bytes_written: 10, bytes_freed: 4, elapsed_ms: 5 };
+        let warnings = [Warning { kind: "deprecated", message: "old".to_string() }];
+        let document = with_extras(serde_json::json!({ "install": [] }), &warnings, Some(&report));
+        assert_eq!(document["summary"]["installed"], 2);
+        assert_eq!(document["summary"]["bytes_freed"], 4);
+        assert_eq!(document["warnings"][0]["kind"], "deprecated");
+        assert_eq!(with_extras(serde_json::json!({ "a": 1 }), &[], None), serde_json::json!({ "a": 1 }));
+        assert_eq!(with_extras(serde_json::json!([1]), &warnings, Some(&report)), serde_json::json!([1]));
+    }
+
+    #[test]
+    fn the_summary_counts_a_small_install_and_removal() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["lib"]), ("lib", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        let counts = || {
+            let report = report_snapshot();
+            [report.installed, report.unchanged, report.removed, report.binaries, report.bytes_written as usize, report.bytes_freed as usize]
+        };
+        install_package(&store, "main", "app", None, &opts).unwrap();
+        assert_eq!(counts(), [2, 0, 0, 2, 14, 0]);
+        install_package(&store, "main", "app", None, &opts).unwrap();
+        assert_eq!(counts(), [2, 1, 0, 2, 14, 0]);
+        remove_package(&store, "app", None);
+        assert_eq!(counts(), [2, 1, 1, 3, 14, 7]);
+    }
+
+    #[test]
+    fn names_match_ignoring_case_unless_that_is_ambiguous() {
+        let config = Config::default();
+        let strict = Config { case_sensitive: true, ..Config::default() };
+        assert_eq!(match_name("Package", ["mytool", "other"], "MyTool", &config), Ok(Some("mytool")));
+        assert_eq!(match_name("Package", ["mytool"], "MyTool", &strict), Ok(None));
+        assert_eq!(match_name("Package", ["Tool", "tool"], "tool", &config), Ok(Some("tool")));
+        assert_eq!(match_name("Package", ["Tool", "tool"], "TOOL", &config).unwrap_err(),
+            "Package name TOOL is ambiguous: Tool, tool differ only by case");
+
+        let (_dir, mut store) = temp_store();
+        write_packages(&store, "main", &[("mytool", "1.0", &[]), ("Twin", "1.0", &[]), ("twin", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        // The index's spelling is what gets planned, installed and recorded
+        assert_eq!(planned(&resolve_plan(&store, "main", "MyTool", None, &opts).unwrap()), ["main:mytool:1.0"]);
+        install_package(&store, "main", "MYTOOL", None, &opts).unwrap();
+        assert!(store.installed.get("mytool").is_some() && store.installed.get("MYTOOL").is_none());
+        assert!(resolve_plan(&store, "main", "TWIN", None, &opts).unwrap_err().contains("is ambiguous"));
+        store.config.case_sensitive = true;
+        assert!(resolve_plan(&store, "main", "MyTool", None, &opts).is_err());
+    }
+
+    #[test]
+    fn a_dependency_cased_differently_finds_the_installed_package() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("app", "1.0", &["Lib", "Base"]), ("lib", "1.0", &[]), ("base", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "lib", None, &opts).unwrap();
+        // lib is kept rather than planned again, and both are recorded under the index's spelling
+        let plan = resolve_plan(&store, "main", "app", None, &opts).unwrap();
+        assert_eq!(planned(&plan), ["main:base:1.0", "main:app:1.0"]);
+        assert_eq!(plan.install[1].dependencies, ["lib:1.0", "base:1.0"]);
+        install_package(&store, "main", "app", None, &opts).unwrap();
+        assert_eq!(store.installed.get("app").unwrap().dependencies, ["lib:1.0", "base:1.0"]);
+        assert!(store.installed.get("lib").unwrap().explicit);
+    }
+
+    #[test]
+    fn repo_clone_copies_everything_but_the_name() {
+        let (_dir, store) = temp_store();
+        remote_repo(&store, vec![index(&[("tool", "1.0", &[])])]);
+        refresh_repo(&store, "web", &Opts { quiet: true, ..Opts::default() }).unwrap();
+        let mut registry = load_registry(&store);
+        registry.insert("web".into(), RepoEntry {
+            priority: Some(4), enabled: false, username: Some("me".into()), token_env: Some("WEB_TOKEN".into()), ..registry["web"].clone()
+        });
+        save_registry(&store, &registry);
+        write_packages(&store, "local", &[("a", "1.0", &[])]);
+
+        repo_clone(&store, "web", "mirror").unwrap();
+        let registry = load_registry(&store);
+        assert_eq!(serde_json::to_value(&registry["mirror"]).unwrap(), serde_json::to_value(&registry["web"]).unwrap());
+        assert_eq!(fs::read(store.cached_index_path("mirror")).unwrap(), fs::read(store.cached_index_path("web")).unwrap());
+
+        for (from, to, err) in [
+            ("web", "mirror", "Repo mirror already exists"),
+            ("web", "local", "Repo local already exists"),
+            ("nope", "x", "Repo nope not found"),
+            ("local", "copy", "local is a local repo; copy its directory to clone it"),
+        ] {
+            assert_eq!(repo_clone(&store, from, to).unwrap_err(), err);
+        }
+        assert!(repo_clone(&store, "web", "../out").is_err());
+        assert_eq!(load_registry(&store).len(), 2);
+    }
+
+    #[test]
+    fn an_unwritable_store_is_reported_before_any_work() {
+        // A path under a regular file can't be created even by root, unlike a read-only directory
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("file"), "").unwrap();
+        let store = Store::new(dir.path().join("file").join("store"));
+        let err = check_store_writable(&store, &args(&["bpm", "/i", "tool"]), &Opts::default()).unwrap_err();
+        assert!(err.starts_with(&format!("Store directory is not writable: {}", store.root.display())), "{}", err);
+        assert!(err.ends_with("Use --store <dir> or BPM_STORE to work on a store elsewhere."), "{}", err);
+
+        // A writable store with an unwritable --prefix fails on the prefix instead
+        let (_ok_dir, ok) = temp_store();
+        check_store_writable(&ok, &args(&["bpm", "/i", "tool"]), &Opts::default()).unwrap();
+        let prefix = dir.path().join("file").join("prefix");
+        let opts = Opts { prefix: Some(prefix.to_string_lossy().into_owned()), ..Opts::default() };
+        let err = check_store_writable(&ok, &args(&["bpm", "/i", "tool"]), &opts).unwrap_err();
+        assert!(err.contains(&prefix.display().to_string()), "{}", err);
+        assert!(!ok.root.join(".bpm-write-test").exists());
+    }
+
+    #[test]
+    fn verify_fails_on_modified_binaries_until_repaired() {
+        let (_dir, store) = temp_store();
+        write_repo(&store, "main", "[tool.\"1.0\"]\npath = \"{src}\"\nbinaries = [\"tool\"]\n", &[("tool", "x")]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        install_package(&store, "main", "tool", None, &opts).unwrap();
+        verify_installed(&store, None, &opts).unwrap();
+        fs::write(store.bins_dir().join("tool"), "changed").unwrap();
+        assert!(verify_installed(&store, None, &opts).is_err());
+        assert!(verify_installed(&store, Some("nope"), &opts).is_err());
+        verify_installed(&store, None, &Opts { repair: true, ..opts.clone() }).unwrap();
+        assert_eq!(fs::read_to_string(store.bins_dir().join("tool")).unwrap(), "x");
+        verify_installed(&store, None, &opts).unwrap();
+    }
+
+    #[test]
+    fn verify_repair_puts_a_corrupted_binary_back() {
+        let (_dir, store) = temp_store();
+        write_packages(&store, "main", &[("tool", "1.0", &[]), ("other", "1.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        for name in ["tool", "other"] { install_package(&store, "main", name, None, &opts).unwrap(); }
+        let bin = store.bins_dir().join("tool");
+        let good = store.installed.get("tool").unwrap().checksums[&bin.to_string_lossy().into_owned()].clone();
+        let corrupt = || {
+            fs::write(&bin, "garbage").unwrap();
+            // A different mtime than the one verify last saw, however coarse the filesystem's clock
+            fs::File::options().write(true).open(&bin).unwrap()
+                .set_modified(std::time::SystemTime::now() - Duration::from_secs(3600)).unwrap();
+        };
+        corrupt();
+        assert_eq!(verify_installed(&store, None, &Opts::default()).unwrap_err(), "1 binaries failed verification");
+        verify_installed(&store, None, &Opts { repair: true, ..Opts::default() }).unwrap();
+        assert_eq!(sha256_file(&bin).as_deref(), Some(good.as_str()));
+        assert_eq!(fs::read_to_string(&bin).unwrap(), "tool 1.0");
+        verify_installed(&store, Some("tool"), &Opts { deep: true, ..Opts::default() }).unwrap();
+
+        // A source that no longer matches either leaves the installed file as it was
+        corrupt();
+        fs::write(store.root.join("main/src/tool"), "tampered").unwrap();
+        assert!(verify_installed(&store, Some("tool"), &Opts { repair: true, ..Opts::default() }).is_err());
+        assert_eq!(fs::read_to_string(&bin).unwrap(), "garbage");
+        assert!(!tmp_sibling(&bin).exists());
+    }
+
+    #[test]
+    fn streamed_plan_is_one_line_per_node_then_a_summary() {
+        // What CliEvents prints with --stream, collected instead
+        struct Streamed(Vec<serde_json::Value>);
+        impl ResolveEvents for Streamed {
+            fn planned(&mut self, step: &PlanStep, _count: usize) { self.0.push(stream_line("node", serde_json::to_value(step).unwrap())); }
+        }
+        let repos = MemRepos::new(&[("main", &index(&[("a", "1.0", &["b"]), ("b", "1.0", &["c"]), ("c", "1.0", &[])]))]);
+        let mut lines = Streamed(Vec::new());
+        let plan = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "a", None, &Config::default(), &Opts::default(), &mut lines).unwrap();
+        lines.0.push(stream_summary(&plan));
+
+        let shape: Vec<String> = lines.0.iter().map(|line| format!("{} {}", line["type"].as_str().unwrap(), line["package"].as_str().unwrap_or("-"))).collect();
+        assert_eq!(shape, ["node c", "node b", "node a", "summary -"]);
+        assert_eq!(lines.0[2]["explicit"], true);
+        assert_eq!(lines.0[3]["packages"], 3);
+        assert!(lines.0.iter().all(|line| !line.to_string().contains('\n')));
+
+        // Only a --dry-run --json plan streams, and never an --interactive one
+        let opts = |line: &[&str]| parse_opts(args(line)).unwrap().1;
+        assert!(streaming(&opts(&["bpm", "/i", "a", "--dry-run", "--json", "--stream"])));
+        assert!(!streaming(&opts(&["bpm", "/i", "a", "--json", "--stream"])));
+        assert!(!streaming(&opts(&["bpm", "/i", "a", "--dry-run", "--json"])));
+        assert!(!streaming(&opts(&["bpm", "/i", "a", "--dry-run", "--json", "--stream", "--interactive"])));
+    }
+
+    #[test]
+    fn a_bare_install_uses_the_default_repo_first() {
+        let (_dir, mut store) = temp_store();
+        write_packages(&store, "alpha", &[("tool", "1.0", &[]), ("solo", "1.0", &[])]);
+        write_packages(&store, "zeta", &[("tool", "2.0", &[])]);
+        let opts = Opts { yes: true, quiet: true, ..Opts::default() };
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("alpha"));
+
+        assert!(repo_default(&store, Some("nope")).is_err());
+        repo_default(&store, Some("zeta")).unwrap();
+        store.config = load_config(&store);
+        assert_eq!(store.config.default_repo.as_deref(), Some("zeta"));
+        let specs = args(&["tool", "solo"]);
+        assert_eq!(install_each(&store, &specs, &opts), (vec!["tool", "solo"], vec![]));
+        let tool = store.installed.get("tool").unwrap();
+        assert_eq!((tool.repo.as_str(), tool.version.as_str()), ("zeta", "2.0"));
+        // Packages the default repo doesn't have are still found elsewhere
+        assert_eq!(store.installed.get("solo").unwrap().repo, "alpha");
+
+        repo_default(&store, Some("--clear")).unwrap();
+        store.config = load_config(&store);
+        assert_eq!(store.config.default_repo, None);
+        assert_eq!(select_repo(&store, "tool", &opts).as_deref(), Some("alpha"));
+    }
+
+    #[test]
+    fn warnings_are_folded_into_the_json_document() {
+        let repos = MemRepos::new(&[("main", &index(&[("a", "1.0", &["b"]), ("b", "1.0", &["a"])]))]);
+        let opts = Opts { allow_cycles: true, ..Opts::default() };
+        let plan = resolve(&repos, InstalledDb::new(), &BTreeMap::new(), "main", "a", None, &Config::default(), &opts, &mut CliEvents::new(&opts)).unwrap();
+        let document = json_document(serde_json::to_value(&plan).unwrap());
+        let warnings = document["warnings"].as_array().unwrap();
+        assert!(warnings.iter().any(|w| w["kind"] == "cycle" && w["message"] == "Circular dependency: a:1.0 -> b:1.0 -> a:1.0"), "{:?}", warnings);
+        assert!(document["install"].is_array());
+        // A document that isn't an object is left as it is; its warnings wait for print_warnings
+        warn("index", "kept for later".to_string());
+        assert_eq!(json_document(serde_json::json!([])), serde_json::json!([]));
+        assert!(take_warnings().iter().any(|w| w.message == "kept for later"));
+    }
+
+    #[test]
+    fn warnings_wait_for_the_end_and_come_out_grouped() {
+        let repos = MemRepos::new(&[("main", &index(&[("app", "1.0", &["a", "lib"]), ("a", "1.0", &["b"]), ("b", "1.0", &["a"]), ("lib", "1.0", &[])]))]);
+        let db = InstalledDb::from([("lib".to_string(), installed("main", "1.0"))]);
+        let opts = Opts { allow_cycles: true, ..Opts::default() };
+        let mut events = CliEvents::new(&opts);
+        resolve(&repos, db, &BTreeMap::new(), "main", "app", None, &Config::default(), &opts, &mut events).unwrap();
+        drop(events);
+
+        // Nothing was printed along the way; both are held for the end, in the order they came up
+        let warnings = take_warnings();
+        assert_eq!(warnings.iter().map(|w| w.kind).collect::<Vec<_>>(), ["cycle", "already-installed"]);
+        assert_eq!(warning_lines(&warnings), [
+            "⚠ 2 warnings:".to_string(),
+            "  Circular dependency: a:1.0 -> b:1.0 -> a:1.0".to_string(),
+            "  Dependency lib of app:1.0 already installed, kept at 1.0".to_string(),
+        ]);
+        assert!(take_warnings().is_empty() && warning_lines(&[]).is_empty());
+    }
+
+    #[test]
+    fn refresh_fetches_the_index_before_resolving() {
+        let (_dir, mut store) = temp_store();
+        remote_repo(&store, vec![index(&[("tool", "1.0", &[])]), index(&[("tool", "1.0", &[]), ("tool", "2.0", &[])])]);
+        write_packages(&store, "local", &[("other", "1.0", &[])]);
+        let opts = Opts { quiet: true, ..Opts::default() };
+        refresh_repo(&store, "web", &opts).unwrap();
+        // The cache counts as fresh, so without --refresh resolution sees the old index
+        store.config.cache_ttl = Some(3600);
+        let latest = |store: &Store| planned(&resolve_plan(store, "web", "tool", None, &opts).unwrap());
+        assert_eq!(latest(&store), ["web:tool:1.0"]);
+
+        // The repo named on the command line is refreshed, whatever its spelling or the ttl
+        refresh_before_resolve(&store, Some(vec!["WEB".into()]), &opts).unwrap();
+        assert_eq!(latest(&store), ["web:tool:2.0"]);
+        // Bare names refresh every enabled remote repo; local ones have nothing to fetch
+        refresh_before_resolve(&store, None, &opts).unwrap();
+        refresh_before_resolve(&store, Some(vec!["local".into()]), &opts).unwrap();
+
+        let mut registry = load_registry(&store);
+        registry.get_mut("web").unwrap().url = Some(format!("{}/gone", mock_server(|_| (404, Vec::new()))));
+        save_registry(&store, &registry);
+        let err = refresh_before_resolve(&store, None, &opts).unwrap_err();
+        assert!(err.starts_with("Failed to refresh web: "), "{}", err);
     }
 }